@@ -43,6 +43,13 @@ fn main() {
     println!(
         r#"cargo:rustc-check-cfg=cfg(CONFIG_MEMORY_UNION_ALL, values("true", "false", none()))"#
     );
+
+    println!(r#"cargo:rustc-check-cfg=cfg(CONFIG_WATCHDOG, values("true", "false", none()))"#);
+    println!(r#"cargo:rustc-check-cfg=cfg(CONFIG_RTC_TICK, values("true", "false", none()))"#);
+    println!(
+        r#"cargo:rustc-check-cfg=cfg(CONFIG_SERIAL_OUTPUT, values("e9", "com1", "com2", "com3", "com4", none()))"#
+    );
+    println!(r#"cargo:rustc-check-cfg=cfg(CONFIG_KEYMAP, values("us", "de", none()))"#);
     // End checks
 
     // Configuration name used when a config is required but should always evaluate
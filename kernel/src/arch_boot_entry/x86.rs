@@ -78,12 +78,15 @@ extern "C" fn _start() -> ! {
             options(nomem, nostack, preserves_flags, pure)
         );
     }
+    // Falls back to the debug port if the configured serial port doesn't work.
+    let _ = set_output_target(configured_output_target());
     #[allow(non_snake_case)]
     let mut BI: BootInfo<'static> = BootInfo {
         cmdline: None,
         memory_map: None,
         bootloader_name: None,
         output: None,
+        rsdp: None,
     };
     unsafe {
         match MAGIC {
@@ -243,6 +246,17 @@ extern "C" fn _start() -> ! {
                             };
                             BI.output = Some(&FBI)
                         },
+                        14 | 15 => {
+                            // ACPI old/new RSDP
+                            if current_tag.tag_len < 28 {
+                                // Unexpected size, something is probably up
+                                panic!("size of RSDP tag < 28");
+                            }
+                            // Prefer the new RSDP if both are provided.
+                            if current_tag.tag_type == 15 || BI.rsdp.is_none() {
+                                BI.rsdp = Some(ptr + size_of::<Tag>());
+                            }
+                        },
                         _ => {
                             // Unknown/unimplemented tag type, ignore
                             swarnings("Unknown tag type ");
@@ -282,6 +296,13 @@ extern "C" fn _start() -> ! {
     }
     sdebugsln("Bootloader information has been successfully loaded");
     sdebugunp(b'\n');
+
+    if let Some(cmdline) = BI.cmdline {
+        select_output_from_cmdline(cmdline);
+    }
+
+    aphrodite::arch::exceptions::init_exceptions(BI.output);
+    sdebugsln("Exception handlers have been installed");
     unsafe {
         if BI.output.clone().is_some() {
             let framebuffer_info = FBI;
@@ -302,6 +323,8 @@ extern "C" fn _start() -> ! {
             sdebugsln("Beginning test output to screen...");
 
             let ega: &dyn aphrodite::display::TextDisplay = &framebuffer_info;
+            egatext::set_console(framebuffer_info);
+            // The driver of the console keeps the cursor hidden once bound.
             framebuffer_info.disable_cursor();
             ega.clear_screen(COLOR_DEFAULT);
             toutputsln("Testing EGA Text framebuffer...", ega).unwrap();
@@ -336,6 +359,7 @@ fn halt_on_panic(info: &PanicInfo) -> ! {
     let mut formatter = FormattingOptions::new().create_formatter(unsafe { &mut FBI });
     let _ = info.message().fmt(&mut formatter);
     aphrodite::arch::interrupts::disable_interrupts();
+    flush_output();
     unsafe {
         asm!("hlt", options(noreturn));
     }
@@ -365,5 +389,9 @@ fn spin_on_panic(info: &PanicInfo) -> ! {
         sfatalsnp("\n");
     }
     aphrodite::arch::interrupts::disable_interrupts();
-    loop {}
+    flush_output();
+    // Only NMIs can wake the CPU up.
+    loop {
+        unsafe { asm!("hlt", options(nomem, nostack)) }
+    }
 }
@@ -0,0 +1,448 @@
+//! Discovery and parsing of ACPI tables. Tables are accessed in place through
+//! their physical addresses.
+#![allow(static_mut_refs)]
+
+/// Returned by [init_acpi] if no RSDP could be found.
+pub const ERR_NO_RSDP: i16 = -1;
+
+/// Returned by [init_acpi] if the checksum of the RSDP or root table is
+/// invalid.
+pub const ERR_INVALID_CHECKSUM: i16 = -2;
+
+/// The signature at the start of the RSDP.
+const RSDP_SIGNATURE: &[u8; 8] = b"RSD PTR ";
+
+/// The root system description pointer.
+#[repr(C, packed)]
+#[derive(Clone, Copy)]
+pub struct Rsdp {
+    /// "RSD PTR ".
+    pub signature: [u8; 8],
+    /// Makes the sum of the first 20 bytes zero.
+    pub checksum: u8,
+    /// The ID of the OEM.
+    pub oem_id: [u8; 6],
+    /// 0 for ACPI 1.0, 2 for ACPI 2.0 and later.
+    pub revision: u8,
+    /// The physical address of the RSDT.
+    pub rsdt_address: u32,
+    /// The length of the whole RSDP. Only valid for revision 2 and later.
+    pub length: u32,
+    /// The physical address of the XSDT. Only valid for revision 2 and later.
+    pub xsdt_address: u64,
+    /// Makes the sum of the whole RSDP zero.
+    pub extended_checksum: u8,
+    /// Reserved.
+    reserved: [u8; 3],
+}
+
+/// The header shared by every system description table.
+#[repr(C, packed)]
+#[derive(Clone, Copy)]
+pub struct SdtHeader {
+    /// The signature of the table, e.g. "APIC".
+    pub signature: [u8; 4],
+    /// The length of the table including the header.
+    pub length: u32,
+    /// The revision of the table.
+    pub revision: u8,
+    /// Makes the sum of the whole table zero.
+    pub checksum: u8,
+    /// The ID of the OEM.
+    pub oem_id: [u8; 6],
+    /// The ID of the OEM's table.
+    pub oem_table_id: [u8; 8],
+    /// The revision of the OEM's table.
+    pub oem_revision: u32,
+    /// The ID of the utility that created the table.
+    pub creator_id: u32,
+    /// The revision of the utility that created the table.
+    pub creator_revision: u32,
+}
+
+impl SdtHeader {
+    /// Returns the contents of the table after the header.
+    pub fn data(&self) -> &'static [u8] {
+        unsafe {
+            core::slice::from_raw_parts(
+                (self as *const Self as *const u8).add(size_of::<SdtHeader>()),
+                (self.length as usize).saturating_sub(size_of::<SdtHeader>()),
+            )
+        }
+    }
+}
+
+/// The root table, either the RSDT (32-bit entries) or XSDT (64-bit entries).
+#[derive(Clone, Copy)]
+struct RootTable {
+    /// The header of the table.
+    header: &'static SdtHeader,
+    /// Whether the table is the XSDT.
+    extended: bool,
+}
+
+/// The root table found by [init_acpi].
+static mut ROOT_TABLE: Option<RootTable> = None;
+
+/// Returns whether the bytes sum to zero.
+fn checksum_valid(bytes: &[u8]) -> bool {
+    bytes.iter().fold(0u8, |sum, b| sum.wrapping_add(*b)) == 0
+}
+
+/// Returns the bytes of a table.
+fn table_bytes(header: &SdtHeader) -> &[u8] {
+    unsafe {
+        core::slice::from_raw_parts(
+            header as *const SdtHeader as *const u8,
+            header.length as usize,
+        )
+    }
+}
+
+/// Searches a memory range for the RSDP on a 16 byte boundary.
+fn search_rsdp(start: usize, end: usize) -> Option<usize> {
+    (start..end).step_by(16).find(|addr| {
+        let bytes = unsafe { core::slice::from_raw_parts(*addr as *const u8, 20) };
+        bytes[..8] == *RSDP_SIGNATURE && checksum_valid(bytes)
+    })
+}
+
+/// Searches the places the RSDP can be on PC compatible systems: the first KiB
+/// of the EBDA and the BIOS area between 0xE0000 and 0xFFFFF.
+fn find_rsdp() -> Option<usize> {
+    let ebda = (unsafe { core::ptr::read_volatile(0x40E as *const u16) } as usize) << 4;
+    if ebda != 0 &&
+        let Some(rsdp) = search_rsdp(ebda, ebda + 1024)
+    {
+        return Some(rsdp);
+    }
+    search_rsdp(0xE0000, 0x100000)
+}
+
+/// Finds the root ACPI table. `rsdp` is the address of the RSDP, if the
+/// bootloader provided one; otherwise the BIOS areas are searched for it.
+pub fn init_acpi(rsdp: Option<usize>) -> Result<(), crate::Error<'static>> {
+    let rsdp = rsdp
+        .or_else(find_rsdp)
+        .ok_or(crate::Error::new("no RSDP found", ERR_NO_RSDP))?;
+    let rsdp = unsafe { &*(rsdp as *const Rsdp) };
+
+    let rsdp_bytes = unsafe { core::slice::from_raw_parts(rsdp as *const Rsdp as *const u8, 20) };
+    if !checksum_valid(rsdp_bytes) {
+        return Err(crate::Error::new(
+            "invalid RSDP checksum",
+            ERR_INVALID_CHECKSUM,
+        ));
+    }
+
+    // The XSDT can't be reached with 32-bit pointers if it's above 4 GiB.
+    let root =
+        if rsdp.revision >= 2 && rsdp.xsdt_address != 0 && rsdp.xsdt_address <= usize::MAX as u64 {
+            RootTable {
+                header: unsafe { &*(rsdp.xsdt_address as usize as *const SdtHeader) },
+                extended: true,
+            }
+        } else {
+            RootTable {
+                header: unsafe { &*(rsdp.rsdt_address as usize as *const SdtHeader) },
+                extended: false,
+            }
+        };
+    if !checksum_valid(table_bytes(root.header)) {
+        return Err(crate::Error::new(
+            "invalid root table checksum",
+            ERR_INVALID_CHECKSUM,
+        ));
+    }
+    unsafe {
+        ROOT_TABLE = Some(root);
+    }
+    Ok(())
+}
+
+/// Returns whether ACPI tables were found.
+pub fn acpi_available() -> bool { unsafe { ROOT_TABLE.is_some() } }
+
+/// Returns the table with a signature. Tables with an invalid checksum or that
+/// are above 4 GiB are skipped.
+pub fn find_table(signature: &[u8; 4]) -> Option<&'static SdtHeader> {
+    let root = unsafe { ROOT_TABLE }?;
+    let data = root.header.data();
+    let entry_size = if root.extended { 8 } else { 4 };
+    data.chunks_exact(entry_size)
+        .filter_map(|entry| {
+            let address = if root.extended {
+                u64::from_le_bytes(entry.try_into().unwrap())
+            } else {
+                u32::from_le_bytes(entry.try_into().unwrap()) as u64
+            };
+            if address == 0 || address > usize::MAX as u64 {
+                return None;
+            }
+            Some(unsafe { &*(address as usize as *const SdtHeader) })
+        })
+        .find(|table| table.signature == *signature && checksum_valid(table_bytes(table)))
+}
+
+/// Returns the index of the CMOS RTC register holding the century, from the
+/// FADT, if there is one.
+pub fn cmos_century_register() -> Option<u8> {
+    let fadt = find_table(b"FACP")?;
+    // The century field is at offset 108 of the table.
+    match fadt.data().get(108 - size_of::<SdtHeader>()) {
+        Some(0) | None => None,
+        Some(register) => Some(*register),
+    }
+}
+
+/// Reads a little endian u16 from a slice.
+fn read_u16(bytes: &[u8], offset: usize) -> u16 {
+    u16::from_le_bytes([bytes[offset], bytes[offset + 1]])
+}
+
+/// Reads a little endian u32 from a slice.
+fn read_u32(bytes: &[u8], offset: usize) -> u32 {
+    u32::from_le_bytes(bytes[offset..offset + 4].try_into().unwrap())
+}
+
+/// Reads a little endian u64 from a slice.
+fn read_u64(bytes: &[u8], offset: usize) -> u64 {
+    u64::from_le_bytes(bytes[offset..offset + 8].try_into().unwrap())
+}
+
+/// The multiple APIC description table.
+#[derive(Clone, Copy)]
+pub struct Madt {
+    /// The header of the table.
+    pub header: &'static SdtHeader,
+}
+
+impl Madt {
+    /// Finds the MADT.
+    pub fn find() -> Option<Self> {
+        let header = find_table(b"APIC")?;
+        if header.data().len() < 8 {
+            return None;
+        }
+        Some(Madt { header })
+    }
+
+    /// Returns the physical address of the local APICs. May be overridden by a
+    /// [MadtEntry::LocalApicAddressOverride].
+    pub fn local_apic_address(&self) -> u64 {
+        let address = self.entries().find_map(|entry| match entry {
+            MadtEntry::LocalApicAddressOverride { address } => Some(address),
+            _ => None,
+        });
+        address.unwrap_or(read_u32(self.header.data(), 0) as u64)
+    }
+
+    /// Returns whether the system also has 8259 PICs.
+    pub fn has_pics(&self) -> bool { read_u32(self.header.data(), 4) & 1 != 0 }
+
+    /// Returns an iterator over the entries of the MADT.
+    pub fn entries(&self) -> MadtEntries {
+        MadtEntries {
+            data: &self.header.data()[8..],
+        }
+    }
+
+    /// Returns the interrupt source override for an ISA IRQ, if there is one.
+    pub fn isa_override(&self, irq: u8) -> Option<MadtEntry> {
+        self.entries().find(|entry| {
+            matches!(entry, MadtEntry::InterruptSourceOverride { bus: 0, source, .. } if *source == irq)
+        })
+    }
+}
+
+/// An entry of the MADT.
+#[derive(Clone, Copy, Debug)]
+pub enum MadtEntry {
+    /// A processor with a local APIC.
+    LocalApic {
+        /// The ACPI ID of the processor.
+        processor_id: u8,
+        /// The ID of the local APIC.
+        apic_id: u8,
+        /// Bit 0 is set if the processor is enabled.
+        flags: u32,
+    },
+    /// An I/O APIC.
+    IoApic {
+        /// The ID of the I/O APIC.
+        id: u8,
+        /// The physical address of the I/O APIC.
+        address: u32,
+        /// The first global system interrupt handled by the I/O APIC.
+        gsi_base: u32,
+    },
+    /// Maps an ISA IRQ to a different global system interrupt.
+    InterruptSourceOverride {
+        /// The bus. 0 for ISA.
+        bus: u8,
+        /// The IRQ on the bus.
+        source: u8,
+        /// The global system interrupt the IRQ is connected to.
+        gsi: u32,
+        /// The polarity (bits 0-1) and trigger mode (bits 2-3).
+        flags: u16,
+    },
+    /// A global system interrupt that should be an NMI.
+    NmiSource {
+        /// The polarity (bits 0-1) and trigger mode (bits 2-3).
+        flags: u16,
+        /// The global system interrupt.
+        gsi: u32,
+    },
+    /// A local APIC LINT pin connected to NMI.
+    LocalApicNmi {
+        /// The ACPI ID of the processor, or 0xFF for all processors.
+        processor_id: u8,
+        /// The polarity (bits 0-1) and trigger mode (bits 2-3).
+        flags: u16,
+        /// The LINT pin, 0 or 1.
+        lint: u8,
+    },
+    /// A 64-bit address of the local APICs.
+    LocalApicAddressOverride {
+        /// The physical address of the local APICs.
+        address: u64,
+    },
+    /// An entry type that isn't parsed.
+    Unknown {
+        /// The type of the entry.
+        entry_type: u8,
+    },
+}
+
+/// An iterator over the entries of the MADT.
+#[derive(Clone)]
+pub struct MadtEntries {
+    /// The entries that haven't been returned yet.
+    data: &'static [u8],
+}
+
+impl Iterator for MadtEntries {
+    type Item = MadtEntry;
+
+    fn next(&mut self) -> Option<MadtEntry> {
+        if self.data.len() < 2 {
+            return None;
+        }
+        let entry_type = self.data[0];
+        let len = self.data[1] as usize;
+        if len < 2 || len > self.data.len() {
+            return None;
+        }
+        let entry = &self.data[..len];
+        self.data = &self.data[len..];
+
+        Some(match (entry_type, len) {
+            (0, 8..) => MadtEntry::LocalApic {
+                processor_id: entry[2],
+                apic_id: entry[3],
+                flags: read_u32(entry, 4),
+            },
+            (1, 12..) => MadtEntry::IoApic {
+                id: entry[2],
+                address: read_u32(entry, 4),
+                gsi_base: read_u32(entry, 8),
+            },
+            (2, 10..) => MadtEntry::InterruptSourceOverride {
+                bus: entry[2],
+                source: entry[3],
+                gsi: read_u32(entry, 4),
+                flags: read_u16(entry, 8),
+            },
+            (3, 8..) => MadtEntry::NmiSource {
+                flags: read_u16(entry, 2),
+                gsi: read_u32(entry, 4),
+            },
+            (4, 6..) => MadtEntry::LocalApicNmi {
+                processor_id: entry[2],
+                flags: read_u16(entry, 3),
+                lint: entry[5],
+            },
+            (5, 12..) => MadtEntry::LocalApicAddressOverride {
+                address: read_u64(entry, 4),
+            },
+            _ => MadtEntry::Unknown { entry_type },
+        })
+    }
+}
+
+/// The HPET description table.
+#[derive(Clone, Copy)]
+pub struct HpetTable {
+    /// The header of the table.
+    pub header: &'static SdtHeader,
+}
+
+impl HpetTable {
+    /// Finds the HPET table. Only HPETs in system memory are supported.
+    pub fn find() -> Option<Self> {
+        let header = find_table(b"HPET")?;
+        let data = header.data();
+        // The base address is a generic address structure with address space
+        // 0 for system memory.
+        if data.len() < 20 || data[4] != 0 {
+            return None;
+        }
+        Some(HpetTable { header })
+    }
+
+    /// Returns the hardware ID of the event timer block.
+    pub fn event_timer_block_id(&self) -> u32 { read_u32(self.header.data(), 0) }
+
+    /// Returns the physical address of the registers.
+    pub fn base_address(&self) -> u64 { read_u64(self.header.data(), 8) }
+
+    /// Returns the sequence number of the HPET.
+    pub fn hpet_number(&self) -> u8 { self.header.data()[16] }
+
+    /// Returns the smallest number of main counter ticks periodic timers can
+    /// be programmed with without losing interrupts.
+    pub fn minimum_tick(&self) -> u16 { read_u16(self.header.data(), 17) }
+}
+
+/// The PCI Express memory mapped configuration table, describing the ECAM
+/// regions of the PCI segments.
+#[derive(Clone, Copy)]
+pub struct McfgTable {
+    /// The header of the table.
+    pub header: &'static SdtHeader,
+}
+
+/// An ECAM region of the MCFG.
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub struct McfgEntry {
+    /// The physical address of the configuration space of bus 0, even if
+    /// [McfgEntry::start_bus] is higher.
+    pub base_address: u64,
+    /// The PCI segment group.
+    pub segment: u16,
+    /// The first bus the region covers.
+    pub start_bus: u8,
+    /// The last bus the region covers.
+    pub end_bus: u8,
+}
+
+impl McfgTable {
+    /// Finds the MCFG.
+    pub fn find() -> Option<Self> {
+        let header = find_table(b"MCFG")?;
+        Some(McfgTable { header })
+    }
+
+    /// Returns the ECAM regions.
+    pub fn entries(&self) -> impl Iterator<Item = McfgEntry> {
+        // 8 reserved bytes come before the entries.
+        let data = self.header.data().get(8..).unwrap_or(&[]);
+        data.chunks_exact(16).map(|entry| McfgEntry {
+            base_address: read_u64(entry, 0),
+            segment: read_u16(entry, 8),
+            start_bus: entry[10],
+            end_bus: entry[11],
+        })
+    }
+}
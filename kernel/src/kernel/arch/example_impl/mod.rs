@@ -13,6 +13,15 @@
 /// Returns the most specific architecture available.
 pub const fn get_arch() -> super::Architecture { super::Architecture::ExampleDummy }
 
+pub mod device {
+    //! Platform devices.
+
+    /// Registers the devices that can't be enumerated, and the drivers of the
+    /// architecture. Called once IRQs are set up.
+    #[aphrodite_proc_macros::kernel_item(DeviceInit)]
+    fn init_devices() {}
+}
+
 pub mod interrupts {
     //! Interrupt-related functions.
 
@@ -34,6 +43,11 @@ pub mod interrupts {
     #[aphrodite_proc_macros::kernel_item(InterruptsDisable)]
     fn disable_interrupts() {}
 
+    /// Enables interrupts and waits for one with the CPU halted. An interrupt
+    /// that is already pending must end the wait.
+    #[aphrodite_proc_macros::kernel_item(InterruptsWait)]
+    fn wait_for_interrupt() {}
+
     /// Disables interrupts and a value that can be used to restore them
     /// with [restore_irq].
     #[aphrodite_proc_macros::kernel_item(InterruptsPop)]
@@ -47,11 +61,20 @@ pub mod interrupts {
     #[aphrodite_proc_macros::kernel_item(ActivateIDT)]
     fn activate_idt(_idt: Idt) {}
 
+    /// The state saved by the architecture's interrupt entry code. Handlers
+    /// may modify it; the modified state is restored when the interrupt
+    /// returns.
+    #[derive(Clone, Copy)]
+    pub struct InterruptFrame {}
+
+    /// A function that handles an interrupt.
+    pub type InterruptHandler = fn(&mut InterruptFrame);
+
     /// An IDT.
     #[derive(Clone, Copy)]
     pub struct Idt {
         vectors: [u16; 256],
-        funcs: [MaybeUninit<fn()>; 256],
+        funcs: [MaybeUninit<InterruptHandler>; 256],
         len: usize,
     }
 
@@ -60,7 +83,7 @@ pub mod interrupts {
     #[derive(Clone, Copy)]
     pub struct IdtBuilder {
         vectors: [u16; 256],
-        funcs: [MaybeUninit<fn()>; 256],
+        funcs: [MaybeUninit<InterruptHandler>; 256],
         idx: usize,
     }
 
@@ -73,8 +96,8 @@ pub mod interrupts {
                 idx: 0,
             }
         }
-        /// Add a function to the IDT.
-        pub fn add_fn(&mut self, vector: u16, func: fn()) -> &mut Self {
+        /// Add a handler to the IDT.
+        pub fn add_fn(&mut self, vector: u16, func: InterruptHandler) -> &mut Self {
             self.vectors[self.idx] = vector;
             self.funcs[self.idx].write(func);
             self.idx += 1;
@@ -101,6 +124,144 @@ pub mod interrupts {
     }
 }
 
+pub mod irq {
+    //! Interrupt controller functions used by [crate::irq]. For every IRQ that
+    //! isn't spurious, the architecture must call [crate::irq::irq_enter] and
+    //! [crate::irq::handle_irq], acknowledge the IRQ, then call
+    //! [crate::irq::irq_exit].
+
+    /// Initalizes the interrupt controller with every IRQ masked.
+    #[aphrodite_proc_macros::kernel_item(IrqInit)]
+    fn init_irq_controller() {}
+
+    /// Masks an IRQ.
+    #[aphrodite_proc_macros::kernel_item(IrqMask)]
+    fn mask_irq(_irq: u8) {}
+
+    /// Unmasks an IRQ.
+    #[aphrodite_proc_macros::kernel_item(IrqUnmask)]
+    fn unmask_irq(_irq: u8) {}
+}
+
+pub mod keyboard {
+    //! The keyboard. Drivers report keys with [crate::keyboard::report_key].
+
+    use crate::keyboard::Modifiers;
+
+    /// Sets the lock LEDs of the keyboard to the locks in `modifiers`. May be
+    /// called from interrupt context.
+    #[aphrodite_proc_macros::kernel_item(KeyboardSetLeds)]
+    fn set_leds(_modifiers: Modifiers) {}
+}
+
+pub mod msi {
+    //! Message signaled interrupts.
+
+    /// Returns whether message signaled interrupts can be delivered.
+    #[aphrodite_proc_macros::kernel_item(MsiSupported)]
+    fn msi_supported() -> bool { false }
+
+    /// Allocates `count` consecutive IRQs for message signaled interrupts,
+    /// whose vectors are aligned to `align`. Returns the first.
+    #[aphrodite_proc_macros::kernel_item(MsiAllocateIrqs)]
+    fn allocate_irqs(_count: u8, _align: u8) -> Option<u8> { None }
+
+    /// Frees IRQs allocated by [allocate_irqs].
+    #[aphrodite_proc_macros::kernel_item(MsiFreeIrqs)]
+    fn free_irqs(_first: u8, _count: u8) {}
+
+    /// Returns the message address and data that raise an IRQ.
+    #[aphrodite_proc_macros::kernel_item(MsiMessage)]
+    fn message(_irq: u8) -> Option<(u64, u32)> { None }
+}
+
+pub mod pci {
+    //! PCI configuration space access.
+
+    use crate::pci::PciAddress;
+
+    /// Sets up configuration space access. Returns whether there is any.
+    #[aphrodite_proc_macros::kernel_item(PciInit)]
+    fn init_pci() -> bool { false }
+
+    /// Returns whether the 4 KiB extended configuration space of PCI Express
+    /// functions can be accessed.
+    #[aphrodite_proc_macros::kernel_item(PciExtendedConfig)]
+    fn extended_config() -> bool { false }
+
+    /// Reads a dword of configuration space at a multiple of 4. Returns all
+    /// ones if the function or offset doesn't exist.
+    #[aphrodite_proc_macros::kernel_item(PciConfigRead)]
+    fn config_read(_address: PciAddress, _offset: u16) -> u32 { 0xFFFF_FFFF }
+
+    /// Writes a dword of configuration space at a multiple of 4.
+    #[aphrodite_proc_macros::kernel_item(PciConfigWrite)]
+    fn config_write(_address: PciAddress, _offset: u16, _value: u32) {}
+}
+
+pub mod percpu {
+    //! Per-CPU data and user TLS.
+
+    use crate::percpu::PerCpu;
+
+    /// Returns a pointer to the [PerCpu] of the current CPU, usually found
+    /// through a dedicated register.
+    #[aphrodite_proc_macros::kernel_item(ThisCpu)]
+    fn this_cpu() -> *mut PerCpu { crate::percpu::boot_cpu() }
+
+    /// Sets the thread pointer userspace uses to find its TLS.
+    #[aphrodite_proc_macros::kernel_item(SetThreadPointer)]
+    fn set_thread_pointer(_ptr: usize) {}
+}
+
+pub mod serial {
+    //! Interrupt driven serial I/O on the serial console, if there is one.
+
+    /// Makes the serial console interrupt driven. Called once IRQs are set up.
+    #[aphrodite_proc_macros::kernel_item(SerialInit)]
+    fn init_serial() {}
+
+    /// Reads a byte received on the serial console without waiting.
+    #[aphrodite_proc_macros::kernel_item(SerialRead)]
+    fn read_console() -> Option<u8> { None }
+
+    /// Queues bytes for the serial console without waiting. Returns the number
+    /// of bytes queued.
+    #[aphrodite_proc_macros::kernel_item(SerialWrite)]
+    fn write_console(bytes: &[u8]) -> usize { bytes.len() }
+}
+
+pub mod syscall {
+    //! Syscall entry points. The architecture must pass every syscall to
+    //! [crate::syscall::dispatch_syscall].
+
+    /// Sets up the syscall entry points.
+    #[aphrodite_proc_macros::kernel_item(SyscallInit)]
+    fn init_syscalls() {}
+}
+
+pub mod time {
+    //! Clocksources. The architecture must register its clocksources with
+    //! [crate::time::register_clocksource].
+
+    /// Registers the clocksources of the architecture.
+    #[aphrodite_proc_macros::kernel_item(TimeInit)]
+    fn init_time() {}
+}
+
+pub mod watchdog {
+    //! The timer behind [crate::watchdog]. It should keep firing while the CPU
+    //! is stuck with interrupts disabled, e.g. by being delivered as an NMI.
+
+    /// Starts calling [crate::watchdog::watchdog_tick] `hz` times a second.
+    #[aphrodite_proc_macros::kernel_item(WatchdogStart)]
+    fn start_watchdog(_hz: u32) {}
+
+    /// Stops the watchdog timer.
+    #[aphrodite_proc_macros::kernel_item(WatchdogStop)]
+    fn stop_watchdog() {}
+}
+
 pub mod output {
     //! Not shown here(see [crate::arch::x86] for an example), but a
     //! LOT of output functions must be implemented. Using macros to
@@ -0,0 +1,451 @@
+//! Driver for the local APIC and the I/O APICs. IRQs below 16 are ISA IRQs,
+//! routed through the interrupt source overrides of the MADT; higher IRQs are
+//! global system interrupts. IRQ `n` is delivered on vector
+//! [APIC_IRQ_BASE] + `n`.
+#![cfg(target_arch = "x86")]
+#![allow(static_mut_refs)]
+
+use super::interrupts::{InterruptFrame, USER_SYSCALL_VECTOR, register_interrupt_handler};
+use super::msr::{IA32_APIC_BASE, msr_supported, read_msr, write_msr};
+use super::output::*;
+use crate::acpi::{Madt, MadtEntry};
+
+/// The vector of IRQ 0.
+pub const APIC_IRQ_BASE: u8 = 0x30;
+/// The vector of the local APIC timer.
+pub const LAPIC_TIMER_VECTOR: u8 = 0xF0;
+/// The IRQ of the local APIC timer.
+pub const LAPIC_TIMER_IRQ: u8 = LAPIC_TIMER_VECTOR - APIC_IRQ_BASE;
+/// The vector of local APIC errors.
+pub const LAPIC_ERROR_VECTOR: u8 = 0xFE;
+/// The spurious interrupt vector.
+pub const SPURIOUS_VECTOR: u8 = 0xFF;
+
+/// The number of ISA IRQs.
+const ISA_IRQ_COUNT: usize = 16;
+/// The highest number of I/O APICs that are used.
+const MAX_IOAPICS: usize = 8;
+
+/// The local APIC ID register.
+const LAPIC_ID: usize = 0x20;
+/// The task priority register.
+const LAPIC_TPR: usize = 0x80;
+/// The end of interrupt register.
+const LAPIC_EOI: usize = 0xB0;
+/// The spurious interrupt vector register.
+const LAPIC_SVR: usize = 0xF0;
+/// The error status register.
+const LAPIC_ESR: usize = 0x280;
+/// The timer entry of the local vector table.
+const LAPIC_LVT_TIMER: usize = 0x320;
+/// The LINT0 entry of the local vector table.
+const LAPIC_LVT_LINT0: usize = 0x350;
+/// The LINT1 entry of the local vector table.
+const LAPIC_LVT_LINT1: usize = 0x360;
+/// The error entry of the local vector table.
+const LAPIC_LVT_ERROR: usize = 0x370;
+/// The initial count of the timer.
+const LAPIC_TIMER_INITIAL: usize = 0x380;
+/// The current count of the timer.
+const LAPIC_TIMER_CURRENT: usize = 0x390;
+/// The divide configuration of the timer.
+const LAPIC_TIMER_DIVIDE: usize = 0x3E0;
+
+/// Masks a local vector table or redirection table entry.
+const MASKED: u32 = 1 << 16;
+/// Makes the timer periodic.
+const TIMER_PERIODIC: u32 = 1 << 17;
+/// Delivers an entry as an NMI.
+const DELIVERY_NMI: u32 = 0b100 << 8;
+/// Makes an entry active low.
+const ACTIVE_LOW: u32 = 1 << 13;
+/// Makes an entry level triggered.
+const LEVEL_TRIGGERED: u32 = 1 << 15;
+/// Enables the local APIC in the spurious interrupt vector register.
+const SVR_ENABLE: u32 = 1 << 8;
+/// Enables the local APIC in [IA32_APIC_BASE].
+const APIC_BASE_ENABLE: u64 = 1 << 11;
+
+/// The I/O APIC version register, holding the number of redirection entries.
+const IOAPIC_VERSION: u32 = 0x01;
+/// The first redirection table register.
+const IOAPIC_REDIRECTION: u32 = 0x10;
+
+/// The divisor of the local APIC timer's input clock.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum TimerDivide {
+    /// Divide by 1.
+    By1,
+    /// Divide by 2.
+    By2,
+    /// Divide by 4.
+    By4,
+    /// Divide by 8.
+    By8,
+    /// Divide by 16.
+    By16,
+    /// Divide by 32.
+    By32,
+    /// Divide by 64.
+    By64,
+    /// Divide by 128.
+    By128,
+}
+
+impl TimerDivide {
+    /// Returns the value of the divide configuration register.
+    const fn encode(self) -> u32 {
+        match self {
+            TimerDivide::By1 => 0b1011,
+            TimerDivide::By2 => 0b0000,
+            TimerDivide::By4 => 0b0001,
+            TimerDivide::By8 => 0b0010,
+            TimerDivide::By16 => 0b0011,
+            TimerDivide::By32 => 0b1000,
+            TimerDivide::By64 => 0b1001,
+            TimerDivide::By128 => 0b1010,
+        }
+    }
+}
+
+/// An I/O APIC.
+#[derive(Clone, Copy)]
+struct IoApic {
+    /// The physical address of the registers.
+    address: usize,
+    /// The first global system interrupt handled.
+    gsi_base: u32,
+    /// The number of redirection entries.
+    entries: u32,
+}
+
+impl IoApic {
+    /// Reads a register.
+    fn read(&self, reg: u32) -> u32 {
+        unsafe {
+            core::ptr::write_volatile(self.address as *mut u32, reg);
+            core::ptr::read_volatile((self.address + 0x10) as *const u32)
+        }
+    }
+
+    /// Writes a register.
+    fn write(&self, reg: u32, value: u32) {
+        unsafe {
+            core::ptr::write_volatile(self.address as *mut u32, reg);
+            core::ptr::write_volatile((self.address + 0x10) as *mut u32, value);
+        }
+    }
+
+    /// Writes the redirection entry of a pin. The high half is written first
+    /// so that the entry is never unmasked with a stale destination.
+    fn write_redirection(&self, pin: u32, low: u32, high: u32) {
+        self.write(IOAPIC_REDIRECTION + pin * 2, MASKED);
+        self.write(IOAPIC_REDIRECTION + pin * 2 + 1, high);
+        self.write(IOAPIC_REDIRECTION + pin * 2, low);
+    }
+
+    /// Returns whether a global system interrupt is handled by this I/O APIC.
+    const fn handles(&self, gsi: u32) -> bool {
+        gsi >= self.gsi_base && gsi < self.gsi_base + self.entries
+    }
+}
+
+/// The route of an IRQ to a global system interrupt.
+#[derive(Clone, Copy)]
+struct Route {
+    /// The global system interrupt.
+    gsi: u32,
+    /// The polarity and trigger mode bits of a redirection entry.
+    flags: u32,
+}
+
+/// The physical address of the local APIC.
+static mut LAPIC_BASE: usize = 0;
+
+/// The I/O APICs found in the MADT.
+static mut IOAPICS: [Option<IoApic>; MAX_IOAPICS] = [None; MAX_IOAPICS];
+
+/// The routes of the ISA IRQs.
+static mut ISA_ROUTES: [Route; ISA_IRQ_COUNT] = [Route { gsi: 0, flags: 0 }; ISA_IRQ_COUNT];
+
+/// The number of spurious interrupts seen so far.
+static mut SPURIOUS_INTERRUPTS: u32 = 0;
+
+/// A bit for every IRQ above the ISA IRQs that is edge triggered and active
+/// high instead of PCI style.
+static mut EDGE_TRIGGERED: [u32; 8] = [0; 8];
+
+/// Returns whether the CPU has a local APIC.
+pub fn apic_supported() -> bool { super::cpuid(1).1 & (1 << 9) != 0 }
+
+/// Reads a local APIC register.
+fn lapic_read(reg: usize) -> u32 {
+    unsafe { core::ptr::read_volatile((LAPIC_BASE + reg) as *const u32) }
+}
+
+/// Writes a local APIC register.
+fn lapic_write(reg: usize, value: u32) {
+    unsafe { core::ptr::write_volatile((LAPIC_BASE + reg) as *mut u32, value) }
+}
+
+/// Returns the ID of the current CPU's local APIC.
+pub fn local_apic_id() -> u8 { (lapic_read(LAPIC_ID) >> 24) as u8 }
+
+/// Signals the end of an interrupt to the local APIC.
+pub fn send_eoi() { lapic_write(LAPIC_EOI, 0); }
+
+/// Converts the MPS INTI flags of a MADT entry to redirection entry bits.
+/// `level_low` is the bus default.
+const fn inti_flags(flags: u16, level_low: bool) -> u32 {
+    let low = match flags & 0b11 {
+        0b01 => false,
+        0b11 => true,
+        _ => level_low,
+    };
+    let level = match (flags >> 2) & 0b11 {
+        0b01 => false,
+        0b11 => true,
+        _ => level_low,
+    };
+    (if low { ACTIVE_LOW } else { 0 }) | (if level { LEVEL_TRIGGERED } else { 0 })
+}
+
+/// Returns the route of an IRQ.
+fn route(irq: u8) -> Route {
+    if (irq as usize) < ISA_IRQ_COUNT {
+        unsafe { ISA_ROUTES[irq as usize] }
+    } else if unsafe { EDGE_TRIGGERED[irq as usize / 32] } & (1 << (irq % 32)) != 0 {
+        Route {
+            gsi: irq as u32,
+            flags: 0,
+        }
+    } else {
+        // Non-ISA interrupts are PCI style by default: active low and level
+        // triggered.
+        Route {
+            gsi: irq as u32,
+            flags: ACTIVE_LOW | LEVEL_TRIGGERED,
+        }
+    }
+}
+
+/// Makes an IRQ above the ISA IRQs edge triggered and active high, e.g. for
+/// devices that aren't on PCI. Takes effect the next time it's unmasked.
+pub fn set_edge_triggered(irq: u8) {
+    if (irq as usize) < ISA_IRQ_COUNT {
+        return;
+    }
+    unsafe {
+        EDGE_TRIGGERED[irq as usize / 32] |= 1 << (irq % 32);
+    }
+}
+
+/// Returns the I/O APIC handling a global system interrupt.
+fn ioapic_for(gsi: u32) -> Option<IoApic> {
+    unsafe {
+        IOAPICS
+            .iter()
+            .flatten()
+            .find(|ioapic| ioapic.handles(gsi))
+            .copied()
+    }
+}
+
+/// Returns the number of global system interrupts the I/O APICs handle. IRQs
+/// from there up to [LAPIC_TIMER_IRQ] aren't routed through them.
+pub fn gsi_count() -> u32 {
+    unsafe {
+        IOAPICS
+            .iter()
+            .flatten()
+            .map(|ioapic| ioapic.gsi_base + ioapic.entries)
+            .max()
+            .unwrap_or(ISA_IRQ_COUNT as u32)
+            .max(ISA_IRQ_COUNT as u32)
+    }
+}
+
+/// Masks or unmasks an IRQ.
+fn set_masked(irq: u8, masked: bool) {
+    let mask = if masked { MASKED } else { 0 };
+    if irq == LAPIC_TIMER_IRQ {
+        lapic_write(
+            LAPIC_LVT_TIMER,
+            (lapic_read(LAPIC_LVT_TIMER) & !MASKED) | mask,
+        );
+        return;
+    }
+    if irq as u16 + APIC_IRQ_BASE as u16 >= LAPIC_TIMER_VECTOR as u16 {
+        return;
+    }
+    let route = route(irq);
+    if let Some(ioapic) = ioapic_for(route.gsi) {
+        ioapic.write_redirection(
+            route.gsi - ioapic.gsi_base,
+            (APIC_IRQ_BASE + irq) as u32 | route.flags | mask,
+            (local_apic_id() as u32) << 24,
+        );
+    }
+}
+
+/// Delivers an ISA IRQ or global system interrupt to the current CPU as an
+/// NMI instead of on its vector. [mask] stops it again.
+pub fn route_as_nmi(irq: u8) {
+    let route = route(irq);
+    if let Some(ioapic) = ioapic_for(route.gsi) {
+        ioapic.write_redirection(
+            route.gsi - ioapic.gsi_base,
+            DELIVERY_NMI | route.flags,
+            (local_apic_id() as u32) << 24,
+        );
+    }
+}
+
+/// Masks an IRQ so that it isn't delivered.
+pub fn mask(irq: u8) { set_masked(irq, true); }
+
+/// Unmasks an IRQ so that it is delivered to the current CPU.
+pub fn unmask(irq: u8) { set_masked(irq, false); }
+
+/// Sets the divisor of the local APIC timer.
+pub fn timer_set_divide(divide: TimerDivide) { lapic_write(LAPIC_TIMER_DIVIDE, divide.encode()); }
+
+/// Starts the local APIC timer counting down from `initial`. The timer raises
+/// [LAPIC_TIMER_IRQ] when it reaches zero, and restarts if `periodic` is set.
+/// Whether the IRQ is masked is left unchanged.
+pub fn timer_start(initial: u32, periodic: bool) {
+    let masked = lapic_read(LAPIC_LVT_TIMER) & MASKED;
+    let mode = if periodic { TIMER_PERIODIC } else { 0 };
+    lapic_write(LAPIC_LVT_TIMER, LAPIC_TIMER_VECTOR as u32 | mode | masked);
+    lapic_write(LAPIC_TIMER_INITIAL, initial);
+}
+
+/// Stops the local APIC timer.
+pub fn timer_stop() { lapic_write(LAPIC_TIMER_INITIAL, 0); }
+
+/// Returns the current count of the local APIC timer.
+pub fn timer_current_count() -> u32 { lapic_read(LAPIC_TIMER_CURRENT) }
+
+/// Returns the number of spurious interrupts seen so far.
+pub fn spurious_interrupts() -> u32 { unsafe { SPURIOUS_INTERRUPTS } }
+
+/// Called for every IRQ vector.
+fn apic_interrupt(frame: &mut InterruptFrame) {
+    let irq = (frame.vector as u8).wrapping_sub(APIC_IRQ_BASE);
+    super::irq::dispatch_irq(irq, frame, |_| send_eoi());
+}
+
+/// Called for local APIC errors.
+fn apic_error(_frame: &mut InterruptFrame) {
+    // The error status register has to be written before it's read.
+    lapic_write(LAPIC_ESR, 0);
+    let esr = lapic_read(LAPIC_ESR);
+    swarnings("Local APIC error 0x");
+    swarningbnpln(&crate::u32_as_hex_u8_slice(esr));
+    send_eoi();
+}
+
+/// Called for spurious interrupts. These must not be acknowledged.
+fn apic_spurious(_frame: &mut InterruptFrame) {
+    unsafe {
+        SPURIOUS_INTERRUPTS += 1;
+    }
+}
+
+/// Sets up the local APIC of the current CPU.
+fn init_local_apic(madt: &Madt) {
+    lapic_write(LAPIC_TPR, 0);
+    lapic_write(LAPIC_LVT_TIMER, LAPIC_TIMER_VECTOR as u32 | MASKED);
+    lapic_write(LAPIC_LVT_LINT0, MASKED);
+    lapic_write(LAPIC_LVT_LINT1, MASKED);
+    for entry in madt.entries() {
+        if let MadtEntry::LocalApicNmi { flags, lint, .. } = entry {
+            let reg = if lint == 0 {
+                LAPIC_LVT_LINT0
+            } else {
+                LAPIC_LVT_LINT1
+            };
+            lapic_write(reg, DELIVERY_NMI | inti_flags(flags, false));
+        }
+    }
+    lapic_write(LAPIC_LVT_ERROR, LAPIC_ERROR_VECTOR as u32);
+    lapic_write(LAPIC_ESR, 0);
+    lapic_write(LAPIC_SVR, SPURIOUS_VECTOR as u32 | SVR_ENABLE);
+    send_eoi();
+}
+
+/// Finds the I/O APICs and ISA routes in the MADT and masks every
+/// redirection entry.
+fn init_ioapics(madt: &Madt) {
+    for (irq, isa_route) in unsafe { ISA_ROUTES.iter_mut() }.enumerate() {
+        *isa_route = match madt.isa_override(irq as u8) {
+            Some(MadtEntry::InterruptSourceOverride { gsi, flags, .. }) => Route {
+                gsi,
+                flags: inti_flags(flags, false),
+            },
+            _ => Route {
+                gsi: irq as u32,
+                flags: 0,
+            },
+        };
+    }
+
+    let mut idx = 0;
+    for entry in madt.entries() {
+        if let MadtEntry::IoApic {
+            address, gsi_base, ..
+        } = entry &&
+            idx < MAX_IOAPICS
+        {
+            let mut ioapic = IoApic {
+                address: address as usize,
+                gsi_base,
+                entries: 0,
+            };
+            ioapic.entries = ((ioapic.read(IOAPIC_VERSION) >> 16) & 0xFF) + 1;
+            for pin in 0..ioapic.entries {
+                ioapic.write_redirection(pin, MASKED, 0);
+            }
+            unsafe {
+                IOAPICS[idx] = Some(ioapic);
+            }
+            idx += 1;
+        }
+    }
+
+    for entry in madt.entries() {
+        if let MadtEntry::NmiSource { flags, gsi } = entry &&
+            let Some(ioapic) = ioapic_for(gsi)
+        {
+            ioapic.write_redirection(
+                gsi - ioapic.gsi_base,
+                DELIVERY_NMI | inti_flags(flags, false),
+                (local_apic_id() as u32) << 24,
+            );
+        }
+    }
+}
+
+/// Disables the PICs, enables the local APIC and masks every I/O APIC entry.
+/// Handlers are registered for every IRQ vector.
+pub fn init(madt: &Madt) {
+    let _irq = super::interrupts::pop_irq();
+    super::pic::disable();
+
+    unsafe {
+        LAPIC_BASE = madt.local_apic_address() as usize;
+        if msr_supported() {
+            write_msr(IA32_APIC_BASE, read_msr(IA32_APIC_BASE) | APIC_BASE_ENABLE);
+        }
+    }
+    init_local_apic(madt);
+    init_ioapics(madt);
+
+    for vector in APIC_IRQ_BASE..=LAPIC_TIMER_VECTOR {
+        if vector as u16 != USER_SYSCALL_VECTOR {
+            register_interrupt_handler(vector, apic_interrupt, false);
+        }
+    }
+    register_interrupt_handler(LAPIC_ERROR_VECTOR, apic_error, false);
+    register_interrupt_handler(SPURIOUS_VECTOR, apic_spurious, false);
+}
@@ -1,5 +1,5 @@
 //! Constants used throughout kernel code.
 #![cfg(target_arch = "x86")]
 
-/// The assembly port number to output debug messages to.
+/// The Bochs/QEMU debug port, the default output target.
 pub(super) const DEBUG_PORT: u16 = 0xE9;
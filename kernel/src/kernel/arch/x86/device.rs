@@ -0,0 +1,39 @@
+//! Platform devices of x86: the legacy devices that can't be enumerated.
+#![cfg(target_arch = "x86")]
+
+use crate::device::{DeviceKind, Driver, register_device, register_driver};
+
+/// The platform devices, which are assumed to exist. Their drivers find out
+/// if they really do.
+const PLATFORM_DEVICES: [&str; 2] = ["i8042-keyboard", "i8042-mouse"];
+
+/// The drivers of x86.
+const DRIVERS: [&Driver; 3] = [
+    &super::keyboard::KEYBOARD_DRIVER,
+    &super::mouse::MOUSE_DRIVER,
+    &super::egatext::EGA_TEXT_DRIVER,
+];
+
+/// Registers a platform device, warning if the registry is full.
+fn register_platform_device(name: &'static str) {
+    if register_device(DeviceKind::Platform { name }).is_err() {
+        super::output::swarningsln("Platform device didn't fit in the registry");
+    }
+}
+
+/// Registers the platform devices, along with the EGA text console if the
+/// bootloader set one up, and the drivers.
+#[aphrodite_proc_macros::kernel_item(DeviceInit)]
+pub fn init_devices() {
+    for name in PLATFORM_DEVICES {
+        register_platform_device(name);
+    }
+    if super::egatext::console().is_some() {
+        register_platform_device("ega-text");
+    }
+    for driver in DRIVERS {
+        if register_driver(driver).is_err() {
+            super::output::swarningsln("Driver didn't fit in the registry");
+        }
+    }
+}
@@ -1,8 +1,30 @@
-//! Stuff for writing and reading to the EGA text buffer.
+//! Stuff for writing and reading to the EGA text buffer. The console the
+//! bootloader set up is a platform device, whose driver owns the cursor.
 #![cfg(target_arch = "x86")]
 
+use super::ports::{inb, outb};
+use crate::device::{Device, DeviceMatch, Driver};
 use crate::display::Color;
 
+/// The index register of the CRT controller.
+const CRTC_INDEX: u16 = 0x3D4;
+/// The data register of the CRT controller.
+const CRTC_DATA: u16 = 0x3D5;
+/// The first scan line of the cursor and whether it's disabled.
+const CRTC_CURSOR_START: u8 = 0x0A;
+/// The last scan line of the cursor.
+const CRTC_CURSOR_END: u8 = 0x0B;
+/// The high byte of the cursor location.
+const CRTC_CURSOR_HIGH: u8 = 0x0E;
+/// The low byte of the cursor location.
+const CRTC_CURSOR_LOW: u8 = 0x0F;
+/// Disables the cursor in [CRTC_CURSOR_START].
+const CURSOR_DISABLED: u8 = 1 << 5;
+/// The first scan line of the usual underline cursor.
+const CURSOR_START_SCAN: u8 = 14;
+/// The last scan line of the usual underline cursor.
+const CURSOR_END_SCAN: u8 = 15;
+
 /// Information about the framebuffer.
 #[derive(Clone, Copy)]
 pub struct FramebufferInfo {
@@ -24,6 +46,48 @@ pub struct FramebufferInfo {
 pub const ERR_INVALID_X: i16 = -1;
 /// Returned when the provided position is invalid in the Y direction.
 pub const ERR_INVALID_Y: i16 = -2;
+/// Returned when the bootloader didn't set up an EGA text console.
+pub const ERR_NO_CONSOLE: i16 = -3;
+
+/// The console the bootloader set up, if it's EGA text.
+static mut CONSOLE: Option<FramebufferInfo> = None;
+
+/// Sets the console the bootloader set up, so that
+/// [DeviceInit](super::device::init_devices) registers it as a platform
+/// device.
+pub fn set_console(info: FramebufferInfo) {
+    unsafe {
+        CONSOLE = Some(info);
+    }
+}
+
+/// Returns the console the bootloader set up, if it's EGA text.
+pub fn console() -> Option<FramebufferInfo> { unsafe { CONSOLE } }
+
+/// The driver of the EGA text console. Text is written without the cursor, so
+/// it's hidden while the driver is bound.
+pub static EGA_TEXT_DRIVER: Driver = Driver {
+    name: "ega-text",
+    matches: &[DeviceMatch::Platform("ega-text")],
+    probe: |_: &Device| probe(),
+    remove: |_: &Device| remove(),
+};
+
+/// Hides the cursor of the console.
+fn probe() -> Result<(), crate::Error<'static>> {
+    let Some(console) = console() else {
+        return Err(crate::Error::new("no EGA text console", ERR_NO_CONSOLE));
+    };
+    console.disable_cursor();
+    Ok(())
+}
+
+/// Shows the cursor of the console again.
+fn remove() {
+    if let Some(console) = console() {
+        console.enable_cursor(CURSOR_START_SCAN, CURSOR_END_SCAN);
+    }
+}
 
 impl core::fmt::Write for FramebufferInfo {
     fn write_str(&mut self, s: &str) -> core::fmt::Result {
@@ -73,40 +137,44 @@ impl crate::display::TextDisplay for FramebufferInfo {
 
 impl FramebufferInfo {
     /// Disables the cursor.
-    pub fn disable_cursor(self) {
-        super::ports::outb(0x3D4, 0x0A);
-        super::ports::outb(0x3D5, 0x20);
-    }
+    pub fn disable_cursor(self) { write_crtc(CRTC_CURSOR_START, CURSOR_DISABLED); }
 
     /// Enables the cursor.
     pub fn enable_cursor(self, start_scan: u8, end_scan: u8) {
-        super::ports::outb(0x3D4, 0x0A);
-        super::ports::outb(0x3D5, (super::ports::inb(0x3D5) & 0xC0) | start_scan);
-
-        super::ports::outb(0x3D4, 0x0B);
-        super::ports::outb(0x3D5, (super::ports::inb(0x3D5) & 0xE0) | end_scan);
+        write_crtc(
+            CRTC_CURSOR_START,
+            (read_crtc(CRTC_CURSOR_START) & 0xC0) | start_scan,
+        );
+        write_crtc(
+            CRTC_CURSOR_END,
+            (read_crtc(CRTC_CURSOR_END) & 0xE0) | end_scan,
+        );
     }
 
     /// Sets the cursor's location.
     pub fn set_cursor_location(self, pos: (u32, u32)) {
         let addr = pos.1 * self.width + pos.0;
 
-        super::ports::outb(0x3D4, 0x0F);
-        super::ports::outb(0x3D5, (addr & 0xFF) as u8);
-        super::ports::outb(0x3D4, 0x0E);
-        super::ports::outb(0x3D5, ((addr >> 8) & 0xFF) as u8);
+        write_crtc(CRTC_CURSOR_LOW, (addr & 0xFF) as u8);
+        write_crtc(CRTC_CURSOR_HIGH, ((addr >> 8) & 0xFF) as u8);
     }
 
     /// Gets the cursor's location.
     pub fn get_cursor_location(self) -> (u32, u32) {
-        let mut addr: u32 = 0;
-
-        super::ports::outb(0x3D4, 0x0F);
-        addr |= super::ports::inb(0x3D5) as u32;
-
-        super::ports::outb(0x3D4, 0x0E);
-        addr |= (super::ports::inb(0x3D5) as u32) << 8;
+        let addr = read_crtc(CRTC_CURSOR_LOW) as u32 | (read_crtc(CRTC_CURSOR_HIGH) as u32) << 8;
 
         (addr % self.width, addr / self.width)
     }
 }
+
+/// Writes a register of the CRT controller.
+fn write_crtc(register: u8, value: u8) {
+    outb(CRTC_INDEX, register);
+    outb(CRTC_DATA, value);
+}
+
+/// Reads a register of the CRT controller.
+fn read_crtc(register: u8) -> u8 {
+    outb(CRTC_INDEX, register);
+    inb(CRTC_DATA)
+}
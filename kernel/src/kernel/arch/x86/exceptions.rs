@@ -0,0 +1,346 @@
+//! CPU exception handlers. Every exception is reported through the fatal
+//! output functions along with a full register dump before halting.
+#![cfg(target_arch = "x86")]
+#![allow(static_mut_refs)]
+
+use core::arch::{asm, global_asm};
+
+use super::gdt::{
+    DOUBLE_FAULT_TSS_SELECTOR, KERNEL_CODE_SELECTOR, KERNEL_DATA_SELECTOR, PER_CPU_SELECTOR,
+};
+use super::interrupts::{IdtBuilder, InterruptFrame};
+use super::output::*;
+use super::ports::inb;
+use super::tss::{DOUBLE_FAULT_TSS, KERNEL_TSS};
+use crate::display::TextDisplay;
+
+/// The number of exception vectors reserved by the CPU.
+pub const EXCEPTION_COUNT: usize = 32;
+
+/// The vector of the non-maskable interrupt.
+pub const NMI_VECTOR: u16 = 2;
+
+/// The vector of the double fault exception.
+pub const DOUBLE_FAULT_VECTOR: u16 = 8;
+
+/// System control port B. Bits 6 and 7 report chipset NMI sources.
+const SYSTEM_CONTROL_PORT_B: u16 = 0x61;
+
+/// The size of the stack used by the double fault task.
+const DOUBLE_FAULT_STACK_SIZE: usize = 16384;
+
+/// The names of the exceptions, indexed by vector.
+pub const EXCEPTION_NAMES: [&str; EXCEPTION_COUNT] = [
+    "Divide Error",
+    "Debug",
+    "Non-Maskable Interrupt",
+    "Breakpoint",
+    "Overflow",
+    "BOUND Range Exceeded",
+    "Invalid Opcode",
+    "Device Not Available",
+    "Double Fault",
+    "Coprocessor Segment Overrun",
+    "Invalid TSS",
+    "Segment Not Present",
+    "Stack-Segment Fault",
+    "General Protection Fault",
+    "Page Fault",
+    "Reserved",
+    "x87 Floating-Point Exception",
+    "Alignment Check",
+    "Machine Check",
+    "SIMD Floating-Point Exception",
+    "Virtualization Exception",
+    "Control Protection Exception",
+    "Reserved",
+    "Reserved",
+    "Reserved",
+    "Reserved",
+    "Reserved",
+    "Reserved",
+    "Hypervisor Injection Exception",
+    "VMM Communication Exception",
+    "Security Exception",
+    "Reserved",
+];
+
+/// Reads a control register. Has to be used in an unsafe block.
+macro_rules! read_cr {
+    ($cr:literal) => {{
+        let out: u32;
+        asm!(concat!("mov {}, ", $cr), out(reg) out, options(nomem, nostack));
+        out
+    }};
+}
+
+/// The control registers at the time of an exception.
+#[derive(Clone, Copy, Debug)]
+pub struct ControlRegisters {
+    /// cr0.
+    pub cr0: u32,
+    /// cr2. Holds the faulting address for page faults.
+    pub cr2: u32,
+    /// cr3.
+    pub cr3: u32,
+    /// cr4.
+    pub cr4: u32,
+}
+
+impl ControlRegisters {
+    /// Reads the current control registers.
+    pub fn read() -> Self {
+        unsafe {
+            ControlRegisters {
+                cr0: read_cr!("cr0"),
+                cr2: read_cr!("cr2"),
+                cr3: read_cr!("cr3"),
+                cr4: read_cr!("cr4"),
+            }
+        }
+    }
+}
+
+// The double fault task starts with the error code on top of its stack.
+global_asm!(
+    ".global aphrodite_double_fault_task",
+    "aphrodite_double_fault_task:",
+    "call {double_fault}",
+    double_fault = sym double_fault_handler,
+);
+
+unsafe extern "C" {
+    /// The entry point of the double fault task.
+    #[link_name = "aphrodite_double_fault_task"]
+    fn double_fault_task();
+}
+
+/// The stack used by the double fault task.
+static mut DOUBLE_FAULT_STACK: [u8; DOUBLE_FAULT_STACK_SIZE] = [0; DOUBLE_FAULT_STACK_SIZE];
+
+/// The display that exceptions are reported to, if any.
+static mut EXCEPTION_DISPLAY: Option<&'static dyn TextDisplay> = None;
+
+/// Outputs a register name and its value in hexadecimal through the fatal
+/// output functions.
+fn report_register(name: &str, value: u32) {
+    let hex = crate::u32_as_hex_u8_slice(value);
+    sfatalsnp(name);
+    sfatalsnp("=0x");
+    sfatalbnp(&hex);
+    sfatalsnp(" ");
+    if let Some(display) = unsafe { EXCEPTION_DISPLAY } {
+        let _ = crate::output::tfatalsnp(name, display);
+        let _ = crate::output::tfatalsnp("=0x", display);
+        let _ = crate::output::tfatalbnp(&hex, display);
+        let _ = crate::output::tfatalsnp(" ", display);
+    }
+}
+
+/// Ends a line of output started with [report_register].
+fn report_newline() {
+    sfatalsnpln("");
+    if let Some(display) = unsafe { EXCEPTION_DISPLAY } {
+        let _ = crate::output::tfatalsnpln("", display);
+    }
+}
+
+/// Outputs the header of an exception report.
+fn report_header(message: &str, vector: u32) {
+    let name = EXCEPTION_NAMES.get(vector as usize).unwrap_or(&"Unknown");
+    sfatals(message);
+    sfatalsnp(name);
+    sfatalsnp(" (vector ");
+    sfatalbnp(&crate::u32_as_u8_slice(vector));
+    sfatalsnpln(")");
+    if let Some(display) = unsafe { EXCEPTION_DISPLAY } {
+        let _ = crate::output::tfatals(message, display);
+        let _ = crate::output::tfatalsnp(name, display);
+        let _ = crate::output::tfatalsnp(" (vector ", display);
+        let _ = crate::output::tfatalbnp(&crate::u32_as_u8_slice(vector), display);
+        let _ = crate::output::tfatalsnpln(")", display);
+    }
+}
+
+/// Outputs the full contents of an [InterruptFrame] and the control registers
+/// through the fatal output functions.
+pub fn report_frame(frame: &InterruptFrame, crs: &ControlRegisters) {
+    report_registers(frame, crs, frame.stack_pointer(), frame.stack_segment());
+}
+
+/// Outputs the contents of an [InterruptFrame] and the control registers with
+/// the provided stack pointer and stack segment.
+fn report_registers(frame: &InterruptFrame, crs: &ControlRegisters, esp: u32, ss: u32) {
+    report_register("error", frame.error_code);
+    report_newline();
+
+    report_register("eax", frame.eax);
+    report_register("ebx", frame.ebx);
+    report_register("ecx", frame.ecx);
+    report_register("edx", frame.edx);
+    report_newline();
+
+    report_register("esi", frame.esi);
+    report_register("edi", frame.edi);
+    report_register("ebp", frame.ebp);
+    report_register("esp", esp);
+    report_newline();
+
+    report_register("eip", frame.eip);
+    report_register("eflags", frame.eflags);
+    report_newline();
+
+    report_register("cs", frame.cs);
+    report_register("ss", ss);
+    report_register("ds", frame.ds);
+    report_register("es", frame.es);
+    report_register("fs", frame.fs);
+    report_register("gs", frame.gs);
+    report_newline();
+
+    report_register("cr0", crs.cr0);
+    report_register("cr2", crs.cr2);
+    report_register("cr3", crs.cr3);
+    report_register("cr4", crs.cr4);
+    report_newline();
+}
+
+/// Sends the queued output, then disables interrupts and halts forever.
+fn halt() -> ! {
+    flush_output();
+    loop {
+        unsafe { asm!("cli", "hlt") }
+    }
+}
+
+/// The handler of every exception except double faults.
+fn exception_handler(frame: &mut InterruptFrame) {
+    let crs = ControlRegisters::read();
+    report_header("Unhandled exception: ", frame.vector);
+    report_frame(frame, &crs);
+    halt();
+}
+
+/// Bits of [SYSTEM_CONTROL_PORT_B] set by the chipset NMI sources: memory
+/// parity and I/O channel check errors.
+const CHIPSET_NMI_SOURCES: u8 = 0b1100_0000;
+
+/// Returns whether the chipset reports an NMI source.
+pub(super) fn chipset_nmi() -> bool { inb(SYSTEM_CONTROL_PORT_B) & CHIPSET_NMI_SOURCES != 0 }
+
+/// The handler of NMIs. Watchdog ticks are passed on; any other NMI is
+/// reported along with the state of the interrupted code, which then resumes.
+fn nmi_handler(frame: &mut InterruptFrame) {
+    if super::watchdog::watchdog_nmi(frame) {
+        return;
+    }
+    let crs = ControlRegisters::read();
+    report_header("Received ", NMI_VECTOR as u32);
+    report_register("port61", inb(SYSTEM_CONTROL_PORT_B) as u32);
+    report_newline();
+    report_frame(frame, &crs);
+}
+
+/// Reports that the watchdog saw no progress for `seconds` seconds, along with
+/// the state of the stuck code, and halts.
+pub fn report_stuck(frame: &InterruptFrame, seconds: u32) -> ! {
+    let crs = ControlRegisters::read();
+    let seconds = crate::u32_as_u8_slice(seconds);
+    let eip = crate::u32_as_hex_u8_slice(frame.eip);
+    sfatals("Watchdog: no progress for ");
+    sfatalbnp(&seconds);
+    sfatalsnp(" seconds, stuck at eip=0x");
+    sfatalbnpln(&eip);
+    if let Some(display) = unsafe { EXCEPTION_DISPLAY } {
+        let _ = crate::output::tfatals("Watchdog: no progress for ", display);
+        let _ = crate::output::tfatalbnp(&seconds, display);
+        let _ = crate::output::tfatalsnp(" seconds, stuck at eip=0x", display);
+        let _ = crate::output::tfatalbnpln(&eip, display);
+    }
+    report_frame(frame, &crs);
+    halt();
+}
+
+/// Runs as its own task when a double fault occurs. The state of the faulting
+/// code was saved in the kernel TSS by the task switch.
+extern "C" fn double_fault_handler(error_code: u32) -> ! {
+    let tss = unsafe { KERNEL_TSS };
+    let crs = ControlRegisters::read();
+    let frame = InterruptFrame {
+        gs: tss.gs,
+        fs: tss.fs,
+        es: tss.es,
+        ds: tss.ds,
+        edi: tss.edi,
+        esi: tss.esi,
+        ebp: tss.ebp,
+        esp_pushad: tss.esp,
+        ebx: tss.ebx,
+        edx: tss.edx,
+        ecx: tss.ecx,
+        eax: tss.eax,
+        vector: DOUBLE_FAULT_VECTOR as u32,
+        error_code,
+        eip: tss.eip,
+        cs: tss.cs,
+        eflags: tss.eflags,
+        user_esp: tss.esp,
+        user_ss: tss.ss,
+    };
+    report_header("Unhandled exception: ", frame.vector);
+    // The task switch saved the real stack pointer, so there is no frame on the
+    // faulting stack to derive it from.
+    report_registers(&frame, &crs, tss.esp, tss.ss);
+    halt();
+}
+
+/// Sets up the double fault TSS so that double faults run on their own stack.
+fn init_double_fault_task() {
+    unsafe {
+        DOUBLE_FAULT_TSS.eip = double_fault_task as *const () as usize as u32;
+        DOUBLE_FAULT_TSS.esp =
+            (&raw const DOUBLE_FAULT_STACK) as usize as u32 + DOUBLE_FAULT_STACK_SIZE as u32;
+        DOUBLE_FAULT_TSS.cr3 = read_cr!("cr3");
+        DOUBLE_FAULT_TSS.eflags = 0x2;
+        DOUBLE_FAULT_TSS.cs = KERNEL_CODE_SELECTOR.bits() as u32;
+        DOUBLE_FAULT_TSS.ss = KERNEL_DATA_SELECTOR.bits() as u32;
+        DOUBLE_FAULT_TSS.ds = KERNEL_DATA_SELECTOR.bits() as u32;
+        DOUBLE_FAULT_TSS.es = KERNEL_DATA_SELECTOR.bits() as u32;
+        DOUBLE_FAULT_TSS.fs = KERNEL_DATA_SELECTOR.bits() as u32;
+        DOUBLE_FAULT_TSS.gs = PER_CPU_SELECTOR.bits() as u32;
+    }
+}
+
+/// Adds entries for all [EXCEPTION_COUNT] exceptions to an [IdtBuilder].
+/// Double faults use a task gate and NMIs don't halt.
+pub fn add_exceptions(builder: &mut IdtBuilder) -> &mut IdtBuilder {
+    for vector in 0..EXCEPTION_COUNT as u16 {
+        if vector == DOUBLE_FAULT_VECTOR {
+            builder.add_task(vector, DOUBLE_FAULT_TSS_SELECTOR);
+            continue;
+        }
+        if vector == NMI_VECTOR {
+            builder.add_fn(vector, nmi_handler, false);
+            continue;
+        }
+        builder.add_fn(vector, exception_handler, false);
+    }
+    builder
+}
+
+/// Loads the boot GDT and an IDT with handlers for every exception. Exceptions
+/// are reported to the serial port and, if provided, the display.
+pub fn init_exceptions(display: Option<&'static dyn TextDisplay>) {
+    let _irq = super::interrupts::pop_irq();
+    unsafe {
+        EXCEPTION_DISPLAY = display;
+    }
+    init_double_fault_task();
+    super::tss::init_kernel_tss();
+    unsafe {
+        super::gdt::load_boot_gdt();
+    }
+    let mut builder = IdtBuilder::new();
+    add_exceptions(&mut builder);
+    super::interrupts::ActivateIDT(builder.finish());
+}
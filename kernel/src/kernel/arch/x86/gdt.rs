@@ -1,30 +1,294 @@
 //! GDT initalization.
 #![cfg(target_arch = "x86")]
+#![allow(static_mut_refs)]
 
-use core::alloc::Layout;
-
-use alloc::vec::Vec;
-
-/// Writes a series of GDT entries to an allocated section of memory and returns
-/// a pointer.
-pub unsafe fn write_gdt_entries(
-    entries: Vec<GDTEntry>,
-) -> Result<*const [u8], crate::Error<'static>> {
-    let mut mem =
-        unsafe { alloc::alloc::alloc(Layout::from_size_align(8 * entries.len(), 1).unwrap()) };
-    for ele in &entries {
-        let ele: &GDTEntry = ele;
-        unsafe { ele.write_to_addr(mem as *mut ())? }
-        mem = (mem as usize + 8) as *mut u8;
+use core::arch::asm;
+
+use super::tss::{DOUBLE_FAULT_TSS, KERNEL_TSS, TaskStateSegment};
+use crate::percpu::PerCpu;
+
+/// A privilege level, used as the RPL of a selector or the DPL of a
+/// descriptor.
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+#[repr(u8)]
+pub enum PrivilegeLevel {
+    /// The kernel.
+    Ring0 = 0,
+    /// Unused.
+    Ring1 = 1,
+    /// Unused.
+    Ring2 = 2,
+    /// Userspace.
+    Ring3 = 3,
+}
+
+/// A segment selector: an index into the GDT and a requested privilege level.
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub struct SegmentSelector(u16);
+
+impl SegmentSelector {
+    /// Creates a selector for a GDT entry.
+    pub const fn new(index: u16, rpl: PrivilegeLevel) -> Self {
+        SegmentSelector((index << 3) | rpl as u16)
+    }
+
+    /// Returns the index of the GDT entry.
+    pub const fn index(self) -> u16 { self.0 >> 3 }
+
+    /// Returns the requested privilege level.
+    pub const fn rpl(self) -> u8 { (self.0 & 0b11) as u8 }
+
+    /// Returns the raw value of the selector, as loaded into segment registers.
+    pub const fn bits(self) -> u16 { self.0 }
+}
+
+/// The selector of the kernel code segment in the flat GDT.
+pub const KERNEL_CODE_SELECTOR: SegmentSelector = SegmentSelector::new(1, PrivilegeLevel::Ring0);
+/// The selector of the kernel data segment in the flat GDT.
+pub const KERNEL_DATA_SELECTOR: SegmentSelector = SegmentSelector::new(2, PrivilegeLevel::Ring0);
+/// The selector of the user code segment in the flat GDT. SYSEXIT requires it
+/// to directly follow the kernel data segment.
+pub const USER_CODE_SELECTOR: SegmentSelector = SegmentSelector::new(3, PrivilegeLevel::Ring3);
+/// The selector of the user data segment in the flat GDT. SYSEXIT requires it
+/// to directly follow the user code segment.
+pub const USER_DATA_SELECTOR: SegmentSelector = SegmentSelector::new(4, PrivilegeLevel::Ring3);
+/// The selector of the kernel TSS in the flat GDT.
+pub const KERNEL_TSS_SELECTOR: SegmentSelector = SegmentSelector::new(5, PrivilegeLevel::Ring0);
+/// The selector of the double fault TSS in the flat GDT.
+pub const DOUBLE_FAULT_TSS_SELECTOR: SegmentSelector =
+    SegmentSelector::new(6, PrivilegeLevel::Ring0);
+/// The selector of the per-CPU data segment in the flat GDT, loaded into gs
+/// while in the kernel.
+pub const PER_CPU_SELECTOR: SegmentSelector = SegmentSelector::new(7, PrivilegeLevel::Ring0);
+/// The selector of the user TLS segment in the flat GDT, loaded into fs by
+/// userspace. Its base is the thread pointer.
+pub const USER_TLS_SELECTOR: SegmentSelector = SegmentSelector::new(8, PrivilegeLevel::Ring3);
+
+/// The highest number of entries in a [Gdt].
+pub const MAX_GDT_ENTRIES: usize = 16;
+
+/// Returned by [GdtBuilder::add_entry] when the GDT is full.
+pub const ERR_GDT_FULL: i16 = -2;
+
+/// The flag selecting 4 KiB granularity for the limit.
+const FLAG_GRANULARITY: u8 = 0b1000;
+/// The flag selecting 32-bit operands and stack.
+const FLAG_32BIT: u8 = 0b0100;
+
+/// The access byte of a present code segment without its DPL.
+const ACCESS_CODE: u8 = 0x9A;
+/// The access byte of a present data segment without its DPL.
+const ACCESS_DATA: u8 = 0x92;
+/// The access byte of an available 32-bit TSS.
+const ACCESS_TSS: u8 = 0x89;
+
+/// The GDT loaded by [load_boot_gdt]. Not allocated as it is loaded before the
+/// allocator exists.
+static mut BOOT_GDT: Gdt = Gdt {
+    entries: [0; MAX_GDT_ENTRIES],
+    len: 0,
+};
+
+/// The GDTR loaded by `lgdt`.
+#[repr(C, packed)]
+struct Gdtr {
+    /// The size of the GDT minus one.
+    size: u16,
+    /// The address of the GDT.
+    address: u32,
+}
+
+/// A GDT. Create one with [GdtBuilder].
+#[derive(Clone, Copy)]
+pub struct Gdt {
+    /// The encoded entries.
+    entries: [u64; MAX_GDT_ENTRIES],
+    /// The number of used entries.
+    len: usize,
+}
+
+impl Gdt {
+    /// Loads this GDT, reloads cs with `code` through a far return and every
+    /// other segment register except gs with `data`. gs is left for the
+    /// per-CPU data.
+    ///
+    /// # Safety
+    ///
+    /// The selectors must refer to flat ring 0 code and data segments in this
+    /// GDT. Nothing may depend on the previous segments.
+    pub unsafe fn load(&'static self, code: SegmentSelector, data: SegmentSelector) {
+        let gdtr = Gdtr {
+            size: (self.len * size_of::<u64>() - 1) as u16,
+            address: self.entries.as_ptr() as usize as u32,
+        };
+        let _irq = super::interrupts::pop_irq();
+        unsafe {
+            asm!(
+                "lgdt [{gdtr}]",
+                "push {code}",
+                "lea {tmp}, [2f]",
+                "push {tmp}",
+                "retf",
+                "2:",
+                "mov ds, {data:x}",
+                "mov es, {data:x}",
+                "mov fs, {data:x}",
+                "mov ss, {data:x}",
+                gdtr = in(reg) &raw const gdtr,
+                code = in(reg) code.bits() as u32,
+                data = in(reg) data.bits() as u32,
+                tmp = out(reg) _,
+            );
+        }
     }
 
-    Ok(core::ptr::from_raw_parts(mem, 8 * entries.len()))
+    /// Changes the base address of an entry. Segment registers holding the
+    /// selector have to be reloaded for the change to take effect.
+    pub fn set_base(&mut self, selector: SegmentSelector, base: u32) {
+        let entry = &mut self.entries[selector.index() as usize];
+        *entry &= !0xFF00_00FF_FFFF_0000;
+        *entry |= ((base as u64 & 0xFF_FFFF) << 16) | ((base as u64 >> 24) << 56);
+    }
+}
+
+/// Loads the task register.
+///
+/// # Safety
+///
+/// The selector must refer to an available TSS descriptor in the loaded GDT.
+pub unsafe fn load_task_register(tss: SegmentSelector) {
+    unsafe { asm!("ltr {0:x}", in(reg) tss.bits() as u32, options(nostack)) }
+}
+
+/// A GDT builder. Entry 0 is always the null descriptor.
+#[derive(Clone, Copy)]
+pub struct GdtBuilder {
+    /// The encoded entries.
+    entries: [u64; MAX_GDT_ENTRIES],
+    /// The number of used entries.
+    len: usize,
+}
+
+impl GdtBuilder {
+    /// Starts creating a new GDT with only the null descriptor.
+    pub const fn new() -> Self {
+        GdtBuilder {
+            entries: [0; MAX_GDT_ENTRIES],
+            len: 1,
+        }
+    }
+
+    /// Starts creating the standard flat GDT: the selectors of its entries are
+    /// [KERNEL_CODE_SELECTOR], [KERNEL_DATA_SELECTOR], [USER_CODE_SELECTOR],
+    /// [USER_DATA_SELECTOR], [KERNEL_TSS_SELECTOR],
+    /// [DOUBLE_FAULT_TSS_SELECTOR], [PER_CPU_SELECTOR] for the boot CPU and
+    /// [USER_TLS_SELECTOR].
+    pub fn flat() -> Self {
+        let mut builder = GdtBuilder::new();
+        builder.add_flat_code(PrivilegeLevel::Ring0);
+        builder.add_flat_data(PrivilegeLevel::Ring0);
+        builder.add_flat_code(PrivilegeLevel::Ring3);
+        builder.add_flat_data(PrivilegeLevel::Ring3);
+        builder.add_tss(&raw const KERNEL_TSS);
+        builder.add_tss(&raw const DOUBLE_FAULT_TSS);
+        builder.add_per_cpu(crate::percpu::boot_cpu());
+        builder.add_flat_data(PrivilegeLevel::Ring3);
+        builder
+    }
+
+    /// Adds an entry and returns its selector with an RPL equal to its DPL.
+    pub fn add_entry(&mut self, entry: GDTEntry) -> Result<SegmentSelector, crate::Error<'static>> {
+        if self.len == MAX_GDT_ENTRIES {
+            return Err(crate::Error::new("GDT is full", ERR_GDT_FULL));
+        }
+        let mut encoded = 0u64;
+        unsafe { entry.write_to_addr((&raw mut encoded) as *mut ())? }
+        self.entries[self.len] = encoded;
+        self.len += 1;
+        let rpl = match (entry.access >> 5) & 0b11 {
+            0 => PrivilegeLevel::Ring0,
+            1 => PrivilegeLevel::Ring1,
+            2 => PrivilegeLevel::Ring2,
+            _ => PrivilegeLevel::Ring3,
+        };
+        Ok(SegmentSelector::new((self.len - 1) as u16, rpl))
+    }
+
+    /// Adds a flat 4 GiB code segment.
+    pub fn add_flat_code(&mut self, dpl: PrivilegeLevel) -> SegmentSelector {
+        self.add_entry(GDTEntry::flat(ACCESS_CODE | ((dpl as u8) << 5)))
+            .unwrap()
+    }
+
+    /// Adds a flat 4 GiB data segment.
+    pub fn add_flat_data(&mut self, dpl: PrivilegeLevel) -> SegmentSelector {
+        self.add_entry(GDTEntry::flat(ACCESS_DATA | ((dpl as u8) << 5)))
+            .unwrap()
+    }
+
+    /// Adds an available TSS descriptor for a TSS.
+    pub fn add_tss(&mut self, tss: *const TaskStateSegment) -> SegmentSelector {
+        self.add_entry(GDTEntry::sized(
+            tss as usize as u32,
+            size_of::<TaskStateSegment>() as u64,
+            ACCESS_TSS,
+            0,
+        ))
+        .unwrap()
+    }
+
+    /// Adds a data segment covering the data of a CPU.
+    pub fn add_per_cpu(&mut self, cpu: *mut PerCpu) -> SegmentSelector {
+        self.add_entry(GDTEntry::sized(
+            cpu as usize as u32,
+            size_of::<PerCpu>() as u64,
+            ACCESS_DATA,
+            FLAG_32BIT,
+        ))
+        .unwrap()
+    }
+
+    /// Creates the GDT.
+    pub const fn finish(&self) -> Gdt {
+        Gdt {
+            entries: self.entries,
+            len: self.len,
+        }
+    }
+}
+
+impl Default for GdtBuilder {
+    fn default() -> Self { Self::new() }
+}
+
+/// Loads the flat GDT built by [GdtBuilder::flat], reloads every segment
+/// register, loads the task register with [KERNEL_TSS_SELECTOR] and makes gs
+/// point to the data of the boot CPU.
+///
+/// # Safety
+///
+/// Must only be called while nothing depends on the segments set up by the
+/// bootloader.
+pub(super) unsafe fn load_boot_gdt() {
+    unsafe {
+        BOOT_GDT = GdtBuilder::flat().finish();
+        BOOT_GDT.load(KERNEL_CODE_SELECTOR, KERNEL_DATA_SELECTOR);
+        load_task_register(KERNEL_TSS_SELECTOR);
+        super::percpu::init_boot_cpu();
+    }
+}
+
+/// Changes the base address of an entry of the GDT loaded by
+/// [load_boot_gdt].
+pub(super) fn set_boot_gdt_base(selector: SegmentSelector, base: u32) {
+    unsafe { BOOT_GDT.set_base(selector, base) }
 }
 
 /// A GDT entry.
 #[derive(Clone, Copy)]
 pub struct GDTEntry {
-    /// The size of the entry. Has to be less than 0xFFFFF.
+    /// The size of the entry. Has to be less than 0xFFFFF. In units of 4 KiB if
+    /// the granularity flag is set.
     pub limit: u32,
     /// The base address of the entry.
     pub base: u32,
@@ -39,6 +303,50 @@ pub struct GDTEntry {
 const GDT_WRITE_ADDR_INVALID_LIMIT: i16 = -1;
 
 impl GDTEntry {
+    /// Returns a flat 4 GiB segment with the provided access byte.
+    pub const fn flat(access: u8) -> Self {
+        GDTEntry {
+            limit: 0xFFFFF,
+            base: 0,
+            access,
+            flags: FLAG_GRANULARITY | FLAG_32BIT,
+        }
+    }
+
+    /// Returns an entry covering `length` bytes from `base`. The granularity
+    /// flag is set if the length doesn't fit a byte granular limit, in which
+    /// case the length is rounded up to 4 KiB. `flags` shouldn't contain the
+    /// granularity flag.
+    pub const fn sized(base: u32, length: u64, access: u8, flags: u8) -> Self {
+        let length = if length == 0 { 1 } else { length };
+        if length <= 0x100000 {
+            GDTEntry {
+                limit: (length - 1) as u32,
+                base,
+                access,
+                flags: flags & !FLAG_GRANULARITY,
+            }
+        } else {
+            let pages = length.div_ceil(4096);
+            GDTEntry {
+                limit: if pages > 0x100000 {
+                    0xFFFFF
+                } else {
+                    (pages - 1) as u32
+                },
+                base,
+                access,
+                flags: flags | FLAG_GRANULARITY,
+            }
+        }
+    }
+
+    /// Writes the entry as the 8 bytes the CPU reads at `ptr`. Fails if the
+    /// limit doesn't fit in 20 bits.
+    ///
+    /// # Safety
+    ///
+    /// `ptr` must be valid for writing 8 bytes.
     const unsafe fn write_to_addr(self, ptr: *mut ()) -> Result<(), crate::Error<'static>> {
         if self.limit > 0xFFFFF {
             return Err(crate::Error::new(
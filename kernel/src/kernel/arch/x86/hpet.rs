@@ -0,0 +1,258 @@
+//! The high precision event timer, found through the ACPI HPET table. Its main
+//! counter is a clocksource and its comparators can be used as clock event
+//! devices. Comparators are only routed through the I/O APICs, to global
+//! system interrupts above the ISA IRQs.
+#![cfg(target_arch = "x86")]
+#![allow(static_mut_refs)]
+
+use super::apic;
+use crate::acpi::HpetTable;
+use crate::time::{ClockEventDevice, register_clock_event_device};
+
+/// The highest number of comparators an HPET can have.
+pub const MAX_COMPARATORS: usize = 32;
+
+/// The number of ISA IRQs, which comparators aren't routed to.
+const ISA_IRQS: u8 = 16;
+
+/// The general capabilities and ID register.
+const REG_CAPABILITIES: usize = 0x000;
+/// The general configuration register.
+const REG_CONFIG: usize = 0x010;
+/// The main counter.
+const REG_COUNTER: usize = 0x0F0;
+/// The configuration register of comparator 0. The registers of the others
+/// follow every [COMPARATOR_STRIDE] bytes.
+const REG_COMPARATOR_CONFIG: usize = 0x100;
+/// The comparator value register of comparator 0.
+const REG_COMPARATOR_VALUE: usize = 0x108;
+/// The distance between the registers of two comparators.
+const COMPARATOR_STRIDE: usize = 0x20;
+
+/// Set in the capabilities if the main counter is 64 bits wide.
+const CAP_COUNTER_64BIT: u32 = 1 << 13;
+/// Starts the main counter.
+const CONFIG_ENABLE: u32 = 1 << 0;
+/// Routes comparators 0 and 1 to IRQs 0 and 8 instead of their own routes.
+const CONFIG_LEGACY_ROUTE: u32 = 1 << 1;
+
+/// Enables the interrupt of a comparator.
+const COMPARATOR_INT_ENABLE: u32 = 1 << 2;
+/// Makes a comparator periodic.
+const COMPARATOR_PERIODIC: u32 = 1 << 3;
+/// Set if a comparator can be periodic.
+const COMPARATOR_PERIODIC_CAP: u32 = 1 << 4;
+/// Makes the next write to the comparator value set the periodic
+/// accumulator.
+const COMPARATOR_VALUE_SET: u32 = 1 << 6;
+/// Makes a 64-bit comparator 32 bits wide.
+const COMPARATOR_32BIT: u32 = 1 << 8;
+/// The shift of the routed I/O APIC input.
+const COMPARATOR_ROUTE_SHIFT: u32 = 9;
+/// The I/O APIC input and the FSB enable bits.
+const COMPARATOR_ROUTE_MASK: u32 = 0b1_1111 << COMPARATOR_ROUTE_SHIFT | 1 << 14;
+
+/// The number of femtoseconds in a second.
+const FEMTOS_PER_SECOND: u64 = 1_000_000_000_000_000;
+/// The largest delta comparators are programmed with, as they are used in
+/// 32-bit mode.
+const MAX_DELTA: u64 = 0x7FFF_FFFF;
+
+/// The address of the registers, or 0 if there is no HPET.
+static mut BASE: usize = 0;
+/// The frequency of the main counter.
+static mut FREQUENCY: u64 = 0;
+/// Whether the main counter is 64 bits wide.
+static mut COUNTER_64BIT: bool = false;
+/// The number of comparators.
+static mut COMPARATOR_COUNT: u8 = 0;
+/// The IRQ of every comparator that has one.
+static mut COMPARATOR_IRQS: [Option<u8>; MAX_COMPARATORS] = [None; MAX_COMPARATORS];
+
+/// Reads a register.
+fn read(register: usize) -> u32 {
+    unsafe { core::ptr::read_volatile((BASE + register) as *const u32) }
+}
+
+/// Writes a register.
+fn write(register: usize, value: u32) {
+    unsafe { core::ptr::write_volatile((BASE + register) as *mut u32, value) }
+}
+
+/// Finds the HPET and starts its main counter. Every comparator that can be
+/// routed to an I/O APIC input is set up, and the first one is registered as
+/// a clock event device. Returns whether there is an HPET.
+pub fn init() -> bool {
+    let Some(table) = HpetTable::find() else {
+        return false;
+    };
+    if table.base_address() > usize::MAX as u64 {
+        return false;
+    }
+    let _irq = super::interrupts::pop_irq();
+    unsafe {
+        BASE = table.base_address() as usize;
+    }
+    let capabilities = read(REG_CAPABILITIES);
+    let period_fs = read(REG_CAPABILITIES + 4);
+    if period_fs == 0 || period_fs > 100_000_000 {
+        unsafe {
+            BASE = 0;
+        }
+        return false;
+    }
+    unsafe {
+        FREQUENCY = FEMTOS_PER_SECOND / period_fs as u64;
+        COUNTER_64BIT = capabilities & CAP_COUNTER_64BIT != 0;
+        COMPARATOR_COUNT = ((capabilities >> 8) & 0b1_1111) as u8 + 1;
+    }
+
+    let config = read(REG_CONFIG) & !(CONFIG_ENABLE | CONFIG_LEGACY_ROUTE);
+    write(REG_CONFIG, config);
+    write(REG_COUNTER, 0);
+    write(REG_COUNTER + 4, 0);
+    for comparator in 0..comparator_count() {
+        let comparator = Comparator(comparator);
+        comparator.write_config(
+            comparator.config() &
+                !(COMPARATOR_INT_ENABLE | COMPARATOR_PERIODIC | COMPARATOR_ROUTE_MASK) |
+                COMPARATOR_32BIT,
+        );
+        if super::irq::using_apic() {
+            comparator.route();
+        }
+    }
+    write(REG_CONFIG, config | CONFIG_ENABLE);
+
+    if let Some(comparator) = (0..comparator_count())
+        .map(Comparator)
+        .find(|comparator| comparator.irq().is_some())
+    {
+        let minimum = (frequency() / 100_000).max(table.minimum_tick() as u64);
+        register_clock_event_device(ClockEventDevice {
+            name: "hpet",
+            frequency: frequency(),
+            min_delta: minimum.max(1),
+            max_delta: MAX_DELTA,
+            rating: 200,
+            data: comparator.0 as usize,
+            program_oneshot: |data, delta| Comparator(data as u8).program_oneshot(delta as u32),
+            program_periodic: comparator.periodic_capable().then_some(
+                |data: usize, period: u64| Comparator(data as u8).program_periodic(period as u32),
+            ),
+            stop: |data| Comparator(data as u8).stop(),
+        });
+    }
+    true
+}
+
+/// Returns whether an HPET was found by [init].
+pub fn hpet_available() -> bool { unsafe { BASE != 0 } }
+
+/// Returns the frequency of the main counter, or 0 if there is no HPET.
+pub fn frequency() -> u64 { unsafe { FREQUENCY } }
+
+/// Returns whether the main counter is 64 bits wide. 32-bit counters wrap
+/// around after a few minutes at most.
+pub fn counter_64bit() -> bool { unsafe { COUNTER_64BIT } }
+
+/// Reads the main counter.
+pub fn counter() -> u64 {
+    if !counter_64bit() {
+        return read(REG_COUNTER) as u64;
+    }
+    // The high half may change between reading both halves.
+    loop {
+        let high = read(REG_COUNTER + 4);
+        let low = read(REG_COUNTER);
+        if read(REG_COUNTER + 4) == high {
+            return ((high as u64) << 32) | low as u64;
+        }
+    }
+}
+
+/// Returns the number of comparators, or 0 if there is no HPET.
+pub fn comparator_count() -> u8 { unsafe { COMPARATOR_COUNT } }
+
+/// A comparator of the HPET, used in 32-bit mode.
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub struct Comparator(pub u8);
+
+impl Comparator {
+    /// Returns the offset of a register of the comparator.
+    const fn register(self, register: usize) -> usize {
+        register + self.0 as usize * COMPARATOR_STRIDE
+    }
+
+    /// Reads the configuration register.
+    fn config(self) -> u32 { read(self.register(REG_COMPARATOR_CONFIG)) }
+
+    /// Writes the configuration register.
+    fn write_config(self, config: u32) { write(self.register(REG_COMPARATOR_CONFIG), config) }
+
+    /// Writes the comparator value.
+    fn write_value(self, value: u32) { write(self.register(REG_COMPARATOR_VALUE), value) }
+
+    /// Routes the comparator to the lowest free I/O APIC input it supports
+    /// above the ISA IRQs and registers its IRQ handler.
+    fn route(self) {
+        let routes = read(self.register(REG_COMPARATOR_CONFIG) + 4);
+        let Some(irq) = (ISA_IRQS..32u8)
+            .find(|irq| routes & (1 << irq) != 0 && !crate::irq::irq_has_handler(*irq))
+        else {
+            return;
+        };
+        apic::set_edge_triggered(irq);
+        self.write_config(
+            (self.config() & !COMPARATOR_ROUTE_MASK) | ((irq as u32) << COMPARATOR_ROUTE_SHIFT),
+        );
+        if crate::irq::register_irq_handler(irq, hpet_irq).is_ok() {
+            unsafe {
+                COMPARATOR_IRQS[self.0 as usize] = Some(irq);
+            }
+        }
+    }
+
+    /// Returns the IRQ of the comparator, if it could be routed.
+    pub fn irq(self) -> Option<u8> { unsafe { COMPARATOR_IRQS[self.0 as usize] } }
+
+    /// Returns whether the comparator can fire periodically.
+    pub fn periodic_capable(self) -> bool { self.config() & COMPARATOR_PERIODIC_CAP != 0 }
+
+    /// Makes the comparator fire once after `delta` main counter ticks.
+    pub fn program_oneshot(self, delta: u32) {
+        let _irq = super::interrupts::pop_irq();
+        self.write_config(self.config() & !COMPARATOR_PERIODIC | COMPARATOR_INT_ENABLE);
+        self.write_value((counter() as u32).wrapping_add(delta));
+    }
+
+    /// Makes the comparator fire every `period` main counter ticks. Does
+    /// nothing if it can't be periodic.
+    pub fn program_periodic(self, period: u32) {
+        if !self.periodic_capable() {
+            return;
+        }
+        let _irq = super::interrupts::pop_irq();
+        self.write_config(
+            self.config() | COMPARATOR_INT_ENABLE | COMPARATOR_PERIODIC | COMPARATOR_VALUE_SET,
+        );
+        // With the value set bit, the first write sets the time of the first
+        // interrupt and the second one the period.
+        self.write_value((counter() as u32).wrapping_add(period));
+        self.write_value(period);
+    }
+
+    /// Stops the comparator from firing.
+    pub fn stop(self) {
+        self.write_config(self.config() & !(COMPARATOR_INT_ENABLE | COMPARATOR_PERIODIC));
+    }
+}
+
+/// Called for the IRQs of the comparators.
+fn hpet_irq(irq: u8) {
+    for comparator in 0..comparator_count() {
+        if Comparator(comparator).irq() == Some(irq) {
+            crate::time::clock_event_fired("hpet", comparator as usize);
+        }
+    }
+}
@@ -2,9 +2,10 @@
 #![cfg(target_arch = "x86")]
 #![allow(static_mut_refs)]
 
-use core::alloc::{Allocator, Layout};
-use core::arch::asm;
-use core::mem::MaybeUninit;
+use core::arch::{asm, global_asm};
+use core::mem::{ManuallyDrop, MaybeUninit};
+
+use super::gdt::{KERNEL_CODE_SELECTOR, KERNEL_DATA_SELECTOR, PER_CPU_SELECTOR, SegmentSelector};
 
 /// The syscall vector.
 pub const USER_SYSCALL_VECTOR: u16 = 0xA0;
@@ -19,13 +20,22 @@ pub fn interrupts_enabled() -> bool {
             "pop {0:e}", out(reg) flags
         )
     }
-    (flags & (1 << 9)) == 0
+    (flags & (1 << 9)) != 0
 }
 
+/// Enables interrupts.
+#[aphrodite_proc_macros::kernel_item(InterruptsEnable)]
+pub fn enable_interrupts() { unsafe { asm!("sti") } }
+
 /// Disables interrupts.
 #[aphrodite_proc_macros::kernel_item(InterruptsDisable)]
 pub fn disable_interrupts() { unsafe { asm!("cli") } }
 
+/// Enables interrupts and halts until one arrives. `sti` only takes effect
+/// after `hlt`, so an interrupt can't arrive in between.
+#[aphrodite_proc_macros::kernel_item(InterruptsWait)]
+pub fn wait_for_interrupt() { unsafe { asm!("sti", "hlt", options(nomem, nostack)) } }
+
 /// PoppedInterrupts implements drop and restores the interrupts upon being
 /// dropped. This is useful in functions where you need interrupts disabled
 /// during it but also want to use functions like [Result::unwrap] or
@@ -54,7 +64,8 @@ pub fn pop_irq() -> PoppedInterrupts {
 /// Restores interrupts after a [pop_irq] call.
 #[aphrodite_proc_macros::kernel_item(InterruptsRestore)]
 pub fn restore_irq(flags: PoppedInterrupts) {
-    let flags = flags.0;
+    // Dropping the PoppedInterrupts would call this function again.
+    let flags = ManuallyDrop::new(flags).0;
     unsafe {
         asm!(
             "push {0:e}", in(reg) flags
@@ -63,54 +74,333 @@ pub fn restore_irq(flags: PoppedInterrupts) {
     }
 }
 
+/// The state of the CPU when an interrupt occurred, as pushed by the entry
+/// stubs. Handlers may modify it; the modified state is restored when the
+/// interrupt returns.
+#[repr(C)]
+#[derive(Clone, Copy, Debug)]
+pub struct InterruptFrame {
+    /// gs at the time of the interrupt.
+    pub gs: u32,
+    /// fs at the time of the interrupt.
+    pub fs: u32,
+    /// es at the time of the interrupt.
+    pub es: u32,
+    /// ds at the time of the interrupt.
+    pub ds: u32,
+    /// edi at the time of the interrupt.
+    pub edi: u32,
+    /// esi at the time of the interrupt.
+    pub esi: u32,
+    /// ebp at the time of the interrupt.
+    pub ebp: u32,
+    /// The value of esp pushed by `pushad`. Ignored when returning. Use
+    /// [InterruptFrame::stack_pointer] for the stack pointer at the time of
+    /// the interrupt.
+    pub esp_pushad: u32,
+    /// ebx at the time of the interrupt.
+    pub ebx: u32,
+    /// edx at the time of the interrupt.
+    pub edx: u32,
+    /// ecx at the time of the interrupt.
+    pub ecx: u32,
+    /// eax at the time of the interrupt.
+    pub eax: u32,
+    /// The interrupt vector.
+    pub vector: u32,
+    /// The error code pushed by the CPU, or zero if the interrupt doesn't
+    /// push one.
+    pub error_code: u32,
+    /// The instruction pointer pushed by the CPU.
+    pub eip: u32,
+    /// The code segment pushed by the CPU.
+    pub cs: u32,
+    /// The flags pushed by the CPU.
+    pub eflags: u32,
+    /// The stack pointer pushed by the CPU. Only valid if the interrupt
+    /// happened in a less privileged ring.
+    pub user_esp: u32,
+    /// The stack segment pushed by the CPU. Only valid if the interrupt
+    /// happened in a less privileged ring.
+    pub user_ss: u32,
+}
+
+impl InterruptFrame {
+    /// Returns whether the interrupt happened in a less privileged ring.
+    pub const fn from_user(&self) -> bool { self.cs & 0b11 != 0 }
+
+    /// Returns the stack pointer at the time of the interrupt.
+    pub fn stack_pointer(&self) -> u32 {
+        if self.from_user() {
+            self.user_esp
+        } else {
+            // The CPU didn't push esp and ss, so the stack pointer was right
+            // above the flags.
+            (&raw const self.user_esp) as usize as u32
+        }
+    }
+
+    /// Returns the stack segment at the time of the interrupt.
+    pub fn stack_segment(&self) -> u32 {
+        if self.from_user() {
+            self.user_ss
+        } else {
+            KERNEL_DATA_SELECTOR.bits() as u32
+        }
+    }
+}
+
+/// A function that handles an interrupt.
+pub type InterruptHandler = fn(&mut InterruptFrame);
+
+/// The size of each entry stub generated in [interrupt_stubs].
+const INTERRUPT_STUB_SIZE: usize = 16;
+
+// Generates one 16-byte stub for each vector. Vectors where the CPU doesn't
+// push an error code get a zero pushed instead so that every handler sees the
+// same frame.
+global_asm!(
+    ".global aphrodite_interrupt_stubs",
+    ".balign 16",
+    "aphrodite_interrupt_stubs:",
+    ".set interrupt_vector, 0",
+    ".rept 256",
+    ".balign 16",
+    ".if interrupt_vector == 8 || (interrupt_vector >= 10 && interrupt_vector <= 14) || interrupt_vector == 17 || interrupt_vector == 21 || interrupt_vector == 29 || interrupt_vector == 30",
+    ".else",
+    "push 0",
+    ".endif",
+    "push interrupt_vector",
+    "jmp aphrodite_interrupt_common",
+    ".set interrupt_vector, interrupt_vector + 1",
+    ".endr",
+    "",
+    "aphrodite_interrupt_common:",
+    "pushad",
+    "mov eax, ds",
+    "push eax",
+    "mov eax, es",
+    "push eax",
+    "mov eax, fs",
+    "push eax",
+    "mov eax, gs",
+    "push eax",
+    "mov ax, {data}",
+    "mov ds, ax",
+    "mov es, ax",
+    "mov ax, {per_cpu}",
+    "mov gs, ax",
+    "cld",
+    "push esp",
+    "call {dispatch}",
+    // The dispatcher returns the frame to return through.
+    "mov esp, eax",
+    "pop eax",
+    "mov gs, ax",
+    "pop eax",
+    "mov fs, ax",
+    "pop eax",
+    "mov es, ax",
+    "pop eax",
+    "mov ds, ax",
+    "popad",
+    "add esp, 8",
+    "iretd",
+    data = const KERNEL_DATA_SELECTOR.bits(),
+    per_cpu = const PER_CPU_SELECTOR.bits(),
+    dispatch = sym interrupt_dispatch,
+);
+
+unsafe extern "C" {
+    /// The first of the 256 interrupt entry stubs. Each stub is
+    /// [INTERRUPT_STUB_SIZE] bytes long.
+    #[link_name = "aphrodite_interrupt_stubs"]
+    fn interrupt_stubs();
+}
+
+/// Returns the address of the entry stub for a vector.
+fn stub_address(vector: u8) -> u32 {
+    (interrupt_stubs as *const () as usize + vector as usize * INTERRUPT_STUB_SIZE) as u32
+}
+
+/// The handlers of each vector.
+static mut INTERRUPT_HANDLERS: [Option<InterruptHandler>; 256] = [None; 256];
+
+/// The frame to return through instead of the interrupted one. See
+/// [set_return_frame].
+static mut RETURN_FRAME: *mut InterruptFrame = core::ptr::null_mut();
+
+/// Makes the current interrupt return through the provided frame instead of
+/// the one passed to its handler. Used for switching contexts.
+///
+/// # Safety
+///
+/// The frame has to be a complete [InterruptFrame] on a stack that stays
+/// valid until the interrupt returns through it, and this has to be called
+/// from an interrupt handler with interrupts disabled.
+pub unsafe fn set_return_frame(frame: *mut InterruptFrame) {
+    unsafe {
+        RETURN_FRAME = frame;
+    }
+}
+
+/// Called by the interrupt entry stubs. Returns the frame to return through.
+extern "C" fn interrupt_dispatch(frame: &mut InterruptFrame) -> *mut InterruptFrame {
+    match unsafe { INTERRUPT_HANDLERS[frame.vector as usize & 0xFF] } {
+        Some(handler) => handler(frame),
+        None => unhandled_interrupt(frame),
+    }
+    let next = unsafe { core::mem::take(&mut RETURN_FRAME) };
+    if next.is_null() { frame } else { next }
+}
+
+/// Called for vectors without a handler. Stray IRQs still get an end of
+/// interrupt, so that their line isn't blocked.
+fn unhandled_interrupt(frame: &mut InterruptFrame) {
+    super::output::swarnings("Unhandled interrupt ");
+    super::output::swarningbnpln(&crate::u32_as_u8_slice(frame.vector));
+    let vector = frame.vector as u8;
+    if super::irq::using_apic() {
+        // The disabled PICs' vectors below the APIC's never came from the local
+        // APIC, so acknowledging them would end an unrelated interrupt.
+        if vector >= super::apic::APIC_IRQ_BASE && vector != super::apic::SPURIOUS_VECTOR {
+            super::apic::send_eoi();
+        }
+    } else if (super::pic::PIC1_OFFSET..super::pic::PIC1_OFFSET + super::pic::PIC_IRQ_COUNT)
+        .contains(&vector)
+    {
+        super::pic::send_stray_eoi(vector - super::pic::PIC1_OFFSET);
+    }
+}
+
+/// Sets the handler of a vector in the active IDT. The vector's gate is
+/// replaced by an interrupt gate to the vector's entry stub.
+pub fn register_interrupt_handler(vector: u8, handler: InterruptHandler, user_callable: bool) {
+    let _irq = pop_irq();
+    unsafe {
+        INTERRUPT_HANDLERS[vector as usize] = Some(handler);
+        IDT_ENTRIES[vector as usize] =
+            GateType::Interrupt.encode(stub_address(vector), user_callable);
+    }
+}
+
+/// Removes the handler of a vector. Interrupts on the vector are reported as
+/// unhandled afterwards.
+pub fn unregister_interrupt_handler(vector: u8) {
+    let _irq = pop_irq();
+    unsafe {
+        INTERRUPT_HANDLERS[vector as usize] = None;
+    }
+}
+
 /// The IDTR. Used internally in [load_idt].
 #[repr(C, packed)]
 struct Idtr {
-    base: *const u8,
-    size: usize,
+    /// The size of the IDT minus one.
+    size: u16,
+    /// The address of the IDT.
+    base: u32,
 }
 
-unsafe impl Send for Idtr {}
-unsafe impl Sync for Idtr {}
-
 /// Loads an interrupt descriptor table.
 fn load_idt(base: *const u8, size: usize) {
     static mut IDTR: MaybeUninit<Idtr> = MaybeUninit::uninit();
     unsafe {
-        IDTR.write(Idtr { base, size });
+        IDTR.write(Idtr {
+            size: (size - 1) as u16,
+            base: base as usize as u32,
+        });
+    }
+    unsafe { asm!("lidt [{}]", in(reg) IDTR.as_ptr() as usize) }
+}
+
+/// The memory that the active IDT is written to. Not allocated as exceptions
+/// have to be handled before the allocator is initalized.
+static mut IDT_ENTRIES: [u64; 256] = [0; 256];
+
+/// The kind of gate used for an IDT entry.
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum GateType {
+    /// A 32-bit interrupt gate. Interrupts are disabled upon entry.
+    Interrupt,
+    /// A 32-bit trap gate. Interrupts are left as they were upon entry.
+    Trap,
+    /// A task gate. Switches to the task whose TSS has the provided selector.
+    Task(SegmentSelector),
+}
+
+impl GateType {
+    /// Encodes an IDT entry for this gate type.
+    const fn encode(self, offset: u32, user_callable: bool) -> u64 {
+        let (selector, gate_type, offset) = match self {
+            GateType::Interrupt => (KERNEL_CODE_SELECTOR.bits(), 0xE, offset),
+            GateType::Trap => (KERNEL_CODE_SELECTOR.bits(), 0xF, offset),
+            GateType::Task(selector) => (selector.bits(), 0x5, 0),
+        };
+        let dpl = if user_callable { 3u64 } else { 0u64 };
+
+        (offset as u64 & 0xFFFF) |
+            ((selector as u64) << 16) |
+            (gate_type << 40) |
+            (dpl << 45) |
+            (1 << 47) |
+            ((offset as u64 >> 16) << 48)
     }
-    unsafe { asm!("lidt {}", in(reg) IDTR.as_ptr() as usize) }
 }
 
-/// Activate an IDT.
+/// Activate an IDT. Every vector without an entry in the IDT gets an interrupt
+/// gate to its entry stub, so handlers can be added later with
+/// [register_interrupt_handler].
 #[aphrodite_proc_macros::kernel_item(ActivateIDT)]
-fn activate_idt(idt: Idt, alloc: crate::mem::MemoryMapAlloc) {
-    let _mem = alloc
-        .allocate(unsafe { Layout::from_size_align_unchecked(8 * idt.len, 1) })
-        .unwrap()
-        .as_mut_ptr();
-    for i in 0..idt.len {
-        let _vector = idt.vectors[i];
-        let _func = unsafe { idt.funcs[i].assume_init() } as usize as u64;
-        let _user_callable = idt.user_callable[i];
+fn activate_idt(idt: Idt) {
+    let _irq = pop_irq();
+    unsafe {
+        for vector in 0..=255u8 {
+            IDT_ENTRIES[vector as usize] = GateType::Interrupt.encode(stub_address(vector), false);
+        }
+        for i in 0..idt.len {
+            let vector = idt.vectors[i] as usize;
+            IDT_ENTRIES[vector] = idt.gates[i].encode(idt.entries[i], idt.user_callable[i]);
+            INTERRUPT_HANDLERS[vector] = idt.handlers[i];
+        }
+        load_idt(
+            (&raw const IDT_ENTRIES) as *const u8,
+            size_of::<[u64; 256]>(),
+        );
     }
 }
 
 /// An Interrupt Descriptor Table.
 #[derive(Clone, Copy)]
 pub struct Idt {
+    /// The vector of each entry.
     vectors: [u16; 256],
-    funcs: [MaybeUninit<fn()>; 256],
+    /// The address each entry's gate points to, or 0 for task gates.
+    entries: [u32; 256],
+    /// The Rust handler of each entry, if it goes through an entry stub.
+    handlers: [Option<InterruptHandler>; 256],
+    /// The gate type of each entry.
+    gates: [GateType; 256],
+    /// Whether each entry can be raised from ring 3.
     user_callable: [bool; 256],
+    /// The number of entries.
     len: usize,
 }
 
 /// A builder of an [Idt].
 #[derive(Clone, Copy)]
 pub struct IdtBuilder {
+    /// The vector of each entry.
     vectors: [u16; 256],
-    funcs: [MaybeUninit<fn()>; 256],
+    /// The address each entry's gate points to, or 0 for task gates.
+    entries: [u32; 256],
+    /// The Rust handler of each entry, if it goes through an entry stub.
+    handlers: [Option<InterruptHandler>; 256],
+    /// The gate type of each entry.
+    gates: [GateType; 256],
+    /// Whether each entry can be raised from ring 3.
     user_callable: [bool; 256],
+    /// The number of entries added so far.
     idx: usize,
 }
 
@@ -119,15 +409,53 @@ impl IdtBuilder {
     pub fn new() -> Self {
         IdtBuilder {
             vectors: [0; 256],
-            funcs: [MaybeUninit::uninit(); 256],
+            entries: [0; 256],
+            handlers: [None; 256],
+            gates: [GateType::Interrupt; 256],
             user_callable: [false; 256],
             idx: 0,
         }
     }
-    /// Add a function to this IdtBuilder.
-    pub fn add_fn(&mut self, vector: u16, func: fn(), user_callable: bool) -> &mut Self {
+    /// Add a handler to this IdtBuilder. The handler is called from the
+    /// vector's entry stub, which saves and restores the interrupted state.
+    pub fn add_fn(
+        &mut self,
+        vector: u16,
+        func: InterruptHandler,
+        user_callable: bool,
+    ) -> &mut Self {
+        self.add(
+            vector,
+            stub_address(vector as u8),
+            GateType::Interrupt,
+            user_callable,
+        );
+        self.handlers[self.idx - 1] = Some(func);
+        self
+    }
+    /// Add a raw entry point to this IdtBuilder instead of a handler. The entry
+    /// point is jumped to directly by the CPU, so it has to preserve all
+    /// registers and return with `iret`.
+    pub fn add_entry(
+        &mut self,
+        vector: u16,
+        entry: unsafe extern "C" fn(),
+        gate: GateType,
+        user_callable: bool,
+    ) -> &mut Self {
+        self.add(vector, entry as usize as u32, gate, user_callable)
+    }
+    /// Add a task gate to this IdtBuilder that switches to the task whose TSS
+    /// has the provided selector.
+    pub fn add_task(&mut self, vector: u16, tss_selector: SegmentSelector) -> &mut Self {
+        self.add(vector, 0, GateType::Task(tss_selector), false)
+    }
+    /// Adds an entry to this IdtBuilder.
+    fn add(&mut self, vector: u16, entry: u32, gate: GateType, user_callable: bool) -> &mut Self {
         self.vectors[self.idx] = vector;
-        self.funcs[self.idx].write(func);
+        self.entries[self.idx] = entry;
+        self.handlers[self.idx] = None;
+        self.gates[self.idx] = gate;
         self.user_callable[self.idx] = user_callable;
         self.idx += 1;
         self
@@ -136,7 +464,9 @@ impl IdtBuilder {
     pub fn finish(&self) -> Idt {
         Idt {
             vectors: self.vectors,
-            funcs: self.funcs,
+            entries: self.entries,
+            handlers: self.handlers,
+            gates: self.gates,
             user_callable: self.user_callable,
             len: self.idx,
         }
@@ -144,12 +474,5 @@ impl IdtBuilder {
 }
 
 impl Default for IdtBuilder {
-    fn default() -> Self {
-        IdtBuilder {
-            vectors: [0; 256],
-            funcs: [MaybeUninit::uninit(); 256],
-            user_callable: [false; 256],
-            idx: 0,
-        }
-    }
+    fn default() -> Self { Self::new() }
 }
@@ -0,0 +1,73 @@
+//! Glue between [crate::irq] and the x86 interrupt controllers. The APICs are
+//! used if the CPU has a local APIC and ACPI provides a MADT; otherwise the
+//! 8259 PICs are used.
+#![cfg(target_arch = "x86")]
+
+use super::interrupts::InterruptFrame;
+use super::output::*;
+use super::{apic, pic};
+
+/// Whether the APICs are used instead of the PICs.
+static mut USING_APIC: bool = false;
+
+/// The frame of the IRQ currently being handled, if any.
+static mut IRQ_FRAME: *const InterruptFrame = core::ptr::null();
+
+/// Returns whether the APICs are used instead of the PICs.
+pub fn using_apic() -> bool { unsafe { USING_APIC } }
+
+/// Initalizes the interrupt controller. Every IRQ starts masked.
+#[aphrodite_proc_macros::kernel_item(IrqInit)]
+pub fn init_irq_controller() {
+    if apic::apic_supported() &&
+        let Some(madt) = crate::acpi::Madt::find()
+    {
+        apic::init(&madt);
+        unsafe {
+            USING_APIC = true;
+        }
+        sdebugsln("Using the APICs for IRQs");
+    } else {
+        pic::init();
+        sdebugsln("Using the 8259 PICs for IRQs");
+    }
+}
+
+/// Masks an IRQ so that it isn't delivered.
+#[aphrodite_proc_macros::kernel_item(IrqMask)]
+pub fn mask_irq(irq: u8) {
+    if using_apic() {
+        apic::mask(irq);
+    } else {
+        pic::mask(irq);
+    }
+}
+
+/// Unmasks an IRQ so that it is delivered.
+#[aphrodite_proc_macros::kernel_item(IrqUnmask)]
+pub fn unmask_irq(irq: u8) {
+    if using_apic() {
+        apic::unmask(irq);
+    } else {
+        pic::unmask(irq);
+    }
+}
+
+/// Returns the frame of the IRQ currently being handled, if any.
+pub fn current_irq_frame() -> Option<&'static InterruptFrame> { unsafe { IRQ_FRAME.as_ref() } }
+
+/// Passes an IRQ that isn't spurious to [crate::irq] and acknowledges it with
+/// `eoi`.
+pub(super) fn dispatch_irq(irq: u8, frame: &InterruptFrame, eoi: fn(u8)) {
+    crate::irq::irq_enter();
+    let previous = unsafe { IRQ_FRAME };
+    unsafe {
+        IRQ_FRAME = frame;
+    }
+    crate::irq::handle_irq(irq);
+    unsafe {
+        IRQ_FRAME = previous;
+    }
+    eoi(irq);
+    crate::irq::irq_exit();
+}
@@ -0,0 +1,535 @@
+//! The keyboard on the first PS/2 port. Scancodes arriving on IRQ 1 are
+//! decoded from set 1, or from set 2 if the controller doesn't translate them,
+//! and reported to [crate::keyboard].
+#![cfg(target_arch = "x86")]
+#![allow(static_mut_refs)]
+
+use super::ps2::{self, DEVICE_ACK, DEVICE_ENABLE_SCANNING, DEVICE_RESEND, Ps2Port};
+use crate::device::{Device, DeviceMatch, Driver};
+use crate::keyboard::{KeyCode, Modifiers};
+
+/// Returned by [init] if there is no first PS/2 port.
+pub const ERR_NO_KEYBOARD: i16 = -1;
+
+/// Sets the lock LEDs to the following byte.
+const KEYBOARD_SET_LEDS: u8 = 0xED;
+/// Gets or sets the scancode set, with the set or 0 as the following byte.
+const KEYBOARD_SCANCODE_SET: u8 = 0xF0;
+
+/// The scroll lock LED.
+const LED_SCROLL_LOCK: u8 = 1 << 0;
+/// The num lock LED.
+const LED_NUM_LOCK: u8 = 1 << 1;
+/// The caps lock LED.
+const LED_CAPS_LOCK: u8 = 1 << 2;
+
+/// Starts an extended scancode.
+const PREFIX_EXTENDED: u8 = 0xE0;
+/// Starts the scancode of the pause key, which has no release.
+const PREFIX_PAUSE: u8 = 0xE1;
+/// Marks a release in set 2.
+const PREFIX_RELEASE: u8 = 0xF0;
+/// Sent by keyboards on a key detection error or a buffer overrun.
+const KEYBOARD_ERROR: [u8; 2] = [0x00, 0xFF];
+
+/// A scancode set the keyboard can send.
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum ScancodeSet {
+    /// The set of the original PC keyboard. Releases have bit 7 set.
+    Set1,
+    /// The set of the PC/AT keyboard. Releases are prefixed with 0xF0.
+    Set2,
+}
+
+/// Turns scancode bytes into key presses and releases.
+#[derive(Clone, Copy, Debug)]
+pub struct ScancodeDecoder {
+    /// The set being decoded.
+    set: ScancodeSet,
+    /// Whether an extended prefix was received.
+    extended: bool,
+    /// Whether a set 2 release prefix was received.
+    release: bool,
+    /// The number of bytes of the pause scancode still to skip.
+    pause_bytes: u8,
+}
+
+impl ScancodeDecoder {
+    /// Creates a decoder for a scancode set.
+    pub const fn new(set: ScancodeSet) -> Self {
+        ScancodeDecoder {
+            set,
+            extended: false,
+            release: false,
+            pause_bytes: 0,
+        }
+    }
+
+    /// Returns the set being decoded.
+    pub const fn set(&self) -> ScancodeSet { self.set }
+
+    /// Decodes the next byte. Returns the key and whether it was pressed once
+    /// a scancode is complete. Pause is only ever pressed.
+    pub fn feed(&mut self, byte: u8) -> Option<(KeyCode, bool)> {
+        if self.pause_bytes != 0 {
+            self.pause_bytes -= 1;
+            return (self.pause_bytes == 0).then_some((KeyCode::Pause, true));
+        }
+        match (self.set, byte) {
+            (_, PREFIX_EXTENDED) => {
+                self.extended = true;
+                return None;
+            },
+            (ScancodeSet::Set1, PREFIX_PAUSE) => {
+                self.pause_bytes = 5;
+                return None;
+            },
+            (ScancodeSet::Set2, PREFIX_PAUSE) => {
+                self.pause_bytes = 7;
+                return None;
+            },
+            (ScancodeSet::Set2, PREFIX_RELEASE) => {
+                self.release = true;
+                return None;
+            },
+            _ => {},
+        }
+        let extended = core::mem::take(&mut self.extended);
+        let release = core::mem::take(&mut self.release);
+        match self.set {
+            ScancodeSet::Set1 => {
+                let key = if extended {
+                    set1_extended_key(byte & 0x7F)
+                } else {
+                    set1_key(byte & 0x7F)
+                };
+                Some((key?, byte & 0x80 == 0))
+            },
+            ScancodeSet::Set2 => {
+                let key = if extended {
+                    set2_extended_key(byte)
+                } else {
+                    set2_key(byte)
+                };
+                Some((key?, !release))
+            },
+        }
+    }
+}
+
+/// Returns the key of a set 1 scancode without its release bit.
+fn set1_key(code: u8) -> Option<KeyCode> {
+    use KeyCode::*;
+    Some(match code {
+        0x01 => Escape,
+        0x02 => Key1,
+        0x03 => Key2,
+        0x04 => Key3,
+        0x05 => Key4,
+        0x06 => Key5,
+        0x07 => Key6,
+        0x08 => Key7,
+        0x09 => Key8,
+        0x0A => Key9,
+        0x0B => Key0,
+        0x0C => Minus,
+        0x0D => Equals,
+        0x0E => Backspace,
+        0x0F => Tab,
+        0x10 => Q,
+        0x11 => W,
+        0x12 => E,
+        0x13 => R,
+        0x14 => T,
+        0x15 => Y,
+        0x16 => U,
+        0x17 => I,
+        0x18 => O,
+        0x19 => P,
+        0x1A => LeftBracket,
+        0x1B => RightBracket,
+        0x1C => Enter,
+        0x1D => LeftCtrl,
+        0x1E => A,
+        0x1F => S,
+        0x20 => D,
+        0x21 => F,
+        0x22 => G,
+        0x23 => H,
+        0x24 => J,
+        0x25 => K,
+        0x26 => L,
+        0x27 => Semicolon,
+        0x28 => Apostrophe,
+        0x29 => Grave,
+        0x2A => LeftShift,
+        0x2B => Backslash,
+        0x2C => Z,
+        0x2D => X,
+        0x2E => C,
+        0x2F => V,
+        0x30 => B,
+        0x31 => N,
+        0x32 => M,
+        0x33 => Comma,
+        0x34 => Period,
+        0x35 => Slash,
+        0x36 => RightShift,
+        0x37 => KeypadMultiply,
+        0x38 => LeftAlt,
+        0x39 => Space,
+        0x3A => CapsLock,
+        0x3B => F1,
+        0x3C => F2,
+        0x3D => F3,
+        0x3E => F4,
+        0x3F => F5,
+        0x40 => F6,
+        0x41 => F7,
+        0x42 => F8,
+        0x43 => F9,
+        0x44 => F10,
+        0x45 => NumLock,
+        0x46 => ScrollLock,
+        0x47 => Keypad7,
+        0x48 => Keypad8,
+        0x49 => Keypad9,
+        0x4A => KeypadMinus,
+        0x4B => Keypad4,
+        0x4C => Keypad5,
+        0x4D => Keypad6,
+        0x4E => KeypadPlus,
+        0x4F => Keypad1,
+        0x50 => Keypad2,
+        0x51 => Keypad3,
+        0x52 => Keypad0,
+        0x53 => KeypadPeriod,
+        0x56 => NonUsBackslash,
+        0x57 => F11,
+        0x58 => F12,
+        _ => return None,
+    })
+}
+
+/// Returns the key of an extended set 1 scancode without its release bit.
+/// The fake shifts some keyboards send around extended keys are ignored.
+fn set1_extended_key(code: u8) -> Option<KeyCode> {
+    use KeyCode::*;
+    Some(match code {
+        0x1C => KeypadEnter,
+        0x1D => RightCtrl,
+        0x35 => KeypadDivide,
+        0x37 => PrintScreen,
+        0x38 => RightAlt,
+        0x47 => Home,
+        0x48 => Up,
+        0x49 => PageUp,
+        0x4B => Left,
+        0x4D => Right,
+        0x4F => End,
+        0x50 => Down,
+        0x51 => PageDown,
+        0x52 => Insert,
+        0x53 => Delete,
+        0x5B => LeftSuper,
+        0x5C => RightSuper,
+        0x5D => Menu,
+        _ => return None,
+    })
+}
+
+/// Returns the key of a set 2 scancode.
+fn set2_key(code: u8) -> Option<KeyCode> {
+    use KeyCode::*;
+    Some(match code {
+        0x01 => F9,
+        0x03 => F5,
+        0x04 => F3,
+        0x05 => F1,
+        0x06 => F2,
+        0x07 => F12,
+        0x09 => F10,
+        0x0A => F8,
+        0x0B => F6,
+        0x0C => F4,
+        0x0D => Tab,
+        0x0E => Grave,
+        0x11 => LeftAlt,
+        0x12 => LeftShift,
+        0x14 => LeftCtrl,
+        0x15 => Q,
+        0x16 => Key1,
+        0x1A => Z,
+        0x1B => S,
+        0x1C => A,
+        0x1D => W,
+        0x1E => Key2,
+        0x21 => C,
+        0x22 => X,
+        0x23 => D,
+        0x24 => E,
+        0x25 => Key4,
+        0x26 => Key3,
+        0x29 => Space,
+        0x2A => V,
+        0x2B => F,
+        0x2C => T,
+        0x2D => R,
+        0x2E => Key5,
+        0x31 => N,
+        0x32 => B,
+        0x33 => H,
+        0x34 => G,
+        0x35 => Y,
+        0x36 => Key6,
+        0x3A => M,
+        0x3B => J,
+        0x3C => U,
+        0x3D => Key7,
+        0x3E => Key8,
+        0x41 => Comma,
+        0x42 => K,
+        0x43 => I,
+        0x44 => O,
+        0x45 => Key0,
+        0x46 => Key9,
+        0x49 => Period,
+        0x4A => Slash,
+        0x4B => L,
+        0x4C => Semicolon,
+        0x4D => P,
+        0x4E => Minus,
+        0x52 => Apostrophe,
+        0x54 => LeftBracket,
+        0x55 => Equals,
+        0x58 => CapsLock,
+        0x59 => RightShift,
+        0x5A => Enter,
+        0x5B => RightBracket,
+        0x5D => Backslash,
+        0x61 => NonUsBackslash,
+        0x66 => Backspace,
+        0x69 => Keypad1,
+        0x6B => Keypad4,
+        0x6C => Keypad7,
+        0x70 => Keypad0,
+        0x71 => KeypadPeriod,
+        0x72 => Keypad2,
+        0x73 => Keypad5,
+        0x74 => Keypad6,
+        0x75 => Keypad8,
+        0x76 => Escape,
+        0x77 => NumLock,
+        0x78 => F11,
+        0x79 => KeypadPlus,
+        0x7A => Keypad3,
+        0x7B => KeypadMinus,
+        0x7C => KeypadMultiply,
+        0x7D => Keypad9,
+        0x7E => ScrollLock,
+        0x83 => F7,
+        _ => return None,
+    })
+}
+
+/// Returns the key of an extended set 2 scancode. The fake shifts some
+/// keyboards send around extended keys are ignored.
+fn set2_extended_key(code: u8) -> Option<KeyCode> {
+    use KeyCode::*;
+    Some(match code {
+        0x11 => RightAlt,
+        0x14 => RightCtrl,
+        0x1F => LeftSuper,
+        0x27 => RightSuper,
+        0x2F => Menu,
+        0x4A => KeypadDivide,
+        0x5A => KeypadEnter,
+        0x69 => End,
+        0x6B => Left,
+        0x6C => Home,
+        0x70 => Insert,
+        0x71 => Delete,
+        0x72 => Down,
+        0x74 => Right,
+        0x75 => Up,
+        0x7A => PageDown,
+        0x7C => PrintScreen,
+        0x7D => PageUp,
+        _ => return None,
+    })
+}
+
+/// The progress of updating the LEDs from the IRQ handler.
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+enum LedUpdate {
+    /// No update is in progress.
+    Idle,
+    /// [KEYBOARD_SET_LEDS] was sent and awaits its acknowledgement.
+    Command,
+    /// The LED byte was sent and awaits its acknowledgement.
+    Data,
+}
+
+/// Decodes the scancodes received on IRQ 1.
+static mut DECODER: ScancodeDecoder = ScancodeDecoder::new(ScancodeSet::Set1);
+/// Whether a keyboard was found by [init].
+static mut KEYBOARD_PRESENT: bool = false;
+/// The progress of the current LED update.
+static mut LED_UPDATE: LedUpdate = LedUpdate::Idle;
+/// The LEDs that should be on.
+static mut LEDS: u8 = 0;
+/// Whether [LEDS] changed since the current update started.
+static mut LEDS_CHANGED: bool = false;
+
+/// Returns the LED byte for the active locks.
+fn leds(modifiers: Modifiers) -> u8 {
+    let mut leds = 0;
+    if modifiers.contains(Modifiers::SCROLL_LOCK) {
+        leds |= LED_SCROLL_LOCK;
+    }
+    if modifiers.contains(Modifiers::NUM_LOCK) {
+        leds |= LED_NUM_LOCK;
+    }
+    if modifiers.contains(Modifiers::CAPS_LOCK) {
+        leds |= LED_CAPS_LOCK;
+    }
+    leds
+}
+
+/// Asks the keyboard which scancode set it sends, switching it to set 2 if
+/// it's one the decoder doesn't know.
+fn detect_scancode_set() -> Result<ScancodeSet, crate::Error<'static>> {
+    let port = Ps2Port::First;
+    if ps2::translation_enabled()? {
+        return Ok(ScancodeSet::Set1);
+    }
+    port.command(KEYBOARD_SCANCODE_SET)?;
+    port.command(0)?;
+    match port.read(ps2::DEVICE_TIMEOUT_US)? {
+        1 => Ok(ScancodeSet::Set1),
+        2 => Ok(ScancodeSet::Set2),
+        _ => {
+            port.command(KEYBOARD_SCANCODE_SET)?;
+            port.command(2)?;
+            Ok(ScancodeSet::Set2)
+        },
+    }
+}
+
+/// Initalizes the controller, resets the keyboard and starts handling IRQ 1.
+pub fn init() -> Result<(), crate::Error<'static>> {
+    ps2::init()?;
+    let port = Ps2Port::First;
+    if !port.present() {
+        return Err(crate::Error::new("no keyboard port", ERR_NO_KEYBOARD));
+    }
+    let _irq = super::interrupts::pop_irq();
+    port.reset()?;
+    let set = detect_scancode_set()?;
+    let leds = leds(crate::keyboard::modifiers());
+    port.command(KEYBOARD_SET_LEDS)?;
+    port.command(leds)?;
+    port.command(DEVICE_ENABLE_SCANNING)?;
+    unsafe {
+        DECODER = ScancodeDecoder::new(set);
+        LEDS = leds;
+        LED_UPDATE = LedUpdate::Idle;
+    }
+    unsafe {
+        KEYBOARD_PRESENT = true;
+    }
+    port.enable_irq()
+}
+
+/// Returns whether a keyboard was found.
+pub fn keyboard_present() -> bool { unsafe { KEYBOARD_PRESENT } }
+
+/// Returns the scancode set being decoded.
+pub fn scancode_set() -> ScancodeSet { unsafe { DECODER.set() } }
+
+/// Handles a byte from the keyboard that answers an LED update. Returns
+/// whether it was one.
+fn handle_led_answer(byte: u8) -> bool {
+    let port = Ps2Port::First;
+    unsafe {
+        match (LED_UPDATE, byte) {
+            (LedUpdate::Idle, _) => false,
+            (LedUpdate::Command, DEVICE_ACK) => {
+                LED_UPDATE = LedUpdate::Data;
+                LEDS_CHANGED = false;
+                let _ = port.write(LEDS);
+                true
+            },
+            (LedUpdate::Data, DEVICE_ACK) => {
+                if LEDS_CHANGED {
+                    LED_UPDATE = LedUpdate::Command;
+                    let _ = port.write(KEYBOARD_SET_LEDS);
+                } else {
+                    LED_UPDATE = LedUpdate::Idle;
+                }
+                true
+            },
+            (LedUpdate::Command, DEVICE_RESEND) => {
+                let _ = port.write(KEYBOARD_SET_LEDS);
+                true
+            },
+            (LedUpdate::Data, DEVICE_RESEND) => {
+                let _ = port.write(LEDS);
+                true
+            },
+            _ => false,
+        }
+    }
+}
+
+/// Sets the lock LEDs of the keyboard. The update finishes in the IRQ
+/// handler, so this can be called from it.
+#[aphrodite_proc_macros::kernel_item(KeyboardSetLeds)]
+pub fn set_leds(modifiers: Modifiers) {
+    if !keyboard_present() {
+        return;
+    }
+    let _irq = super::interrupts::pop_irq();
+    unsafe {
+        LEDS = leds(modifiers);
+        if LED_UPDATE == LedUpdate::Idle {
+            LED_UPDATE = LedUpdate::Command;
+            let _ = Ps2Port::First.write(KEYBOARD_SET_LEDS);
+        } else {
+            LEDS_CHANGED = true;
+        }
+    }
+}
+
+/// Handles a byte from the first port. Called by the PS/2 IRQ handler.
+pub(super) fn handle_byte(byte: u8) {
+    if handle_led_answer(byte) ||
+        KEYBOARD_ERROR.contains(&byte) ||
+        byte == DEVICE_ACK ||
+        byte == DEVICE_RESEND
+    {
+        return;
+    }
+    if let Some((code, pressed)) = unsafe { DECODER.feed(byte) } {
+        crate::keyboard::report_key(code, pressed);
+        if code == KeyCode::Pause {
+            crate::keyboard::report_key(code, false);
+        }
+    }
+}
+
+/// Stops handling the keyboard.
+fn remove() {
+    Ps2Port::First.disable_irq();
+    unsafe {
+        KEYBOARD_PRESENT = false;
+    }
+}
+
+/// The driver of the keyboard on the first PS/2 port.
+pub static KEYBOARD_DRIVER: Driver = Driver {
+    name: "ps2-keyboard",
+    matches: &[DeviceMatch::Platform("i8042-keyboard")],
+    probe: |_: &Device| init(),
+    remove: |_: &Device| remove(),
+};
@@ -3,18 +3,37 @@
 
 use core::arch::asm;
 
+pub mod apic;
+pub mod device;
 pub mod egatext;
-mod gdt;
+pub mod exceptions;
+pub mod gdt;
+pub mod hpet;
 pub mod interrupts;
+pub mod irq;
+pub mod keyboard;
 pub mod memory;
+pub mod mouse;
+pub mod msi;
+pub mod msr;
 pub mod output;
 pub mod paging;
+pub mod pci;
+pub mod percpu;
+pub mod pic;
+pub mod pit;
 pub mod ports;
+pub mod ps2;
+pub mod rtc;
+pub mod serial;
+pub mod syscall;
+pub mod time;
+pub mod tss;
+pub mod watchdog;
 
 mod constants;
 
 use constants::*;
-use interrupts::{pop_irq, restore_irq};
 use ports::{inb, outb};
 
 /// Returns the most specific architecture available.
@@ -32,19 +51,25 @@ pub fn cpuid(id: u32) -> (u32, u32, u32) {
     out
 }
 
-/// Returns whether extended functions are available
-/// (more specifically, 0x80000001 or higher)
-pub fn cpuid_extended_functions() -> bool {
+/// Returns the highest extended function supported by cpuid.
+pub fn cpuid_max_extended_function() -> u32 {
     let out: u32;
     unsafe {
         asm!(
-            "mov eax, 0x80000000",
-            "cpuid", out("eax") out
+            "cpuid",
+            inout("eax") 0x80000000u32 => out,
+            out("ebx") _,
+            out("ecx") _,
+            out("edx") _,
         )
     }
-    out >= 0x80000001
+    out
 }
 
+/// Returns whether extended functions are available
+/// (more specifically, 0x80000001 or higher)
+pub fn cpuid_extended_functions() -> bool { cpuid_max_extended_function() >= 0x80000001 }
+
 /// Returns whether the a20 gate is enabled.
 pub fn test_a20() -> bool {
     let addr0: usize;
@@ -62,46 +87,9 @@ pub fn test_a20() -> bool {
     addr0 != addr1
 }
 
-/// Waits for a keyboard command to complete.
-pub fn wait_for_keyboard_cmd() { while inb(0x64) & 0b10 > 1 {} }
-
-/// Waits for there to be data to read from the keyboard.
-pub fn wait_for_keyboard_data() { while inb(0x64) & 0b1 == 0 {} }
-
-/// Sends a keyboard command.
-pub fn send_keyboard_cmd(byte: u8) { outb(0x64, byte); }
-
-/// Gets data from the keyboard.
-pub fn get_keyboard_data() -> u8 { inb(0x60) }
-
-/// Sends data to the keyboard.
-pub fn send_keyboard_data(data: u8) { outb(0x60, data); }
-
-/// Tries to enable the a20 gate via the keyboard controller method.
-pub fn enable_a20_keyboard() {
-    let irq = pop_irq();
-
-    wait_for_keyboard_cmd();
-    send_keyboard_cmd(0xAD); // disable keyboard
-
-    wait_for_keyboard_cmd();
-    send_keyboard_cmd(0xD0); // read from input
-
-    wait_for_keyboard_cmd();
-    wait_for_keyboard_data();
-    let a = get_keyboard_data();
-
-    wait_for_keyboard_cmd();
-    send_keyboard_cmd(0xD1); // write to output
-
-    wait_for_keyboard_cmd();
-    send_keyboard_data(a | 2);
-
-    wait_for_keyboard_cmd();
-    send_keyboard_cmd(0xAE); // enable keyboard
-
-    restore_irq(irq);
-}
+/// Tries to enable the a20 gate via the keyboard controller method. Does
+/// nothing if there is no PS/2 controller.
+pub fn enable_a20_keyboard() { let _ = ps2::enable_a20(); }
 
 /// Tries to enable the a20 gate via fast a20.
 /// Note that this may not work or do something unexpected.
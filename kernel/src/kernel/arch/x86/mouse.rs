@@ -0,0 +1,222 @@
+//! The mouse on the second PS/2 port. IntelliMouse wheels and side buttons are
+//! detected and enabled. Packets arriving on IRQ 12 are decoded into motion,
+//! wheel and button events for [crate::input].
+#![cfg(target_arch = "x86")]
+#![allow(static_mut_refs)]
+
+use super::ps2::{
+    self, DEVICE_ENABLE_SCANNING, DEVICE_IDENTIFY, DEVICE_SET_DEFAULTS, DEVICE_SET_SAMPLE_RATE,
+    Ps2Port,
+};
+use crate::device::{Device, DeviceMatch, Driver};
+use crate::input::{InputEvent, MouseButton, report_input_event};
+
+/// Returned by [init] if there is no second PS/2 port.
+pub const ERR_NO_MOUSE: i16 = -1;
+
+/// The ID of a mouse with a wheel.
+const MOUSE_ID_WHEEL: u8 = 3;
+/// The ID of a mouse with a wheel and two side buttons.
+const MOUSE_ID_FIVE_BUTTONS: u8 = 4;
+/// The sample rate the mouse is left at.
+const SAMPLE_RATE: u8 = 100;
+/// The sample rates that unlock [MOUSE_ID_WHEEL].
+const WHEEL_SEQUENCE: [u8; 3] = [200, 100, 80];
+/// The sample rates that unlock [MOUSE_ID_FIVE_BUTTONS] on a wheel mouse.
+const FIVE_BUTTON_SEQUENCE: [u8; 3] = [200, 200, 80];
+
+/// The first byte of a packet: the left, right and middle buttons.
+const PACKET_BUTTONS: u8 = 0b111;
+/// Always set in the first byte of a packet.
+const PACKET_ALWAYS_SET: u8 = 1 << 3;
+/// The sign of the X movement.
+const PACKET_X_SIGN: u8 = 1 << 4;
+/// The sign of the Y movement.
+const PACKET_Y_SIGN: u8 = 1 << 5;
+/// Set if the X movement was too large.
+const PACKET_X_OVERFLOW: u8 = 1 << 6;
+/// Set if the Y movement was too large.
+const PACKET_Y_OVERFLOW: u8 = 1 << 7;
+/// The side buttons in the fourth byte of a [MouseKind::FiveButtons] packet.
+const PACKET_SIDE_BUTTONS: u8 = 0b11 << 4;
+
+/// The buttons in the order of their bits in [BUTTONS].
+const BUTTON_ORDER: [MouseButton; 5] = [
+    MouseButton::Left,
+    MouseButton::Right,
+    MouseButton::Middle,
+    MouseButton::Fourth,
+    MouseButton::Fifth,
+];
+
+/// The kind of mouse, which decides the packet format.
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum MouseKind {
+    /// Three buttons and 3-byte packets.
+    Standard,
+    /// Three buttons and a wheel, with 4-byte packets.
+    Wheel,
+    /// Five buttons and a wheel, with 4-byte packets.
+    FiveButtons,
+}
+
+impl MouseKind {
+    /// Returns the length of the packets of the mouse.
+    pub const fn packet_len(self) -> usize {
+        match self {
+            MouseKind::Standard => 3,
+            MouseKind::Wheel | MouseKind::FiveButtons => 4,
+        }
+    }
+}
+
+/// The kind of the mouse found by [init].
+static mut KIND: Option<MouseKind> = None;
+/// The packet being received.
+static mut PACKET: [u8; 4] = [0; 4];
+/// The number of bytes of [PACKET] received.
+static mut RECEIVED: usize = 0;
+/// The held buttons, one bit each in [BUTTON_ORDER].
+static mut BUTTONS: u8 = 0;
+
+/// Sets the sample rate of the mouse.
+fn set_sample_rate(rate: u8) -> Result<(), crate::Error<'static>> {
+    Ps2Port::Second.command(DEVICE_SET_SAMPLE_RATE)?;
+    Ps2Port::Second.command(rate)
+}
+
+/// Returns the ID of the mouse.
+fn identify() -> Result<u8, crate::Error<'static>> {
+    Ps2Port::Second.command(DEVICE_IDENTIFY)?;
+    Ps2Port::Second.read(ps2::DEVICE_TIMEOUT_US)
+}
+
+/// Finds out which extensions the mouse has by sending the sample rate
+/// sequences that enable them, which leaves them enabled.
+fn detect_kind() -> Result<MouseKind, crate::Error<'static>> {
+    for rate in WHEEL_SEQUENCE {
+        set_sample_rate(rate)?;
+    }
+    if identify()? != MOUSE_ID_WHEEL {
+        return Ok(MouseKind::Standard);
+    }
+    for rate in FIVE_BUTTON_SEQUENCE {
+        set_sample_rate(rate)?;
+    }
+    if identify()? == MOUSE_ID_FIVE_BUTTONS {
+        Ok(MouseKind::FiveButtons)
+    } else {
+        Ok(MouseKind::Wheel)
+    }
+}
+
+/// Initalizes the controller, resets the mouse, enables its extensions and
+/// starts handling IRQ 12.
+pub fn init() -> Result<(), crate::Error<'static>> {
+    ps2::init()?;
+    let port = Ps2Port::Second;
+    if !port.present() {
+        return Err(crate::Error::new("no mouse port", ERR_NO_MOUSE));
+    }
+    let _irq = super::interrupts::pop_irq();
+    port.reset()?;
+    port.command(DEVICE_SET_DEFAULTS)?;
+    let kind = detect_kind()?;
+    set_sample_rate(SAMPLE_RATE)?;
+    port.command(DEVICE_ENABLE_SCANNING)?;
+    unsafe {
+        RECEIVED = 0;
+        BUTTONS = 0;
+        KIND = Some(kind);
+    }
+    port.enable_irq()
+}
+
+/// Returns the kind of mouse found, if there is one.
+pub fn mouse_kind() -> Option<MouseKind> { unsafe { KIND } }
+
+/// Handles a byte from the second port. Called by the PS/2 IRQ handler.
+pub(super) fn handle_byte(byte: u8) {
+    let Some(kind) = mouse_kind() else {
+        return;
+    };
+    unsafe {
+        // Drops bytes until one can start a packet, to resynchronize after a
+        // lost byte.
+        if RECEIVED == 0 && byte & PACKET_ALWAYS_SET == 0 {
+            return;
+        }
+        PACKET[RECEIVED] = byte;
+        RECEIVED += 1;
+        if RECEIVED < kind.packet_len() {
+            return;
+        }
+        RECEIVED = 0;
+        decode_packet(kind, PACKET);
+    }
+}
+
+/// Returns a 9-bit movement from its low byte and sign.
+fn movement(low: u8, negative: bool) -> i16 {
+    if negative {
+        low as i16 - 0x100
+    } else {
+        low as i16
+    }
+}
+
+/// Reports the events of a complete packet.
+fn decode_packet(kind: MouseKind, packet: [u8; 4]) {
+    let flags = packet[0];
+    if flags & (PACKET_X_OVERFLOW | PACKET_Y_OVERFLOW) == 0 {
+        let dx = movement(packet[1], flags & PACKET_X_SIGN != 0);
+        // PS/2 Y grows upwards.
+        let dy = -movement(packet[2], flags & PACKET_Y_SIGN != 0);
+        if dx != 0 || dy != 0 {
+            report_input_event(InputEvent::MouseMotion { dx, dy });
+        }
+    }
+
+    let mut buttons = flags & PACKET_BUTTONS;
+    let wheel = match kind {
+        MouseKind::Standard => 0,
+        MouseKind::Wheel => packet[3] as i8,
+        MouseKind::FiveButtons => {
+            buttons |= (packet[3] & PACKET_SIDE_BUTTONS) >> 1;
+            // The wheel is the sign extended low nibble.
+            ((packet[3] << 4) as i8) >> 4
+        },
+    };
+    if wheel != 0 {
+        report_input_event(InputEvent::MouseWheel { delta: wheel });
+    }
+
+    let changed = buttons ^ unsafe { BUTTONS };
+    for (bit, button) in BUTTON_ORDER.into_iter().enumerate() {
+        if changed & (1 << bit) != 0 {
+            report_input_event(InputEvent::MouseButton {
+                button,
+                pressed: buttons & (1 << bit) != 0,
+            });
+        }
+    }
+    unsafe {
+        BUTTONS = buttons;
+    }
+}
+
+/// Stops handling the mouse.
+fn remove() {
+    Ps2Port::Second.disable_irq();
+    unsafe {
+        KIND = None;
+    }
+}
+
+/// The driver of the mouse on the second PS/2 port.
+pub static MOUSE_DRIVER: Driver = Driver {
+    name: "ps2-mouse",
+    matches: &[DeviceMatch::Platform("i8042-mouse")],
+    probe: |_: &Device| init(),
+    remove: |_: &Device| remove(),
+};
@@ -0,0 +1,78 @@
+//! Message signaled interrupts through the local APIC. Messages are written
+//! to the local APIC address of the boot processor with the vector of the IRQ
+//! in the data, as fixed, edge triggered interrupts. They don't go through an
+//! I/O APIC, so their IRQs are taken from above the GSIs.
+#![cfg(target_arch = "x86")]
+#![allow(static_mut_refs)]
+
+use super::apic::{APIC_IRQ_BASE, LAPIC_TIMER_IRQ};
+use super::interrupts::USER_SYSCALL_VECTOR;
+use crate::irq::IRQ_COUNT;
+
+/// The address MSIs are written to, with the destination APIC ID in bits
+/// 12-19.
+const MSI_ADDRESS_BASE: u64 = 0xFEE0_0000;
+/// The shift of the destination APIC ID in the address.
+const MSI_DESTINATION_SHIFT: u64 = 12;
+
+/// The IRQs allocated for MSIs.
+static mut ALLOCATED: [bool; IRQ_COUNT] = [false; IRQ_COUNT];
+
+/// Returns whether an IRQ can be given to an MSI.
+fn irq_free(irq: u8) -> bool {
+    irq as u32 >= super::apic::gsi_count() &&
+        irq < LAPIC_TIMER_IRQ &&
+        (irq + APIC_IRQ_BASE) as u16 != USER_SYSCALL_VECTOR &&
+        unsafe { !ALLOCATED[irq as usize] } &&
+        !crate::irq::irq_has_handler(irq)
+}
+
+/// Returns whether MSIs can be delivered, which needs the local APIC.
+#[aphrodite_proc_macros::kernel_item(MsiSupported)]
+pub fn msi_supported() -> bool { super::irq::using_apic() }
+
+/// Allocates `count` consecutive IRQs whose vectors are aligned to `align`.
+/// Returns the first.
+#[aphrodite_proc_macros::kernel_item(MsiAllocateIrqs)]
+pub fn allocate_irqs(count: u8, align: u8) -> Option<u8> {
+    if !msi_supported() || count == 0 {
+        return None;
+    }
+    let align = align.max(1) as u16;
+    let _irq = super::interrupts::pop_irq();
+    let mut vector = (APIC_IRQ_BASE as u16).next_multiple_of(align);
+    while vector + count as u16 <= LAPIC_TIMER_IRQ as u16 + APIC_IRQ_BASE as u16 {
+        let first = (vector - APIC_IRQ_BASE as u16) as u8;
+        if (first..first + count).all(irq_free) {
+            for irq in first..first + count {
+                unsafe {
+                    ALLOCATED[irq as usize] = true;
+                }
+            }
+            return Some(first);
+        }
+        vector += align;
+    }
+    None
+}
+
+/// Frees IRQs allocated by [allocate_irqs].
+#[aphrodite_proc_macros::kernel_item(MsiFreeIrqs)]
+pub fn free_irqs(first: u8, count: u8) {
+    let _irq = super::interrupts::pop_irq();
+    for irq in first..first.saturating_add(count) {
+        if let Some(allocated) = unsafe { ALLOCATED.get_mut(irq as usize) } {
+            *allocated = false;
+        }
+    }
+}
+
+/// Returns the message address and data that raise an IRQ on this processor.
+#[aphrodite_proc_macros::kernel_item(MsiMessage)]
+pub fn message(irq: u8) -> Option<(u64, u32)> {
+    if !msi_supported() || irq >= LAPIC_TIMER_IRQ {
+        return None;
+    }
+    let address = MSI_ADDRESS_BASE | (super::apic::local_apic_id() as u64) << MSI_DESTINATION_SHIFT;
+    Some((address, (irq + APIC_IRQ_BASE) as u32))
+}
@@ -0,0 +1,47 @@
+//! Model-specific registers.
+#![cfg(target_arch = "x86")]
+
+use core::arch::asm;
+
+/// The base address and enable bit of the local APIC.
+pub const IA32_APIC_BASE: u32 = 0x1B;
+/// The code segment loaded by SYSENTER. SYSEXIT derives the user segments from
+/// it.
+pub const IA32_SYSENTER_CS: u32 = 0x174;
+/// The stack pointer loaded by SYSENTER.
+pub const IA32_SYSENTER_ESP: u32 = 0x175;
+/// The instruction pointer loaded by SYSENTER.
+pub const IA32_SYSENTER_EIP: u32 = 0x176;
+
+/// Returns whether the CPU supports the rdmsr and wrmsr instructions.
+pub fn msr_supported() -> bool { super::cpuid(1).1 & (1 << 5) != 0 }
+
+/// Reads a model-specific register.
+///
+/// # Safety
+/// The register must exist; reading one that doesn't raises a general
+/// protection fault.
+pub unsafe fn read_msr(msr: u32) -> u64 {
+    let low: u32;
+    let high: u32;
+    unsafe {
+        asm!("rdmsr", in("ecx") msr, out("eax") low, out("edx") high, options(nomem, nostack));
+    }
+    ((high as u64) << 32) | low as u64
+}
+
+/// Writes a model-specific register.
+///
+/// # Safety
+/// The register must exist and the value must be valid for it.
+pub unsafe fn write_msr(msr: u32, value: u64) {
+    unsafe {
+        asm!(
+            "wrmsr",
+            in("ecx") msr,
+            in("eax") value as u32,
+            in("edx") (value >> 32) as u32,
+            options(nostack)
+        );
+    }
+}
@@ -1,97 +1,198 @@
 //! Functions to output to various things
 #![cfg(target_arch = "x86")]
 
-use super::ports;
 use paste::paste;
 
+use super::ports;
+use super::serial::{ComPort, LineConfig, Uart, serial_write_blocking, serial_write_polled};
+
+/// Where the `s*` functions write to.
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum OutputTarget {
+    /// The Bochs/QEMU debug port, which doesn't exist on real hardware.
+    DebugPort,
+    /// A 16550 UART, at 115200 baud 8N1.
+    Serial(ComPort),
+}
+
+impl OutputTarget {
+    /// Returns the target with a name: "e9" for the debug port or "com1" to
+    /// "com4".
+    pub fn from_name(name: &str) -> Option<Self> {
+        match name {
+            "e9" => Some(OutputTarget::DebugPort),
+            _ => ComPort::from_name(name).map(OutputTarget::Serial),
+        }
+    }
+}
+
+/// The current output target.
+static mut OUTPUT_TARGET: OutputTarget = OutputTarget::DebugPort;
+
+/// Returns the output target selected by `CONFIG_SERIAL_OUTPUT`, the debug
+/// port by default.
+pub fn configured_output_target() -> OutputTarget {
+    if cfg!(CONFIG_SERIAL_OUTPUT = "com1") {
+        OutputTarget::Serial(ComPort::Com1)
+    } else if cfg!(CONFIG_SERIAL_OUTPUT = "com2") {
+        OutputTarget::Serial(ComPort::Com2)
+    } else if cfg!(CONFIG_SERIAL_OUTPUT = "com3") {
+        OutputTarget::Serial(ComPort::Com3)
+    } else if cfg!(CONFIG_SERIAL_OUTPUT = "com4") {
+        OutputTarget::Serial(ComPort::Com4)
+    } else {
+        OutputTarget::DebugPort
+    }
+}
+
+/// Returns the current output target.
+pub fn output_target() -> OutputTarget { unsafe { OUTPUT_TARGET } }
+
+/// Makes the `s*` functions write to a target. UARTs are initalized first; if
+/// that fails, the target stays unchanged.
+pub fn set_output_target(target: OutputTarget) -> Result<(), crate::Error<'static>> {
+    if let OutputTarget::Serial(port) = target {
+        Uart::new(port).init(LineConfig::default())?;
+    }
+    unsafe {
+        OUTPUT_TARGET = target;
+    }
+    Ok(())
+}
+
+/// Selects the output target given by a `serial=<target>` command line
+/// argument, with the names accepted by [OutputTarget::from_name].
+pub fn select_output_from_cmdline(cmdline: &str) {
+    let Some(target) = cmdline
+        .split_ascii_whitespace()
+        .filter_map(|argument| argument.strip_prefix("serial="))
+        .next_back()
+    else {
+        return;
+    };
+    match OutputTarget::from_name(target) {
+        Some(target) => {
+            if set_output_target(target).is_err() {
+                swarningsln("The serial port selected on the command line doesn't work");
+            }
+        },
+        None => swarningsln("Unknown serial= target on the command line"),
+    }
+}
+
+/// Writes bytes to the output target. UARTs are written through
+/// [serial_write_blocking] while interrupts are enabled, so that output stays
+/// in order once the port is interrupt driven, and by polling otherwise, as
+/// queued bytes might never be sent then, e.g. in NMIs and panics.
+fn write_bytes(bytes: &[u8]) {
+    match output_target() {
+        OutputTarget::DebugPort => ports::outbs(super::DEBUG_PORT, bytes),
+        OutputTarget::Serial(port) if super::interrupts::interrupts_enabled() => {
+            serial_write_blocking(port, bytes)
+        },
+        OutputTarget::Serial(port) => serial_write_polled(port, bytes),
+    }
+}
+
+/// Sends everything still queued for the output target. Called before halting,
+/// after which the serial IRQ handler doesn't send anything anymore.
+pub fn flush_output() {
+    if let OutputTarget::Serial(port) = output_target() {
+        serial_write_polled(port, &[]);
+    }
+}
+
+/// Writes a byte to the output target.
+fn write_byte(byte: u8) { write_bytes(&[byte]); }
+
 macro_rules! message_funcs {
     ($func_name:ident, $prefix:literal, $level:ident) => {
         paste! {
-            /// Outputs a $func_name message &str to the debug serial port.
+            /// Outputs a $func_name message &str to the output target.
             pub fn [< s $func_name s >](s: &str) {
                 if cfg!($level = "false") {
                     return
                 }
-                ports::outbs(super::DEBUG_PORT, $prefix.as_bytes());
-                ports::outbs(super::DEBUG_PORT, s.as_bytes());
+                write_bytes($prefix.as_bytes());
+                write_bytes(s.as_bytes());
             }
-            /// Outputs a $func_name message &str and a newline to the debug serial port.
+            /// Outputs a $func_name message &str and a newline to the output target.
             pub fn [< s $func_name sln >](s: &str) {
                 if cfg!($level = "false") {
                     return
                 }
-                ports::outbs(super::DEBUG_PORT, $prefix.as_bytes());
-                ports::outbs(super::DEBUG_PORT, s.as_bytes());
-                ports::outb(super::DEBUG_PORT, b'\n');
+                write_bytes($prefix.as_bytes());
+                write_bytes(s.as_bytes());
+                write_byte(b'\n');
             }
 
-            /// Outputs a $func_name message &\[u8] to the debug serial port.
+            /// Outputs a $func_name message &\[u8] to the output target.
             pub fn [< s $func_name b >](s: &[u8]) {
                 if cfg!($level = "false") {
                     return
                 }
-                ports::outbs(super::DEBUG_PORT, $prefix.as_bytes());
-                ports::outbs(super::DEBUG_PORT, s);
+                write_bytes($prefix.as_bytes());
+                write_bytes(s);
             }
-            /// Outputs a $func_name message &\[u8] and a newline to the debug serial port.
+            /// Outputs a $func_name message &\[u8] and a newline to the output target.
             pub fn [< s $func_name bln >](s: &[u8]) {
                 if cfg!($level = "false") {
                     return
                 }
-                ports::outbs(super::DEBUG_PORT, $prefix.as_bytes());
-                ports::outbs(super::DEBUG_PORT, s);
-                ports::outb(super::DEBUG_PORT, b'\n');
+                write_bytes($prefix.as_bytes());
+                write_bytes(s);
+                write_byte(b'\n');
             }
 
-            /// Outputs a(n) $func_name message u8 to the debug serial port.
+            /// Outputs a(n) $func_name message u8 to the output target.
             pub fn [< s $func_name u >](s: u8) {
                 if cfg!($level = "false") {
                     return
                 }
-                ports::outbs(super::DEBUG_PORT, $prefix.as_bytes());
-                ports::outb(super::DEBUG_PORT, s);
+                write_bytes($prefix.as_bytes());
+                write_byte(s);
             }
 
             ///////////////////////////////////////////////////////////////
 
-            /// Outputs a $func_name message &str to the debug serial port without a prefix.
+            /// Outputs a $func_name message &str to the output target without a prefix.
             pub fn [< s $func_name snp >](s: &str) {
                 if cfg!($level = "false") {
                     return
                 }
-                ports::outbs(super::DEBUG_PORT, s.as_bytes());
+                write_bytes(s.as_bytes());
             }
-            /// Outputs a $func_name message &str and a newline to the debug serial port without a prefix.
+            /// Outputs a $func_name message &str and a newline to the output target without a prefix.
             pub fn [< s $func_name snpln >](s: &str) {
                 if cfg!($level = "false") {
                     return
                 }
-                ports::outbs(super::DEBUG_PORT, s.as_bytes());
-                ports::outb(super::DEBUG_PORT, b'\n');
+                write_bytes(s.as_bytes());
+                write_byte(b'\n');
             }
 
-            /// Outputs a $func_name message &\[u8] to the debug serial port without a prefix.
+            /// Outputs a $func_name message &\[u8] to the output target without a prefix.
             pub fn [< s $func_name bnp >](s: &[u8]) {
                 if cfg!($level = "false") {
                     return
                 }
-                ports::outbs(super::DEBUG_PORT, s);
+                write_bytes(s);
             }
-            /// Outputs a $func_name message &\[u8] and a newline to the debug serial port without a prefix.
+            /// Outputs a $func_name message &\[u8] and a newline to the output target without a prefix.
             pub fn [< s $func_name bnpln >](s: &[u8]) {
                 if cfg!($level = "false") {
                     return
                 }
-                ports::outbs(super::DEBUG_PORT, s);
-                ports::outb(super::DEBUG_PORT, b'\n');
+                write_bytes(s);
+                write_byte(b'\n');
             }
 
-            /// Outputs a(n) $func_name message u8 to the debug serial port without a prefix.
+            /// Outputs a(n) $func_name message u8 to the output target without a prefix.
             pub fn [< s $func_name unp >](s: u8) {
                 if cfg!($level = "false") {
                     return
                 }
-                ports::outb(super::DEBUG_PORT, s);
+                write_byte(s);
             }
         }
     };
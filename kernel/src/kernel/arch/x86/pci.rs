@@ -0,0 +1,127 @@
+//! PCI configuration space access. Where the ACPI MCFG table describes an
+//! ECAM region for segment 0, configuration space is memory mapped and the
+//! whole 4 KiB of every function can be reached. Otherwise, and for buses
+//! outside that region, configuration mechanism #1 is used: the address of a
+//! dword is written to port 0xCF8 and the dword is accessed through port
+//! 0xCFC, which only reaches the first 256 bytes.
+#![cfg(target_arch = "x86")]
+#![allow(static_mut_refs)]
+
+use super::ports::{inl, outl};
+use crate::acpi::{McfgEntry, McfgTable};
+use crate::pci::{EXTENDED_CONFIG_SIZE, PciAddress};
+
+/// Selects the dword accessed through [CONFIG_DATA].
+const CONFIG_ADDRESS: u16 = 0xCF8;
+/// Accesses the dword selected through [CONFIG_ADDRESS].
+const CONFIG_DATA: u16 = 0xCFC;
+/// Enables the access in [CONFIG_ADDRESS].
+const CONFIG_ENABLE: u32 = 1 << 31;
+/// The size of the configuration space reachable through the ports.
+const LEGACY_CONFIG_SIZE: u16 = 256;
+
+/// The ECAM region of segment 0, if the MCFG has a usable one.
+static mut ECAM: Option<McfgEntry> = None;
+/// Whether configuration mechanism #1 is supported.
+static mut PORTS_SUPPORTED: bool = false;
+
+/// Returns the value of [CONFIG_ADDRESS] selecting a dword.
+fn config_address(address: PciAddress, offset: u16) -> u32 {
+    CONFIG_ENABLE |
+        (address.bus as u32) << 16 |
+        ((address.device & 0x1F) as u32) << 11 |
+        ((address.function & 0x7) as u32) << 8 |
+        (offset & 0xFC) as u32
+}
+
+/// Returns the address of a dword in the ECAM region, if the bus is in it.
+fn ecam_address(address: PciAddress, offset: u16) -> Option<usize> {
+    let ecam = unsafe { ECAM }?;
+    if address.bus < ecam.start_bus || address.bus > ecam.end_bus {
+        return None;
+    }
+    let offset = (address.bus as u64) << 20 |
+        ((address.device & 0x1F) as u64) << 15 |
+        ((address.function & 0x7) as u64) << 12 |
+        (offset & 0xFFC) as u64;
+    Some((ecam.base_address + offset) as usize)
+}
+
+/// Returns the ECAM region of segment 0 from the MCFG, if it's addressable.
+fn find_ecam() -> Option<McfgEntry> {
+    McfgTable::find()?.entries().find(|entry| {
+        entry.segment == 0 &&
+            entry.start_bus <= entry.end_bus &&
+            entry.base_address + ((entry.end_bus as u64 + 1) << 20) <= usize::MAX as u64 + 1
+    })
+}
+
+/// Returns whether configuration mechanism #1 is supported, by checking that
+/// [CONFIG_ADDRESS] holds the enable bit.
+fn ports_supported() -> bool {
+    let _irq = super::interrupts::pop_irq();
+    let saved = inl(CONFIG_ADDRESS);
+    outl(CONFIG_ADDRESS, CONFIG_ENABLE);
+    let supported = inl(CONFIG_ADDRESS) == CONFIG_ENABLE;
+    outl(CONFIG_ADDRESS, saved);
+    supported
+}
+
+/// Finds the ECAM region and checks for configuration mechanism #1. Returns
+/// whether either is available.
+#[aphrodite_proc_macros::kernel_item(PciInit)]
+pub fn init_pci() -> bool {
+    unsafe {
+        ECAM = find_ecam();
+        PORTS_SUPPORTED = ports_supported();
+    }
+    if ecam_available() {
+        super::output::sdebugsln("Using ECAM for PCI configuration space");
+    }
+    ecam_available() || unsafe { PORTS_SUPPORTED }
+}
+
+/// Returns whether configuration space is memory mapped.
+pub fn ecam_available() -> bool { unsafe { ECAM.is_some() } }
+
+/// Returns whether extended configuration space can be accessed.
+#[aphrodite_proc_macros::kernel_item(PciExtendedConfig)]
+pub fn extended_config() -> bool { ecam_available() }
+
+/// Reads a dword of configuration space. Offsets that can't be reached read
+/// as all ones.
+#[aphrodite_proc_macros::kernel_item(PciConfigRead)]
+pub fn config_read(address: PciAddress, offset: u16) -> u32 {
+    if offset >= EXTENDED_CONFIG_SIZE {
+        return 0xFFFF_FFFF;
+    }
+    if let Some(ecam) = ecam_address(address, offset) {
+        return unsafe { core::ptr::read_volatile(ecam as *const u32) };
+    }
+    if offset >= LEGACY_CONFIG_SIZE || unsafe { !PORTS_SUPPORTED } {
+        return 0xFFFF_FFFF;
+    }
+    // The address and data accesses must not be split by another access.
+    let _irq = super::interrupts::pop_irq();
+    outl(CONFIG_ADDRESS, config_address(address, offset));
+    inl(CONFIG_DATA)
+}
+
+/// Writes a dword of configuration space. Writes to offsets that can't be
+/// reached are ignored.
+#[aphrodite_proc_macros::kernel_item(PciConfigWrite)]
+pub fn config_write(address: PciAddress, offset: u16, value: u32) {
+    if offset >= EXTENDED_CONFIG_SIZE {
+        return;
+    }
+    if let Some(ecam) = ecam_address(address, offset) {
+        unsafe { core::ptr::write_volatile(ecam as *mut u32, value) };
+        return;
+    }
+    if offset >= LEGACY_CONFIG_SIZE || unsafe { !PORTS_SUPPORTED } {
+        return;
+    }
+    let _irq = super::interrupts::pop_irq();
+    outl(CONFIG_ADDRESS, config_address(address, offset));
+    outl(CONFIG_DATA, value);
+}
@@ -0,0 +1,53 @@
+//! Per-CPU data through gs and user TLS through fs.
+#![cfg(target_arch = "x86")]
+
+use core::arch::asm;
+
+use aphrodite_proc_macros::kernel_item;
+
+use super::gdt::{PER_CPU_SELECTOR, USER_TLS_SELECTOR, set_boot_gdt_base};
+use crate::percpu::{PerCpu, boot_cpu};
+
+/// Makes gs point to the data of the boot CPU.
+///
+/// # Safety
+///
+/// The GDT built by [GdtBuilder::flat](super::gdt::GdtBuilder::flat) must be
+/// loaded.
+pub(super) unsafe fn init_boot_cpu() {
+    unsafe {
+        (*boot_cpu()).this = boot_cpu();
+        asm!("mov gs, {0:x}", in(reg) PER_CPU_SELECTOR.bits() as u32, options(nostack));
+    }
+}
+
+/// Returns a pointer to the data of the current CPU, read from the start of
+/// the gs segment.
+#[kernel_item(ThisCpu)]
+pub fn this_cpu() -> *mut PerCpu {
+    let this: *mut PerCpu;
+    unsafe {
+        asm!("mov {0}, gs:[0]", out(reg) this, options(nostack, readonly, preserves_flags));
+    }
+    this
+}
+
+/// Sets the thread pointer of userspace: the base of [USER_TLS_SELECTOR]. By
+/// convention, the first word of the TLS block points to itself.
+#[kernel_item(SetThreadPointer)]
+pub fn set_thread_pointer(ptr: usize) {
+    set_boot_gdt_base(USER_TLS_SELECTOR, ptr as u32);
+    // Returning through iretd reloads fs, but SYSEXIT doesn't.
+    unsafe {
+        asm!(
+            "mov {tmp:e}, fs",
+            "cmp {tmp:x}, {tls:x}",
+            "jne 2f",
+            "mov fs, {tmp:x}",
+            "2:",
+            tmp = out(reg) _,
+            tls = in(reg) USER_TLS_SELECTOR.bits() as u32,
+            options(nostack),
+        );
+    }
+}
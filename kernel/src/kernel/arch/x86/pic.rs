@@ -0,0 +1,182 @@
+//! Driver for the legacy 8259 programmable interrupt controllers.
+#![cfg(target_arch = "x86")]
+
+use super::interrupts::{InterruptFrame, register_interrupt_handler};
+use super::ports::{inb, io_wait, outb};
+
+/// The command port of the master PIC.
+const PIC1_COMMAND: u16 = 0x20;
+/// The data port of the master PIC.
+const PIC1_DATA: u16 = 0x21;
+/// The command port of the slave PIC.
+const PIC2_COMMAND: u16 = 0xA0;
+/// The data port of the slave PIC.
+const PIC2_DATA: u16 = 0xA1;
+
+/// ICW1: ICW4 will be sent.
+const ICW1_ICW4: u8 = 0x01;
+/// ICW1: start initalization.
+const ICW1_INIT: u8 = 0x10;
+/// ICW4: 8086/88 mode.
+const ICW4_8086: u8 = 0x01;
+
+/// OCW2: non-specific end of interrupt.
+const OCW2_EOI: u8 = 0x20;
+/// OCW3: the next read from the command port returns the in-service register.
+const OCW3_READ_ISR: u8 = 0x0B;
+
+/// The IRQ on the master PIC that the slave PIC is cascaded through.
+pub const CASCADE_IRQ: u8 = 2;
+
+/// The number of IRQs handled by the two PICs.
+pub const PIC_IRQ_COUNT: u8 = 16;
+
+/// The vector IRQ 0 is remapped to. IRQs 0-7 use the following vectors.
+pub const PIC1_OFFSET: u8 = 0x20;
+/// The vector IRQ 8 is remapped to. IRQs 8-15 use the following vectors.
+pub const PIC2_OFFSET: u8 = 0x28;
+
+/// The number of spurious IRQs seen so far.
+static mut SPURIOUS_IRQS: u32 = 0;
+
+/// Remaps the PICs so that IRQs 0-7 start at `offset1` and IRQs 8-15 start
+/// at `offset2`. All IRQs are masked afterwards except the cascade.
+pub fn remap(offset1: u8, offset2: u8) {
+    let _irq = super::interrupts::pop_irq();
+
+    outb(PIC1_COMMAND, ICW1_INIT | ICW1_ICW4);
+    io_wait();
+    outb(PIC2_COMMAND, ICW1_INIT | ICW1_ICW4);
+    io_wait();
+
+    outb(PIC1_DATA, offset1);
+    io_wait();
+    outb(PIC2_DATA, offset2);
+    io_wait();
+
+    outb(PIC1_DATA, 1 << CASCADE_IRQ); // tell the master where the slave is
+    io_wait();
+    outb(PIC2_DATA, CASCADE_IRQ); // tell the slave its cascade identity
+    io_wait();
+
+    outb(PIC1_DATA, ICW4_8086);
+    io_wait();
+    outb(PIC2_DATA, ICW4_8086);
+    io_wait();
+
+    outb(PIC1_DATA, !(1 << CASCADE_IRQ));
+    outb(PIC2_DATA, 0xFF);
+}
+
+/// Returns the data port and bit of an IRQ.
+const fn irq_port(irq: u8) -> (u16, u8) {
+    if irq < 8 {
+        (PIC1_DATA, irq)
+    } else {
+        (PIC2_DATA, irq - 8)
+    }
+}
+
+/// Masks an IRQ so that it isn't delivered.
+pub fn mask(irq: u8) {
+    if irq >= PIC_IRQ_COUNT {
+        return;
+    }
+    let (port, bit) = irq_port(irq);
+    outb(port, inb(port) | (1 << bit));
+}
+
+/// Unmasks an IRQ so that it is delivered.
+pub fn unmask(irq: u8) {
+    if irq >= PIC_IRQ_COUNT {
+        return;
+    }
+    let (port, bit) = irq_port(irq);
+    outb(port, inb(port) & !(1 << bit));
+}
+
+/// Masks every IRQ on both PICs.
+pub fn mask_all() {
+    outb(PIC1_DATA, 0xFF);
+    outb(PIC2_DATA, 0xFF);
+}
+
+/// Sends an end of interrupt for an IRQ. IRQs from the slave PIC need an end
+/// of interrupt on both PICs.
+pub fn send_eoi(irq: u8) {
+    if irq >= 8 {
+        outb(PIC2_COMMAND, OCW2_EOI);
+    }
+    outb(PIC1_COMMAND, OCW2_EOI);
+}
+
+/// Returns the combined in-service registers of both PICs, with the slave in
+/// the upper byte.
+pub fn read_isr() -> u16 {
+    outb(PIC1_COMMAND, OCW3_READ_ISR);
+    outb(PIC2_COMMAND, OCW3_READ_ISR);
+    ((inb(PIC2_COMMAND) as u16) << 8) | inb(PIC1_COMMAND) as u16
+}
+
+/// Returns whether an IRQ is spurious. Only IRQ 7 and IRQ 15 can be spurious;
+/// they are if their bit in the in-service register isn't set.
+pub fn is_spurious(irq: u8) -> bool {
+    if irq != 7 && irq != 15 {
+        return false;
+    }
+    read_isr() & (1 << irq) == 0
+}
+
+/// Returns the number of spurious IRQs seen so far.
+pub fn spurious_irqs() -> u32 { unsafe { SPURIOUS_IRQS } }
+
+/// Returns whether an IRQ is spurious. If it is, it's counted and gets the end
+/// of interrupt it needs.
+fn check_spurious(irq: u8) -> bool {
+    if !is_spurious(irq) {
+        return false;
+    }
+    unsafe {
+        SPURIOUS_IRQS += 1;
+    }
+    // The master PIC doesn't know that a spurious IRQ from the slave was
+    // spurious, so it still needs an end of interrupt.
+    if irq == 15 {
+        send_eoi(CASCADE_IRQ);
+    }
+    true
+}
+
+/// Sends the end of interrupt for an IRQ nothing handled, unless it was
+/// spurious.
+pub fn send_stray_eoi(irq: u8) {
+    if !check_spurious(irq) {
+        send_eoi(irq);
+    }
+}
+
+/// Called for every vector the PICs are remapped to.
+fn pic_interrupt(frame: &mut InterruptFrame) {
+    let irq = (frame.vector as u8).wrapping_sub(PIC1_OFFSET);
+    if check_spurious(irq) {
+        return;
+    }
+    super::irq::dispatch_irq(irq, frame, send_eoi);
+}
+
+/// Remaps the PICs to [PIC1_OFFSET] and [PIC2_OFFSET] and registers handlers
+/// for their vectors. All IRQs start masked.
+pub fn init() {
+    remap(PIC1_OFFSET, PIC2_OFFSET);
+    for irq in 0..PIC_IRQ_COUNT {
+        register_interrupt_handler(PIC1_OFFSET + irq, pic_interrupt, false);
+    }
+}
+
+/// Masks every IRQ and moves the PICs' vectors out of the way so that they
+/// don't interfere with another interrupt controller. Spurious IRQs can still
+/// arrive on the remapped vectors and are handled as usual.
+pub fn disable() {
+    init();
+    mask_all();
+}
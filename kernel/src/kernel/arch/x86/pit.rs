@@ -0,0 +1,278 @@
+//! The 8253/8254 programmable interval timer. Channel 0 either raises IRQ 0
+//! periodically and is counted in ticks, or is the clock event device if there
+//! is nothing better, or is stopped so that it doesn't wake idle CPUs; channel
+//! 2 is polled in one-shot mode to wait for short, precise amounts of time,
+//! e.g. to calibrate other clocks.
+#![cfg(target_arch = "x86")]
+#![allow(static_mut_refs)]
+
+use super::interrupts::InterruptFrame;
+use super::ports::{inb, outb};
+use crate::time::{ClockEventDevice, register_clock_event_device};
+
+/// The input frequency of the PIT in Hz.
+pub const PIT_FREQUENCY: u32 = 1193182;
+/// The frequency channel 0 is started at by the kernel.
+pub const PIT_DEFAULT_HZ: u32 = 1000;
+/// The IRQ raised by channel 0.
+pub const PIT_IRQ: u8 = 0;
+
+/// The data port of channel 0.
+const PIT_CHANNEL0: u16 = 0x40;
+/// The data port of channel 2.
+const PIT_CHANNEL2: u16 = 0x42;
+/// The mode/command port.
+const PIT_COMMAND: u16 = 0x43;
+/// Controls the gate of channel 2 (bit 0) and the speaker (bit 1), and reads
+/// the output of channel 2 (bit 5).
+const SYSTEM_CONTROL_PORT_B: u16 = 0x61;
+
+/// Latches the count of channel 0 for reading.
+const CHANNEL0_LATCH: u8 = 0b0000_0000;
+/// Channel 0, low then high byte, mode 0 (interrupt on terminal count).
+/// Writing only this stops channel 0 until a count is written.
+const CHANNEL0_ONESHOT: u8 = 0b0011_0000;
+/// Channel 0, low then high byte, mode 2 (rate generator).
+const CHANNEL0_RATE: u8 = 0b0011_0100;
+/// Channel 2, low then high byte, mode 0 (interrupt on terminal count).
+const CHANNEL2_ONESHOT: u8 = 0b1011_0000;
+/// The gate of channel 2 in [SYSTEM_CONTROL_PORT_B].
+const CHANNEL2_GATE: u8 = 1 << 0;
+/// The speaker enable bit in [SYSTEM_CONTROL_PORT_B].
+const SPEAKER_ENABLE: u8 = 1 << 1;
+/// The output of channel 2 in [SYSTEM_CONTROL_PORT_B].
+const CHANNEL2_OUTPUT: u8 = 1 << 5;
+
+/// The frequency channel 0 ticks at, or 0 if it isn't ticking.
+static mut FREQUENCY: u32 = 0;
+/// The divisor channel 0 ticks with, or 0 if it isn't ticking.
+static mut DIVISOR: u16 = 0;
+/// Whether channel 0 is used as the clock event device instead of ticking.
+static mut CLOCK_EVENT: bool = false;
+/// The number of cycles channel 0 counts between two interrupts.
+static mut PERIOD: u32 = 0;
+/// Set while channel 0 is programmed, when its count can't be read.
+static mut PROGRAMMING: bool = false;
+/// The number of channel 0 interrupts since it was started. Only written by
+/// [tick].
+static mut TICKS: u64 = 0;
+
+/// Returns the PIT divisor for a frequency.
+fn divisor(hz: u32) -> u16 { (PIT_FREQUENCY / hz.max(1)).clamp(1, 0xFFFF) as u16 }
+
+/// Writes a command for channel 0, followed by a count if there is one.
+fn write_channel0(command: u8, count: Option<u16>) {
+    let _irq = super::interrupts::pop_irq();
+    unsafe {
+        PROGRAMMING = true;
+    }
+    outb(PIT_COMMAND, command);
+    if let Some(count) = count {
+        outb(PIT_CHANNEL0, count as u8);
+        outb(PIT_CHANNEL0, (count >> 8) as u8);
+    }
+    unsafe {
+        PROGRAMMING = false;
+    }
+}
+
+/// Handles IRQ 0 if nothing else does. Returns whether it's handled.
+fn register_irq() -> bool {
+    crate::irq::irq_has_handler(PIT_IRQ) ||
+        crate::irq::register_irq_handler(PIT_IRQ, pit_irq).is_ok()
+}
+
+/// Programs channel 0 to interrupt `hz` times a second and counts the
+/// interrupts. The frequency is rounded to what the PIT can produce, between
+/// about 19 Hz and its input frequency. Does nothing while channel 0 is the
+/// clock event device.
+pub fn start(hz: u32) {
+    let divisor = divisor(hz);
+    let _irq = super::interrupts::pop_irq();
+    if clock_event_mode() {
+        return;
+    }
+    write_channel0(CHANNEL0_RATE, Some(divisor));
+    unsafe {
+        FREQUENCY = PIT_FREQUENCY / divisor as u32;
+        DIVISOR = divisor;
+        PERIOD = divisor as u32;
+    }
+    if !register_irq() {
+        super::output::swarningsln("Failed to count PIT ticks: IRQ 0 is in use");
+    }
+}
+
+/// Stops channel 0 ticking and masks IRQ 0, so that it doesn't wake idle
+/// CPUs. Does nothing while channel 0 is the clock event device.
+pub fn stop() {
+    let _irq = super::interrupts::pop_irq();
+    if clock_event_mode() || frequency() == 0 {
+        return;
+    }
+    write_channel0(CHANNEL0_ONESHOT, None);
+    unsafe {
+        FREQUENCY = 0;
+        DIVISOR = 0;
+        PERIOD = 0;
+    }
+    crate::irq::unregister_irq_handler(PIT_IRQ);
+}
+
+/// Returns the frequency channel 0 ticks at, or 0 if it isn't ticking.
+pub fn frequency() -> u32 { unsafe { FREQUENCY } }
+
+/// Returns whether channel 0 is used as the clock event device.
+pub fn clock_event_mode() -> bool { unsafe { CLOCK_EVENT } }
+
+/// Programs channel 0 as the clock event device, to interrupt after `cycles`
+/// with `command` deciding whether it repeats.
+fn program_clock_event(command: u8, cycles: u64) {
+    let count = cycles.clamp(1, 0xFFFF) as u16;
+    let _irq = super::interrupts::pop_irq();
+    unsafe {
+        CLOCK_EVENT = true;
+        FREQUENCY = 0;
+        DIVISOR = 0;
+        PERIOD = count as u32;
+    }
+    write_channel0(command, Some(count));
+}
+
+/// Stops channel 0 if it's the clock event device.
+fn stop_clock_event(_data: usize) {
+    let _irq = super::interrupts::pop_irq();
+    if clock_event_mode() {
+        write_channel0(CHANNEL0_ONESHOT, None);
+        unsafe {
+            PERIOD = 0;
+        }
+    }
+}
+
+/// Registers channel 0 as a clock event device, for when there is no better
+/// one. Its ticks can't be a clocksource then.
+pub(super) fn register_clock_event() {
+    if !register_irq() {
+        return;
+    }
+    register_clock_event_device(ClockEventDevice {
+        name: "pit",
+        frequency: PIT_FREQUENCY as u64,
+        min_delta: (PIT_FREQUENCY / 100_000) as u64,
+        max_delta: 0xFFFF,
+        rating: 50,
+        data: 0,
+        program_oneshot: |_, delta| program_clock_event(CHANNEL0_ONESHOT, delta),
+        program_periodic: Some(|_, period| program_clock_event(CHANNEL0_RATE, period)),
+        stop: stop_clock_event,
+    });
+}
+
+/// Returns the number of channel 0 interrupts since [start].
+pub fn ticks() -> u64 {
+    // An interrupt, even an NMI, may increment the count between reading its
+    // halves.
+    loop {
+        let first = unsafe { core::ptr::read_volatile(&raw const TICKS) };
+        let second = unsafe { core::ptr::read_volatile(&raw const TICKS) };
+        if first == second {
+            return first;
+        }
+    }
+}
+
+/// Returns whether channel 0 reloaded less than half a period ago, i.e.
+/// whether it just raised IRQ 0. Used to tell its NMIs apart from others.
+pub(super) fn channel0_just_fired() -> bool {
+    unsafe {
+        if DIVISOR == 0 {
+            return false;
+        }
+        // An NMI in the middle of programming can't latch the count without
+        // breaking the byte order, and most likely is the tick anyway.
+        if PROGRAMMING {
+            return true;
+        }
+    }
+    outb(PIT_COMMAND, CHANNEL0_LATCH);
+    let count = inb(PIT_CHANNEL0) as u16 | (inb(PIT_CHANNEL0) as u16) << 8;
+    // Channel 0 counts down from the divisor to 1 and then reloads.
+    let divisor = unsafe { DIVISOR };
+    divisor.wrapping_sub(count) < divisor / 2
+}
+
+/// Counts a channel 0 interrupt. Called for IRQ 0, or from the NMI handler
+/// when the watchdog delivers IRQ 0 as an NMI.
+pub(super) fn tick(frame: Option<&InterruptFrame>) {
+    unsafe {
+        core::ptr::write_volatile(&raw mut TICKS, TICKS + 1);
+    }
+    super::watchdog::pit_tick(frame, unsafe { PERIOD });
+}
+
+/// Called for IRQ 0. Also drives the kernel timers if there is no clock event
+/// device.
+fn pit_irq(_irq: u8) {
+    let frame = super::irq::current_irq_frame();
+    if clock_event_mode() {
+        super::watchdog::pit_tick(frame, unsafe { PERIOD });
+        crate::time::clock_event_fired("pit", 0);
+        return;
+    }
+    tick(frame);
+    if crate::time::clock_event_device().is_none() {
+        crate::timer::timer_tick();
+    }
+}
+
+/// Starts channel 2 counting down `count` cycles of [PIT_FREQUENCY]. Poll
+/// [oneshot_done] to see when it reaches zero.
+pub fn oneshot_start(count: u16) {
+    let _irq = super::interrupts::pop_irq();
+    let port_b = inb(SYSTEM_CONTROL_PORT_B) & !(CHANNEL2_GATE | SPEAKER_ENABLE);
+    outb(SYSTEM_CONTROL_PORT_B, port_b);
+    outb(PIT_COMMAND, CHANNEL2_ONESHOT);
+    outb(PIT_CHANNEL2, count as u8);
+    outb(PIT_CHANNEL2, (count >> 8) as u8);
+    // A rising edge of the gate starts the count.
+    outb(SYSTEM_CONTROL_PORT_B, port_b | CHANNEL2_GATE);
+}
+
+/// Returns whether the count started by [oneshot_start] reached zero.
+pub fn oneshot_done() -> bool { inb(SYSTEM_CONTROL_PORT_B) & CHANNEL2_OUTPUT != 0 }
+
+/// Waits for channel 2 to count down `count` cycles of [PIT_FREQUENCY].
+pub fn oneshot_wait(count: u16) {
+    oneshot_start(count);
+    while !oneshot_done() {
+        core::hint::spin_loop();
+    }
+}
+
+/// Busy-waits for a number of microseconds using channel 2. Works with
+/// interrupts disabled.
+pub fn busy_wait_us(us: u32) {
+    let mut remaining = (us as u64 * PIT_FREQUENCY as u64).div_ceil(1_000_000);
+    while remaining != 0 {
+        let count = remaining.min(0xFFFF);
+        oneshot_wait(count as u16);
+        remaining -= count;
+    }
+}
+
+/// Sleeps for at least a number of milliseconds. Halts until enough channel 0
+/// ticks passed if they're being counted and interrupts are enabled, and
+/// busy-waits otherwise.
+pub fn sleep_ms(ms: u32) {
+    let hz = frequency();
+    if hz == 0 || !super::interrupts::interrupts_enabled() || crate::irq::in_interrupt() {
+        busy_wait_us(ms.saturating_mul(1000));
+        return;
+    }
+    // One tick more, as the current tick is already partially over.
+    let target = ticks() + (ms as u64 * hz as u64).div_ceil(1000) + 1;
+    while ticks() < target {
+        crate::idle::idle();
+    }
+}
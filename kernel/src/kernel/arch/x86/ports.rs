@@ -32,6 +32,28 @@ pub fn inb(port: u16) -> u8 {
     out
 }
 
+/// Outputs a dword to an IO port
+#[inline(always)]
+pub fn outl(port: u16, val: u32) {
+    unsafe {
+        asm!(
+            "out dx, eax", in("dx") port, in("eax") val
+        )
+    }
+}
+
+/// Reads a dword from an IO port
+#[inline(always)]
+pub fn inl(port: u16) -> u32 {
+    let out;
+    unsafe {
+        asm!(
+            "in eax, dx", out("eax") out, in("dx") port
+        )
+    }
+    out
+}
+
 /// Wait a short, indeterminable time
 #[inline(always)]
 pub fn io_wait() { outb(0x80, 0); }
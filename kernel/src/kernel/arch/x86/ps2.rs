@@ -0,0 +1,396 @@
+//! The 8042 PS/2 controller. It has a first port, usually a keyboard on IRQ 1,
+//! and on most machines a second one, usually a mouse on IRQ 12. Every wait is
+//! bounded, so a missing controller or device only results in an error.
+#![cfg(target_arch = "x86")]
+#![allow(static_mut_refs)]
+
+use super::ports::{inb, outb};
+
+/// Returned if the controller or a device didn't respond in time.
+pub const ERR_TIMEOUT: i16 = -1;
+/// Returned if the controller failed its self test.
+pub const ERR_SELF_TEST_FAILED: i16 = -2;
+/// Returned if a port doesn't exist or failed its test.
+pub const ERR_NO_PORT: i16 = -3;
+/// Returned if a device kept asking for a byte to be resent, or reported an
+/// error.
+pub const ERR_DEVICE_ERROR: i16 = -4;
+
+/// The data port, shared by the controller and both devices.
+const DATA_PORT: u16 = 0x60;
+/// Reads the status and takes controller commands.
+const COMMAND_PORT: u16 = 0x64;
+
+/// Set if there is a byte to read from [DATA_PORT].
+const STATUS_OUTPUT_FULL: u8 = 1 << 0;
+/// Set while the controller hasn't taken the last written byte yet.
+const STATUS_INPUT_FULL: u8 = 1 << 1;
+/// Set if the byte in [DATA_PORT] is from the second port.
+const STATUS_SECOND_PORT_DATA: u8 = 1 << 5;
+
+/// Reads the configuration byte.
+const CMD_READ_CONFIG: u8 = 0x20;
+/// Writes the configuration byte.
+const CMD_WRITE_CONFIG: u8 = 0x60;
+/// Disables the second port.
+const CMD_DISABLE_SECOND: u8 = 0xA7;
+/// Enables the second port.
+const CMD_ENABLE_SECOND: u8 = 0xA8;
+/// Tests the second port. Answers 0 if it works.
+const CMD_TEST_SECOND: u8 = 0xA9;
+/// Tests the controller. Answers [SELF_TEST_PASSED] if it works.
+const CMD_SELF_TEST: u8 = 0xAA;
+/// Tests the first port. Answers 0 if it works.
+const CMD_TEST_FIRST: u8 = 0xAB;
+/// Disables the first port.
+const CMD_DISABLE_FIRST: u8 = 0xAD;
+/// Enables the first port.
+const CMD_ENABLE_FIRST: u8 = 0xAE;
+/// Reads the output port.
+const CMD_READ_OUTPUT: u8 = 0xD0;
+/// Writes the next byte written to [DATA_PORT] to the output port.
+const CMD_WRITE_OUTPUT: u8 = 0xD1;
+/// Sends the next byte written to [DATA_PORT] to the second port.
+const CMD_WRITE_SECOND: u8 = 0xD4;
+
+/// The answer to [CMD_SELF_TEST] of a working controller.
+const SELF_TEST_PASSED: u8 = 0x55;
+
+/// Enables the IRQ of the first port.
+const CONFIG_FIRST_IRQ: u8 = 1 << 0;
+/// Enables the IRQ of the second port.
+const CONFIG_SECOND_IRQ: u8 = 1 << 1;
+/// Set while the clock of the second port is disabled.
+const CONFIG_SECOND_CLOCK_DISABLED: u8 = 1 << 5;
+/// Translates scancode set 2 from the first port to set 1.
+const CONFIG_TRANSLATION: u8 = 1 << 6;
+
+/// The bit of the output port gating address line 20.
+const OUTPUT_A20: u8 = 1 << 1;
+
+/// Resets a device. It answers [DEVICE_ACK], then [DEVICE_SELF_TEST_PASSED].
+pub(super) const DEVICE_RESET: u8 = 0xFF;
+/// Sets the defaults: 100 samples a second, 4 counts per millimeter and
+/// stream mode for mice.
+pub(super) const DEVICE_SET_DEFAULTS: u8 = 0xF6;
+/// Sets the sample rate of a mouse to the following byte.
+pub(super) const DEVICE_SET_SAMPLE_RATE: u8 = 0xF3;
+/// Makes a device send its ID bytes.
+pub(super) const DEVICE_IDENTIFY: u8 = 0xF2;
+/// Makes a device start sending data.
+pub(super) const DEVICE_ENABLE_SCANNING: u8 = 0xF4;
+/// Acknowledges a command or data byte.
+pub(super) const DEVICE_ACK: u8 = 0xFA;
+/// Asks for the last byte to be sent again.
+pub(super) const DEVICE_RESEND: u8 = 0xFE;
+/// Sent after a successful reset.
+pub(super) const DEVICE_SELF_TEST_PASSED: u8 = 0xAA;
+
+/// How long to wait for the controller, in microseconds.
+const CONTROLLER_TIMEOUT_US: u32 = 10_000;
+/// How long to wait for a device to answer a command, in microseconds.
+pub(super) const DEVICE_TIMEOUT_US: u32 = 100_000;
+/// How long to wait for a device to finish resetting, in microseconds.
+pub(super) const RESET_TIMEOUT_US: u32 = 1_000_000;
+/// How often a byte is resent before giving up.
+const RESEND_ATTEMPTS: u32 = 3;
+/// The granularity of the waits, in microseconds.
+const POLL_INTERVAL_US: u32 = 10;
+
+/// A port of the controller.
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum Ps2Port {
+    /// The first port, usually a keyboard.
+    First,
+    /// The second port, usually a mouse.
+    Second,
+}
+
+impl Ps2Port {
+    /// Returns the ISA IRQ of the port.
+    pub const fn irq(self) -> u8 {
+        match self {
+            Ps2Port::First => 1,
+            Ps2Port::Second => 12,
+        }
+    }
+
+    /// Returns the bit of the configuration byte enabling the IRQ of the port.
+    const fn config_irq(self) -> u8 {
+        match self {
+            Ps2Port::First => CONFIG_FIRST_IRQ,
+            Ps2Port::Second => CONFIG_SECOND_IRQ,
+        }
+    }
+
+    /// Returns whether the port exists and passed its test in [init].
+    pub fn present(self) -> bool {
+        unsafe {
+            match self {
+                Ps2Port::First => FIRST_PRESENT,
+                Ps2Port::Second => SECOND_PRESENT,
+            }
+        }
+    }
+
+    /// Writes a byte to the device on the port without waiting for an answer.
+    pub fn write(self, byte: u8) -> Result<(), crate::Error<'static>> {
+        if self == Ps2Port::Second {
+            write_command(CMD_WRITE_SECOND)?;
+        }
+        write_data(byte)
+    }
+
+    /// Sends a byte to the device and waits for it to be acknowledged,
+    /// resending it if the device asks to. The IRQ of the port must be
+    /// disabled, or its handler must not take the answer.
+    pub fn command(self, byte: u8) -> Result<(), crate::Error<'static>> {
+        for _ in 0..RESEND_ATTEMPTS {
+            self.write(byte)?;
+            match self.read(DEVICE_TIMEOUT_US)? {
+                DEVICE_ACK => return Ok(()),
+                DEVICE_RESEND => continue,
+                _ => break,
+            }
+        }
+        Err(crate::Error::new(
+            "PS/2 device didn't acknowledge a command",
+            ERR_DEVICE_ERROR,
+        ))
+    }
+
+    /// Reads the next byte the device sends, waiting up to `timeout_us`
+    /// microseconds. Bytes from the other port that arrive in the meantime
+    /// are passed to its driver.
+    pub fn read(self, timeout_us: u32) -> Result<u8, crate::Error<'static>> {
+        loop {
+            wait(timeout_us, || status() & STATUS_OUTPUT_FULL != 0)?;
+            match try_read() {
+                Some((port, byte)) if port == self => return Ok(byte),
+                Some((port, byte)) => dispatch(port, byte),
+                None => {},
+            }
+        }
+    }
+
+    /// Resets the device and waits for it to pass its self test. Returns the
+    /// byte sent after that, usually the ID of a mouse, if there is one.
+    pub fn reset(self) -> Result<Option<u8>, crate::Error<'static>> {
+        self.command(DEVICE_RESET)?;
+        if self.read(RESET_TIMEOUT_US)? != DEVICE_SELF_TEST_PASSED {
+            return Err(crate::Error::new(
+                "PS/2 device failed its self test",
+                ERR_DEVICE_ERROR,
+            ));
+        }
+        Ok(self.read(DEVICE_TIMEOUT_US).ok())
+    }
+
+    /// Registers the PS/2 IRQ handler for the port and enables its IRQ. Bytes
+    /// from the device are passed to its driver from then on.
+    pub fn enable_irq(self) -> Result<(), crate::Error<'static>> {
+        if !crate::irq::irq_has_handler(self.irq()) {
+            crate::irq::register_irq_handler(self.irq(), ps2_irq)?;
+        }
+        self.set_irq_enabled(true)
+    }
+
+    /// Stops handling the IRQ of the port.
+    pub fn disable_irq(self) {
+        // The controller may be gone, which leaves nothing to disable.
+        let _ = self.set_irq_enabled(false);
+        crate::irq::unregister_irq_handler(self.irq());
+    }
+
+    /// Enables or disables the IRQ of the port.
+    pub fn set_irq_enabled(self, enabled: bool) -> Result<(), crate::Error<'static>> {
+        let _irq = super::interrupts::pop_irq();
+        let config = read_config()?;
+        if enabled {
+            write_config(config | self.config_irq())
+        } else {
+            write_config(config & !self.config_irq())
+        }
+    }
+}
+
+/// Whether the first port exists and works.
+static mut FIRST_PRESENT: bool = false;
+/// Whether the second port exists and works.
+static mut SECOND_PRESENT: bool = false;
+/// Whether [init] already ran.
+static mut INITALIZED: bool = false;
+
+/// Returns the status register.
+fn status() -> u8 { inb(COMMAND_PORT) }
+
+/// Waits until `done` returns true, for up to `timeout_us` microseconds.
+fn wait(timeout_us: u32, done: impl Fn() -> bool) -> Result<(), crate::Error<'static>> {
+    for _ in 0..timeout_us / POLL_INTERVAL_US {
+        if done() {
+            return Ok(());
+        }
+        super::pit::busy_wait_us(POLL_INTERVAL_US);
+    }
+    if done() {
+        Ok(())
+    } else {
+        Err(crate::Error::new("PS/2 controller timed out", ERR_TIMEOUT))
+    }
+}
+
+/// Sends a command to the controller.
+fn write_command(command: u8) -> Result<(), crate::Error<'static>> {
+    wait(CONTROLLER_TIMEOUT_US, || status() & STATUS_INPUT_FULL == 0)?;
+    outb(COMMAND_PORT, command);
+    Ok(())
+}
+
+/// Writes a byte to the data port.
+fn write_data(byte: u8) -> Result<(), crate::Error<'static>> {
+    wait(CONTROLLER_TIMEOUT_US, || status() & STATUS_INPUT_FULL == 0)?;
+    outb(DATA_PORT, byte);
+    Ok(())
+}
+
+/// Reads a byte from the data port, waiting up to `timeout_us` microseconds
+/// for one.
+fn read_data(timeout_us: u32) -> Result<u8, crate::Error<'static>> {
+    wait(timeout_us, || status() & STATUS_OUTPUT_FULL != 0)?;
+    Ok(inb(DATA_PORT))
+}
+
+/// Reads the byte in the data port, if there is one, along with the port it
+/// came from.
+fn try_read() -> Option<(Ps2Port, u8)> {
+    let status = status();
+    if status & STATUS_OUTPUT_FULL == 0 {
+        return None;
+    }
+    let port = if status & STATUS_SECOND_PORT_DATA != 0 {
+        Ps2Port::Second
+    } else {
+        Ps2Port::First
+    };
+    Some((port, inb(DATA_PORT)))
+}
+
+/// Discards every byte waiting in the data port.
+fn flush() {
+    for _ in 0..64 {
+        if status() & STATUS_OUTPUT_FULL == 0 {
+            break;
+        }
+        inb(DATA_PORT);
+    }
+}
+
+/// Reads the configuration byte.
+fn read_config() -> Result<u8, crate::Error<'static>> {
+    write_command(CMD_READ_CONFIG)?;
+    read_data(CONTROLLER_TIMEOUT_US)
+}
+
+/// Writes the configuration byte.
+fn write_config(config: u8) -> Result<(), crate::Error<'static>> {
+    write_command(CMD_WRITE_CONFIG)?;
+    write_data(config)
+}
+
+/// Sets the A20 bit of the output port.
+fn set_output_a20() -> Result<(), crate::Error<'static>> {
+    flush();
+    write_command(CMD_READ_OUTPUT)?;
+    let output = read_data(CONTROLLER_TIMEOUT_US)?;
+    write_command(CMD_WRITE_OUTPUT)?;
+    write_data(output | OUTPUT_A20)
+}
+
+/// Enables the A20 gate through the output port of the controller. The first
+/// port is disabled meanwhile, so that no keyboard byte is read instead.
+pub fn enable_a20() -> Result<(), crate::Error<'static>> {
+    let _irq = super::interrupts::pop_irq();
+    write_command(CMD_DISABLE_FIRST)?;
+    let result = set_output_a20();
+    write_command(CMD_ENABLE_FIRST).and(result)
+}
+
+/// Returns whether the controller translates the scancodes of the first port
+/// to set 1.
+pub fn translation_enabled() -> Result<bool, crate::Error<'static>> {
+    Ok(read_config()? & CONFIG_TRANSLATION != 0)
+}
+
+/// Tests the controller and finds out which ports exist and work. Both ports
+/// are left enabled with their IRQs disabled, for their drivers to enable.
+/// Does nothing if it already succeeded.
+pub fn init() -> Result<(), crate::Error<'static>> {
+    if unsafe { INITALIZED } {
+        return Ok(());
+    }
+    let _irq = super::interrupts::pop_irq();
+    write_command(CMD_DISABLE_FIRST)?;
+    write_command(CMD_DISABLE_SECOND)?;
+    flush();
+
+    let config = read_config()? & !(CONFIG_FIRST_IRQ | CONFIG_SECOND_IRQ);
+    write_config(config)?;
+
+    write_command(CMD_SELF_TEST)?;
+    if read_data(CONTROLLER_TIMEOUT_US)? != SELF_TEST_PASSED {
+        return Err(crate::Error::new(
+            "PS/2 controller failed its self test",
+            ERR_SELF_TEST_FAILED,
+        ));
+    }
+    // Some controllers reset the configuration byte in their self test.
+    write_config(config)?;
+
+    // The clock of the second port is only enabled by enabling it if it
+    // exists.
+    let mut second = config & CONFIG_SECOND_CLOCK_DISABLED != 0;
+    if second {
+        write_command(CMD_ENABLE_SECOND)?;
+        second = read_config()? & CONFIG_SECOND_CLOCK_DISABLED == 0;
+        write_command(CMD_DISABLE_SECOND)?;
+    }
+
+    write_command(CMD_TEST_FIRST)?;
+    let first = read_data(CONTROLLER_TIMEOUT_US)? == 0;
+    if second {
+        write_command(CMD_TEST_SECOND)?;
+        second = read_data(CONTROLLER_TIMEOUT_US)? == 0;
+    }
+    if !first && !second {
+        return Err(crate::Error::new("no working PS/2 port", ERR_NO_PORT));
+    }
+
+    if first {
+        write_command(CMD_ENABLE_FIRST)?;
+    }
+    if second {
+        write_command(CMD_ENABLE_SECOND)?;
+    }
+    unsafe {
+        FIRST_PRESENT = first;
+        SECOND_PRESENT = second;
+        INITALIZED = true;
+    }
+    Ok(())
+}
+
+/// Called for IRQs 1 and 12. Either may find a byte of the other port in the
+/// shared data port, so bytes are passed on by the port the status names.
+fn ps2_irq(_irq: u8) {
+    while let Some((port, byte)) = try_read() {
+        dispatch(port, byte);
+    }
+}
+
+/// Passes a byte to the driver of the port it came from.
+fn dispatch(port: Ps2Port, byte: u8) {
+    match port {
+        Ps2Port::First => super::keyboard::handle_byte(byte),
+        Ps2Port::Second => super::mouse::handle_byte(byte),
+    }
+}
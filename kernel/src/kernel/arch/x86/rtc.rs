@@ -0,0 +1,186 @@
+//! The CMOS real-time clock. Provides the date and time at boot, and can raise
+//! IRQ 8 periodically as a secondary tick source.
+#![cfg(target_arch = "x86")]
+
+use super::ports::{inb, outb};
+use crate::time::DateTime;
+
+/// The IRQ of the RTC.
+pub const RTC_IRQ: u8 = 8;
+
+/// Selects a CMOS register. Bit 7 disables NMIs, so it's always left clear.
+const CMOS_INDEX: u16 = 0x70;
+/// Reads or writes the selected CMOS register.
+const CMOS_DATA: u16 = 0x71;
+
+/// The seconds register.
+const REG_SECONDS: u8 = 0x00;
+/// The minutes register.
+const REG_MINUTES: u8 = 0x02;
+/// The hours register.
+const REG_HOURS: u8 = 0x04;
+/// The day of the month register.
+const REG_DAY: u8 = 0x07;
+/// The month register.
+const REG_MONTH: u8 = 0x08;
+/// The year register, without the century.
+const REG_YEAR: u8 = 0x09;
+/// Status register A: the update-in-progress flag and the periodic rate.
+const REG_STATUS_A: u8 = 0x0A;
+/// Status register B: the data format and the interrupt enables.
+const REG_STATUS_B: u8 = 0x0B;
+/// Status register C: the interrupt flags, cleared when read.
+const REG_STATUS_C: u8 = 0x0C;
+
+/// Set in status register A while the RTC updates its registers.
+const STATUS_A_UPDATE_IN_PROGRESS: u8 = 1 << 7;
+/// Set in status register B if the registers are binary instead of BCD.
+const STATUS_B_BINARY: u8 = 1 << 2;
+/// Set in status register B if the hours are in 24-hour format.
+const STATUS_B_24_HOUR: u8 = 1 << 1;
+/// Enables the periodic interrupt in status register B.
+const STATUS_B_PERIODIC: u8 = 1 << 6;
+/// Set in 12-hour format hours for PM.
+const HOUR_PM: u8 = 1 << 7;
+
+/// The base frequency of the periodic interrupt.
+const RTC_BASE_FREQUENCY: u32 = 32768;
+
+/// The frequency of the periodic interrupt, or 0 if it's disabled.
+static mut PERIODIC_FREQUENCY: u32 = 0;
+/// The number of periodic interrupts since [start_periodic].
+static mut TICKS: u64 = 0;
+
+/// Reads a CMOS register.
+fn read_register(register: u8) -> u8 {
+    outb(CMOS_INDEX, register);
+    inb(CMOS_DATA)
+}
+
+/// Writes a CMOS register.
+fn write_register(register: u8, value: u8) {
+    outb(CMOS_INDEX, register);
+    outb(CMOS_DATA, value);
+}
+
+/// Converts a BCD byte to binary.
+const fn from_bcd(value: u8) -> u8 { (value >> 4) * 10 + (value & 0x0F) }
+
+/// The raw time registers: seconds, minutes, hours, day, month, year and
+/// century.
+type RawTime = [u8; 7];
+
+/// Reads the time registers once the RTC isn't updating them.
+fn read_raw(century_register: Option<u8>) -> RawTime {
+    while read_register(REG_STATUS_A) & STATUS_A_UPDATE_IN_PROGRESS != 0 {
+        core::hint::spin_loop();
+    }
+    [
+        read_register(REG_SECONDS),
+        read_register(REG_MINUTES),
+        read_register(REG_HOURS),
+        read_register(REG_DAY),
+        read_register(REG_MONTH),
+        read_register(REG_YEAR),
+        century_register.map(read_register).unwrap_or(0),
+    ]
+}
+
+/// Reads the date and time from the RTC. The RTC is assumed to be in UTC.
+pub fn read_time() -> DateTime {
+    let century_register = crate::acpi::cmos_century_register();
+    let _irq = super::interrupts::pop_irq();
+    // An update may start right after the flag was checked, so read until
+    // two reads agree.
+    let mut raw = read_raw(century_register);
+    loop {
+        let again = read_raw(century_register);
+        if again == raw {
+            break;
+        }
+        raw = again;
+    }
+    let status_b = read_register(REG_STATUS_B);
+
+    let [second, minute, hour, day, month, year, century] = raw;
+    let pm = hour & HOUR_PM != 0;
+    let convert = |value: u8| {
+        if status_b & STATUS_B_BINARY != 0 {
+            value
+        } else {
+            from_bcd(value)
+        }
+    };
+    let mut hour = convert(hour & !HOUR_PM);
+    if status_b & STATUS_B_24_HOUR == 0 {
+        // 12 AM is midnight and 12 PM is noon.
+        hour %= 12;
+        if pm {
+            hour += 12;
+        }
+    }
+    let year = convert(year) as u16;
+    let year = match century_register {
+        Some(_) => convert(century) as u16 * 100 + year,
+        None => 2000 + year,
+    };
+    DateTime {
+        year,
+        month: convert(month),
+        day: convert(day),
+        hour,
+        minute: convert(minute),
+        second: convert(second),
+    }
+}
+
+/// Starts the periodic interrupt at `32768 >> (rate - 1)` Hz. `rate` is
+/// clamped to between 3 (8192 Hz) and 15 (2 Hz).
+pub fn start_periodic(rate: u8) {
+    let rate = rate.clamp(3, 15);
+    {
+        let _irq = super::interrupts::pop_irq();
+        let status_a = read_register(REG_STATUS_A);
+        write_register(REG_STATUS_A, (status_a & 0xF0) | rate);
+        let status_b = read_register(REG_STATUS_B);
+        write_register(REG_STATUS_B, status_b | STATUS_B_PERIODIC);
+        read_register(REG_STATUS_C);
+        unsafe {
+            PERIODIC_FREQUENCY = RTC_BASE_FREQUENCY >> (rate - 1);
+        }
+    }
+    if !crate::irq::irq_has_handler(RTC_IRQ) &&
+        crate::irq::register_irq_handler(RTC_IRQ, rtc_irq).is_err()
+    {
+        super::output::swarningsln("Failed to start the RTC interrupt: IRQ 8 is in use");
+    }
+}
+
+/// Stops the periodic interrupt.
+pub fn stop_periodic() {
+    crate::irq::unregister_irq_handler(RTC_IRQ);
+    let _irq = super::interrupts::pop_irq();
+    let status_b = read_register(REG_STATUS_B);
+    write_register(REG_STATUS_B, status_b & !STATUS_B_PERIODIC);
+    unsafe {
+        PERIODIC_FREQUENCY = 0;
+    }
+}
+
+/// Returns the frequency of the periodic interrupt, or 0 if it's disabled.
+pub fn periodic_frequency() -> u32 { unsafe { PERIODIC_FREQUENCY } }
+
+/// Returns the number of periodic interrupts since [start_periodic].
+pub fn ticks() -> u64 {
+    let _irq = super::interrupts::pop_irq();
+    unsafe { TICKS }
+}
+
+/// Called for IRQ 8.
+fn rtc_irq(_irq: u8) {
+    // The RTC doesn't interrupt again until status register C is read.
+    read_register(REG_STATUS_C);
+    unsafe {
+        core::ptr::write_volatile(&raw mut TICKS, TICKS + 1);
+    }
+}
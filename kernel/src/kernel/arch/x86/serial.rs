@@ -0,0 +1,603 @@
+//! A driver for 16550 compatible UARTs on the standard COM ports. UARTs can
+//! be polled, or driven by IRQs 3 and 4 with ring buffers for received and
+//! transmitted bytes.
+#![cfg(target_arch = "x86")]
+
+use core::sync::atomic::{AtomicBool, AtomicU32, Ordering};
+
+use super::ports::{inb, outb};
+use crate::ringbuf::RingBuffer;
+
+/// Returned by [Uart::init] if there is no working UART on the port.
+pub const ERR_NO_UART: i16 = -1;
+
+/// Returned by [Uart::set_line_config] if the baud rate can't be produced.
+pub const ERR_INVALID_BAUD_RATE: i16 = -2;
+
+/// The clock of the UART divided by 16, the highest baud rate.
+pub const MAX_BAUD_RATE: u32 = 115200;
+
+/// The size of the receive and transmit buffers of every port.
+pub const SERIAL_BUFFER_SIZE: usize = 1024;
+
+/// Received data when reading, data to transmit when writing. The low byte of
+/// the divisor while [LINE_CONTROL_DLAB] is set.
+const REG_DATA: u16 = 0;
+/// Enables interrupts. The high byte of the divisor while [LINE_CONTROL_DLAB]
+/// is set.
+const REG_INTERRUPT_ENABLE: u16 = 1;
+/// Identifies pending interrupts when reading, controls the FIFOs when
+/// writing.
+const REG_FIFO_CONTROL: u16 = 2;
+/// The data format.
+const REG_LINE_CONTROL: u16 = 3;
+/// Controls the modem lines, the OUT2 interrupt gate and loopback.
+const REG_MODEM_CONTROL: u16 = 4;
+/// The state of the receiver and transmitter.
+const REG_LINE_STATUS: u16 = 5;
+/// The state of the modem lines.
+const REG_MODEM_STATUS: u16 = 6;
+/// A register with no function, used to detect the UART.
+const REG_SCRATCH: u16 = 7;
+
+/// Interrupts when a byte was received.
+const INTERRUPT_RECEIVED: u8 = 1 << 0;
+/// Interrupts when the transmit holding register is empty.
+const INTERRUPT_TRANSMIT_EMPTY: u8 = 1 << 1;
+/// Interrupts on receive errors.
+const INTERRUPT_LINE_STATUS: u8 = 1 << 2;
+
+/// Set in the interrupt identification if no interrupt is pending.
+const IIR_NONE_PENDING: u8 = 1 << 0;
+/// The interrupt identification bits.
+const IIR_ID_MASK: u8 = 0b1110;
+/// The modem status changed.
+const IIR_MODEM_STATUS: u8 = 0b0000;
+/// The transmit holding register is empty.
+const IIR_TRANSMIT_EMPTY: u8 = 0b0010;
+/// Bytes were received.
+const IIR_RECEIVED: u8 = 0b0100;
+/// A receive error happened.
+const IIR_LINE_STATUS: u8 = 0b0110;
+/// Bytes were received a while ago, but fewer than the trigger level.
+const IIR_TIMEOUT: u8 = 0b1100;
+/// The size of the transmit FIFO.
+const TRANSMIT_FIFO_SIZE: usize = 16;
+
+/// Makes the first two registers access the divisor.
+const LINE_CONTROL_DLAB: u8 = 1 << 7;
+/// Enables and clears both FIFOs, with an interrupt trigger level of 14 bytes.
+const FIFO_ENABLE_CLEAR_14: u8 = 0b1100_0111;
+/// Data terminal ready and request to send.
+const MODEM_DTR_RTS: u8 = 0b0011;
+/// Gates the interrupt line of the UART to the interrupt controller.
+const MODEM_OUT2: u8 = 1 << 3;
+/// Connects the transmitter to the receiver.
+const MODEM_LOOPBACK: u8 = 1 << 4;
+/// Set if a received byte is waiting.
+const LINE_STATUS_DATA_READY: u8 = 1 << 0;
+/// Set if a received byte was lost because the receive FIFO was full.
+const LINE_STATUS_OVERRUN: u8 = 1 << 1;
+/// Set if the transmit holding register is empty.
+const LINE_STATUS_TRANSMIT_EMPTY: u8 = 1 << 5;
+
+/// A standard COM port.
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum ComPort {
+    /// COM1, at 0x3F8 on IRQ 4.
+    Com1,
+    /// COM2, at 0x2F8 on IRQ 3.
+    Com2,
+    /// COM3, at 0x3E8 on IRQ 4.
+    Com3,
+    /// COM4, at 0x2E8 on IRQ 3.
+    Com4,
+}
+
+impl ComPort {
+    /// Returns the first I/O port of the UART.
+    pub const fn base(self) -> u16 {
+        match self {
+            ComPort::Com1 => 0x3F8,
+            ComPort::Com2 => 0x2F8,
+            ComPort::Com3 => 0x3E8,
+            ComPort::Com4 => 0x2E8,
+        }
+    }
+
+    /// Every COM port.
+    pub const ALL: [ComPort; 4] = [ComPort::Com1, ComPort::Com2, ComPort::Com3, ComPort::Com4];
+
+    /// Returns the index of the port, from 0 for COM1 to 3 for COM4.
+    pub const fn index(self) -> usize {
+        match self {
+            ComPort::Com1 => 0,
+            ComPort::Com2 => 1,
+            ComPort::Com3 => 2,
+            ComPort::Com4 => 3,
+        }
+    }
+
+    /// Returns the ISA IRQ of the UART.
+    pub const fn irq(self) -> u8 {
+        match self {
+            ComPort::Com1 | ComPort::Com3 => 4,
+            ComPort::Com2 | ComPort::Com4 => 3,
+        }
+    }
+
+    /// Returns the port with a name from "com1" to "com4".
+    pub fn from_name(name: &str) -> Option<Self> {
+        match name {
+            "com1" => Some(ComPort::Com1),
+            "com2" => Some(ComPort::Com2),
+            "com3" => Some(ComPort::Com3),
+            "com4" => Some(ComPort::Com4),
+            _ => None,
+        }
+    }
+}
+
+/// The parity bit of every character.
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum Parity {
+    /// No parity bit.
+    None,
+    /// Odd parity.
+    Odd,
+    /// Even parity.
+    Even,
+    /// The parity bit is always 1.
+    Mark,
+    /// The parity bit is always 0.
+    Space,
+}
+
+/// The number of stop bits of every character.
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum StopBits {
+    /// One stop bit.
+    One,
+    /// Two stop bits, or 1.5 with 5 data bits.
+    Two,
+}
+
+/// The speed and data format of a UART.
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub struct LineConfig {
+    /// The baud rate. Must divide [MAX_BAUD_RATE].
+    pub baud_rate: u32,
+    /// The number of data bits, from 5 to 8.
+    pub data_bits: u8,
+    /// The parity.
+    pub parity: Parity,
+    /// The number of stop bits.
+    pub stop_bits: StopBits,
+}
+
+impl LineConfig {
+    /// Returns the line control register for the format.
+    const fn line_control(&self) -> u8 {
+        let data_bits = match self.data_bits {
+            0..=5 => 0b00,
+            6 => 0b01,
+            7 => 0b10,
+            _ => 0b11,
+        };
+        let stop_bits = match self.stop_bits {
+            StopBits::One => 0,
+            StopBits::Two => 1 << 2,
+        };
+        let parity = match self.parity {
+            Parity::None => 0b000,
+            Parity::Odd => 0b001,
+            Parity::Even => 0b011,
+            Parity::Mark => 0b101,
+            Parity::Space => 0b111,
+        };
+        data_bits | stop_bits | (parity << 3)
+    }
+}
+
+impl Default for LineConfig {
+    /// 115200 baud, 8 data bits, no parity and one stop bit.
+    fn default() -> Self {
+        LineConfig {
+            baud_rate: MAX_BAUD_RATE,
+            data_bits: 8,
+            parity: Parity::None,
+            stop_bits: StopBits::One,
+        }
+    }
+}
+
+/// A 16550 compatible UART.
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub struct Uart {
+    /// The first I/O port of the UART.
+    base: u16,
+}
+
+impl Uart {
+    /// Returns the UART of a COM port. It has to be initalized with
+    /// [Uart::init] first.
+    pub const fn new(port: ComPort) -> Self { Uart { base: port.base() } }
+
+    /// Returns the first I/O port of the UART.
+    pub const fn base(&self) -> u16 { self.base }
+
+    /// Checks that the UART exists and works, then sets its line configuration,
+    /// enables its FIFOs and disables its interrupts.
+    pub fn init(&self, config: LineConfig) -> Result<(), crate::Error<'static>> {
+        // Floating ports read 0xFF, so a working scratch register means a UART.
+        outb(self.base + REG_SCRATCH, 0x5A);
+        if inb(self.base + REG_SCRATCH) != 0x5A {
+            return Err(crate::Error::new("no UART found", ERR_NO_UART));
+        }
+        outb(self.base + REG_INTERRUPT_ENABLE, 0);
+        self.set_line_config(config)?;
+        outb(self.base + REG_FIFO_CONTROL, FIFO_ENABLE_CLEAR_14);
+
+        // Sends a byte to itself to check that it transmits and receives.
+        outb(
+            self.base + REG_MODEM_CONTROL,
+            MODEM_LOOPBACK | MODEM_DTR_RTS,
+        );
+        outb(self.base + REG_DATA, 0xAE);
+        let mut received = None;
+        for _ in 0..1000 {
+            received = self.try_read_byte();
+            if received.is_some() {
+                break;
+            }
+            super::ports::io_wait();
+        }
+        if received != Some(0xAE) {
+            return Err(crate::Error::new("UART failed loopback test", ERR_NO_UART));
+        }
+        outb(self.base + REG_MODEM_CONTROL, MODEM_DTR_RTS | MODEM_OUT2);
+        Ok(())
+    }
+
+    /// Sets the baud rate and data format.
+    pub fn set_line_config(&self, config: LineConfig) -> Result<(), crate::Error<'static>> {
+        if config.baud_rate == 0 ||
+            config.baud_rate > MAX_BAUD_RATE ||
+            !MAX_BAUD_RATE.is_multiple_of(config.baud_rate)
+        {
+            return Err(crate::Error::new(
+                "invalid baud rate",
+                ERR_INVALID_BAUD_RATE,
+            ));
+        }
+        let divisor = (MAX_BAUD_RATE / config.baud_rate) as u16;
+        outb(self.base + REG_LINE_CONTROL, LINE_CONTROL_DLAB);
+        outb(self.base + REG_DATA, divisor as u8);
+        outb(self.base + REG_INTERRUPT_ENABLE, (divisor >> 8) as u8);
+        outb(self.base + REG_LINE_CONTROL, config.line_control());
+        Ok(())
+    }
+
+    /// Returns whether a byte can be written without waiting.
+    pub fn transmit_ready(&self) -> bool {
+        inb(self.base + REG_LINE_STATUS) & LINE_STATUS_TRANSMIT_EMPTY != 0
+    }
+
+    /// Waits until the UART can transmit, then writes a byte.
+    pub fn write_byte(&self, byte: u8) {
+        while !self.transmit_ready() {
+            core::hint::spin_loop();
+        }
+        outb(self.base + REG_DATA, byte);
+    }
+
+    /// Writes bytes as they are. [serial_write] translates newlines.
+    pub fn write_bytes(&self, bytes: &[u8]) {
+        for byte in bytes {
+            self.write_byte(*byte);
+        }
+    }
+
+    /// Reads a received byte, if there is one.
+    pub fn try_read_byte(&self) -> Option<u8> {
+        if inb(self.base + REG_LINE_STATUS) & LINE_STATUS_DATA_READY == 0 {
+            return None;
+        }
+        Some(inb(self.base + REG_DATA))
+    }
+}
+
+/// The state of an interrupt driven port.
+struct SerialState {
+    /// Whether the port is interrupt driven.
+    enabled: AtomicBool,
+    /// Received bytes. Filled by the IRQ handler.
+    receive: RingBuffer<u8, SERIAL_BUFFER_SIZE>,
+    /// Bytes to transmit. Emptied by the IRQ handler.
+    transmit: RingBuffer<u8, SERIAL_BUFFER_SIZE>,
+    /// Set while [transmit](SerialState::transmit) is used. Only an NMI can
+    /// find it set, and must leave the buffer alone then.
+    transmit_busy: AtomicBool,
+    /// The number of received bytes lost because a buffer was full.
+    overruns: AtomicU32,
+}
+
+impl SerialState {
+    /// Creates the state of a port that isn't interrupt driven.
+    const fn new() -> Self {
+        SerialState {
+            enabled: AtomicBool::new(false),
+            receive: RingBuffer::new(),
+            transmit: RingBuffer::new(),
+            transmit_busy: AtomicBool::new(false),
+            overruns: AtomicU32::new(0),
+        }
+    }
+
+    /// Claims the transmit buffer. Returns false if it's already in use,
+    /// which means that an NMI interrupted its user.
+    fn claim_transmit(&self) -> bool { !self.transmit_busy.swap(true, Ordering::Acquire) }
+
+    /// Releases the transmit buffer claimed with
+    /// [claim_transmit](SerialState::claim_transmit).
+    fn release_transmit(&self) { self.transmit_busy.store(false, Ordering::Release); }
+}
+
+/// The state of every port, indexed by [ComPort::index].
+static SERIAL_STATE: [SerialState; 4] = [
+    SerialState::new(),
+    SerialState::new(),
+    SerialState::new(),
+    SerialState::new(),
+];
+
+/// Makes a port interrupt driven. The UART must have been initalized with
+/// [Uart::init].
+pub fn enable_serial_interrupts(port: ComPort) -> Result<(), crate::Error<'static>> {
+    let irq = port.irq();
+    if !crate::irq::irq_has_handler(irq) {
+        crate::irq::register_irq_handler(irq, serial_irq)?;
+    }
+    let _irq = super::interrupts::pop_irq();
+    SERIAL_STATE[port.index()]
+        .enabled
+        .store(true, Ordering::Release);
+    outb(
+        port.base() + REG_INTERRUPT_ENABLE,
+        INTERRUPT_RECEIVED | INTERRUPT_LINE_STATUS,
+    );
+    Ok(())
+}
+
+/// Returns whether a port is interrupt driven.
+pub fn serial_interrupts_enabled(port: ComPort) -> bool {
+    SERIAL_STATE[port.index()].enabled.load(Ordering::Acquire)
+}
+
+/// Called for IRQs 3 and 4, which are shared by two ports each.
+fn serial_irq(irq: u8) {
+    for port in ComPort::ALL {
+        if port.irq() == irq && serial_interrupts_enabled(port) {
+            handle_port_interrupt(port);
+        }
+    }
+}
+
+/// Handles every pending interrupt of a port.
+fn handle_port_interrupt(port: ComPort) {
+    let base = port.base();
+    let state = &SERIAL_STATE[port.index()];
+    loop {
+        let iir = inb(base + REG_FIFO_CONTROL);
+        if iir & IIR_NONE_PENDING != 0 {
+            break;
+        }
+        match iir & IIR_ID_MASK {
+            IIR_LINE_STATUS => {
+                if inb(base + REG_LINE_STATUS) & LINE_STATUS_OVERRUN != 0 {
+                    state.overruns.fetch_add(1, Ordering::Relaxed);
+                }
+            },
+            IIR_RECEIVED | IIR_TIMEOUT => {
+                while inb(base + REG_LINE_STATUS) & LINE_STATUS_DATA_READY != 0 {
+                    let byte = inb(base + REG_DATA);
+                    if !state.receive.push(byte) {
+                        state.overruns.fetch_add(1, Ordering::Relaxed);
+                    }
+                }
+            },
+            IIR_TRANSMIT_EMPTY => {
+                if state.claim_transmit() {
+                    fill_transmit_fifo(port);
+                    state.release_transmit();
+                }
+            },
+            IIR_MODEM_STATUS => {
+                inb(base + REG_MODEM_STATUS);
+            },
+            _ => break,
+        }
+    }
+}
+
+/// Moves buffered bytes to the transmit FIFO once it's empty, and disables
+/// the transmit interrupt once nothing is left to send. The transmit buffer
+/// must be claimed.
+fn fill_transmit_fifo(port: ComPort) {
+    let base = port.base();
+    let state = &SERIAL_STATE[port.index()];
+    if inb(base + REG_LINE_STATUS) & LINE_STATUS_TRANSMIT_EMPTY == 0 {
+        return;
+    }
+    for _ in 0..TRANSMIT_FIFO_SIZE {
+        let Some(byte) = state.transmit.pop() else {
+            break;
+        };
+        outb(base + REG_DATA, byte);
+    }
+    let enabled = if state.transmit.is_empty() {
+        INTERRUPT_RECEIVED | INTERRUPT_LINE_STATUS
+    } else {
+        INTERRUPT_RECEIVED | INTERRUPT_LINE_STATUS | INTERRUPT_TRANSMIT_EMPTY
+    };
+    outb(base + REG_INTERRUPT_ENABLE, enabled);
+}
+
+/// Reads a received byte without waiting, if there is one.
+pub fn serial_read(port: ComPort) -> Option<u8> {
+    if !serial_interrupts_enabled(port) {
+        return Uart::new(port).try_read_byte();
+    }
+    // The kernel is the only consumer, but may be interrupted by itself.
+    let _irq = super::interrupts::pop_irq();
+    SERIAL_STATE[port.index()].receive.pop()
+}
+
+/// Reads received bytes into a buffer without waiting. Returns the number of
+/// bytes read.
+pub fn serial_read_bytes(port: ComPort, buffer: &mut [u8]) -> usize {
+    let mut read = 0;
+    while read < buffer.len() &&
+        let Some(byte) = serial_read(port)
+    {
+        buffer[read] = byte;
+        read += 1;
+    }
+    read
+}
+
+/// Waits for a byte to be received and reads it. Idles while waiting if
+/// interrupts are enabled.
+pub fn serial_read_blocking(port: ComPort) -> u8 {
+    loop {
+        if let Some(byte) = serial_read(port) {
+            return byte;
+        }
+        if serial_interrupts_enabled(port) &&
+            super::interrupts::interrupts_enabled() &&
+            !crate::irq::in_interrupt()
+        {
+            crate::idle::idle();
+        } else {
+            core::hint::spin_loop();
+        }
+    }
+}
+
+/// Returns the bytes a byte is sent as: "\n" is sent as "\r\n" for
+/// terminals.
+fn translate_newline(byte: u8) -> impl Iterator<Item = u8> {
+    (byte == b'\n').then_some(b'\r').into_iter().chain([byte])
+}
+
+/// Writes bytes by polling, translating "\n" to "\r\n".
+fn write_polled(port: ComPort, bytes: &[u8]) {
+    let uart = Uart::new(port);
+    for byte in bytes.iter().flat_map(|byte| translate_newline(*byte)) {
+        uart.write_byte(byte);
+    }
+}
+
+/// Queues bytes for transmission without waiting, translating "\n" to
+/// "\r\n". Returns the number of bytes queued, which is less than the length
+/// of `bytes` if the buffer is full. Polled ports write every byte before
+/// returning.
+pub fn serial_write(port: ComPort, bytes: &[u8]) -> usize {
+    if !serial_interrupts_enabled(port) {
+        write_polled(port, bytes);
+        return bytes.len();
+    }
+    let state = &SERIAL_STATE[port.index()];
+    // Several writers may be producers; only one may push at a time.
+    let _irq = super::interrupts::pop_irq();
+    if !state.claim_transmit() {
+        write_polled(port, bytes);
+        return bytes.len();
+    }
+    let written = bytes
+        .iter()
+        .take_while(|byte| {
+            // A newline is queued whole or not at all.
+            let free = state.transmit.capacity() - state.transmit.len();
+            translate_newline(**byte).count() <= free &&
+                translate_newline(**byte).all(|byte| state.transmit.push(byte))
+        })
+        .count();
+    fill_transmit_fifo(port);
+    state.release_transmit();
+    written
+}
+
+/// Writes bytes by polling, without relying on the IRQ handler, translating
+/// "\n" to "\r\n". Bytes still queued are sent first, unless an NMI
+/// interrupted their queueing. Used where interrupts are disabled, e.g. in
+/// NMIs and panics, so that nothing is left in the buffer.
+pub fn serial_write_polled(port: ComPort, bytes: &[u8]) {
+    let state = &SERIAL_STATE[port.index()];
+    let _irq = super::interrupts::pop_irq();
+    if state.claim_transmit() {
+        let uart = Uart::new(port);
+        while let Some(byte) = state.transmit.pop() {
+            uart.write_byte(byte);
+        }
+        state.release_transmit();
+    }
+    write_polled(port, bytes);
+}
+
+/// Queues every byte for transmission, idling while the buffer is full if
+/// interrupts are enabled.
+pub fn serial_write_blocking(port: ComPort, mut bytes: &[u8]) {
+    while !bytes.is_empty() {
+        let written = serial_write(port, bytes);
+        bytes = &bytes[written..];
+        if written == 0 {
+            if super::interrupts::interrupts_enabled() && !crate::irq::in_interrupt() {
+                crate::idle::idle();
+            } else {
+                core::hint::spin_loop();
+            }
+        }
+    }
+}
+
+/// Returns the number of received bytes a port lost, because either its
+/// receive FIFO or its receive buffer was full.
+pub fn serial_overruns(port: ComPort) -> u32 {
+    SERIAL_STATE[port.index()].overruns.load(Ordering::Relaxed)
+}
+
+/// Returns the port serial output goes to, if it's a UART.
+fn console_port() -> Option<ComPort> {
+    match super::output::output_target() {
+        super::output::OutputTarget::Serial(port) => Some(port),
+        super::output::OutputTarget::DebugPort => None,
+    }
+}
+
+/// Makes the UART serial output goes to interrupt driven.
+#[aphrodite_proc_macros::kernel_item(SerialInit)]
+pub fn init_serial() {
+    if let Some(port) = console_port() &&
+        enable_serial_interrupts(port).is_err()
+    {
+        super::output::swarningsln("Failed to make the serial console interrupt driven");
+    }
+}
+
+/// Reads a byte received on the serial console without waiting.
+#[aphrodite_proc_macros::kernel_item(SerialRead)]
+pub fn read_console() -> Option<u8> { serial_read(console_port()?) }
+
+/// Queues bytes for the serial console without waiting. Returns the number of
+/// bytes queued.
+#[aphrodite_proc_macros::kernel_item(SerialWrite)]
+pub fn write_console(bytes: &[u8]) -> usize {
+    match console_port() {
+        Some(port) => serial_write(port, bytes),
+        None => {
+            super::ports::outbs(super::DEBUG_PORT, bytes);
+            bytes.len()
+        },
+    }
+}
@@ -0,0 +1,131 @@
+//! The x86 syscall entry points. Syscalls are made with `int 0xA0` or, if the
+//! CPU supports it, SYSENTER. Both reach [crate::syscall::dispatch_syscall].
+//!
+//! With `int 0xA0`, the number is passed in eax, the arguments in ebx, ecx and
+//! edx, and the result is returned in eax.
+//!
+//! With SYSENTER, the number is passed in eax and the arguments in ebx, esi and
+//! edi, as ecx and edx have to hold the stack pointer and instruction pointer
+//! to return to. The result is returned in eax; ecx and edx are clobbered.
+//! LLVM reserves esi on x86, so Rust callers can't pass it as an asm operand;
+//! `fast_syscall!` in the user library saves esi and loads it from memory
+//! right before SYSENTER instead. ebx, esi and edi are preserved.
+#![cfg(target_arch = "x86")]
+
+use core::arch::global_asm;
+
+use super::gdt::{
+    KERNEL_CODE_SELECTOR, KERNEL_DATA_SELECTOR, PER_CPU_SELECTOR, USER_CODE_SELECTOR,
+    USER_DATA_SELECTOR,
+};
+use super::interrupts::{InterruptFrame, USER_SYSCALL_VECTOR, register_interrupt_handler};
+use super::msr::{
+    IA32_SYSENTER_CS, IA32_SYSENTER_EIP, IA32_SYSENTER_ESP, msr_supported, write_msr,
+};
+
+/// Whether SYSENTER has been set up.
+static mut SYSENTER_ENABLED: bool = false;
+
+// SYSENTER doesn't save anything, so the user stack pointer (ecx) and return
+// address (edx) are saved on the kernel stack and handed back to SYSEXIT.
+global_asm!(
+    ".global aphrodite_sysenter_entry",
+    "aphrodite_sysenter_entry:",
+    "push ecx",
+    "push edx",
+    "mov ecx, ds",
+    "push ecx",
+    "mov ecx, es",
+    "push ecx",
+    "mov ecx, gs",
+    "push ecx",
+    "mov ecx, {data}",
+    "mov ds, ecx",
+    "mov es, ecx",
+    "mov ecx, {per_cpu}",
+    "mov gs, ecx",
+    "push edi",
+    "push esi",
+    "push ebx",
+    "push eax",
+    "cld",
+    "call {dispatch}",
+    "add esp, 16",
+    "pop ecx",
+    "mov gs, ecx",
+    "pop ecx",
+    "mov es, ecx",
+    "pop ecx",
+    "mov ds, ecx",
+    "pop edx",
+    "pop ecx",
+    // sti only takes effect after the next instruction, so no interrupt can
+    // arrive on the kernel stack with the user segments loaded.
+    "sti",
+    "sysexit",
+    data = const KERNEL_DATA_SELECTOR.bits(),
+    per_cpu = const PER_CPU_SELECTOR.bits(),
+    dispatch = sym sysenter_dispatch,
+);
+
+unsafe extern "C" {
+    /// The entry point of SYSENTER.
+    #[link_name = "aphrodite_sysenter_entry"]
+    fn sysenter_entry();
+}
+
+/// Called by the SYSENTER entry point.
+extern "C" fn sysenter_dispatch(id: u32, arg0: u32, arg1: u32, arg2: u32) -> u32 {
+    crate::syscall::dispatch_syscall(id, arg0, arg1, arg2)
+}
+
+/// Called for [USER_SYSCALL_VECTOR].
+fn syscall_interrupt(frame: &mut InterruptFrame) {
+    frame.eax = crate::syscall::dispatch_syscall(frame.eax, frame.ebx, frame.ecx, frame.edx);
+}
+
+/// Returns whether the CPU supports SYSENTER and SYSEXIT.
+pub fn sysenter_supported() -> bool { msr_supported() && super::cpuid(1).1 & (1 << 11) != 0 }
+
+/// Returns whether SYSENTER has been set up.
+pub fn sysenter_enabled() -> bool { unsafe { SYSENTER_ENABLED } }
+
+/// Sets the stack pointer SYSENTER switches to. Does nothing if SYSENTER
+/// isn't enabled. [super::tss::set_kernel_stack] keeps it in sync with the
+/// TSS.
+pub fn set_sysenter_stack(esp: u32) {
+    if sysenter_enabled() {
+        unsafe { write_msr(IA32_SYSENTER_ESP, esp as u64) }
+    }
+}
+
+// SYSEXIT loads the user segments two and three entries after
+// IA32_SYSENTER_CS.
+const _: () = assert!(
+    USER_CODE_SELECTOR.index() == KERNEL_CODE_SELECTOR.index() + 2 &&
+        USER_DATA_SELECTOR.index() == KERNEL_CODE_SELECTOR.index() + 3
+);
+
+/// Programs the SYSENTER MSRs. SYSENTER uses the same kernel stack as
+/// interrupts from user mode.
+fn init_sysenter() {
+    unsafe {
+        write_msr(IA32_SYSENTER_CS, KERNEL_CODE_SELECTOR.bits() as u64);
+        write_msr(IA32_SYSENTER_ESP, super::tss::kernel_stack() as u64);
+        write_msr(
+            IA32_SYSENTER_EIP,
+            sysenter_entry as *const () as usize as u64,
+        );
+        SYSENTER_ENABLED = true;
+    }
+}
+
+/// Installs a gate callable from ring 3 at [USER_SYSCALL_VECTOR] and sets up
+/// SYSENTER if the CPU supports it.
+#[aphrodite_proc_macros::kernel_item(SyscallInit)]
+pub fn init_syscalls() {
+    register_interrupt_handler(USER_SYSCALL_VECTOR as u8, syscall_interrupt, true);
+    if sysenter_supported() {
+        init_sysenter();
+    }
+}
@@ -0,0 +1,200 @@
+//! The x86 clocksources: PIT ticks, the HPET and the TSC when the system has
+//! them and optionally the periodic RTC interrupt. The wall clock is read from
+//! the RTC.
+#![cfg(target_arch = "x86")]
+
+use core::arch::asm;
+
+use super::apic::{self, LAPIC_TIMER_IRQ, TimerDivide};
+use super::hpet;
+use super::output::*;
+use super::pit::{self, PIT_DEFAULT_HZ, PIT_FREQUENCY};
+use crate::time::{
+    ClockEventDevice, Clocksource, register_clock_event_device, register_clocksource,
+};
+
+/// The number of PIT cycles the TSC is measured over during calibration,
+/// about 10 ms.
+const CALIBRATION_CYCLES: u16 = 11932;
+/// The number of calibration runs. The shortest one is used, as the others
+/// were likely interrupted by e.g. SMIs.
+const CALIBRATION_RUNS: u32 = 3;
+
+/// Reads the time stamp counter.
+pub fn rdtsc() -> u64 {
+    let low: u32;
+    let high: u32;
+    unsafe {
+        asm!("rdtsc", out("eax") low, out("edx") high, options(nomem, nostack));
+    }
+    ((high as u64) << 32) | low as u64
+}
+
+/// Returns whether the CPU has a TSC.
+pub fn tsc_supported() -> bool { super::cpuid(1).1 & (1 << 4) != 0 }
+
+/// Returns whether the TSC runs at a constant rate in every power and
+/// frequency state.
+pub fn tsc_invariant() -> bool {
+    super::cpuid_extended_functions() &&
+        super::cpuid_max_extended_function() >= 0x80000007 &&
+        super::cpuid(0x80000007).1 & (1 << 8) != 0
+}
+
+/// Measures the frequency of the TSC against the HPET if there is one, and
+/// PIT channel 2 otherwise.
+pub fn calibrate_tsc() -> u64 {
+    let _irq = super::interrupts::pop_irq();
+    if hpet::hpet_available() {
+        let cycles = hpet::frequency() / 100;
+        // The run with the fewest TSC cycles per HPET cycle, which was
+        // interrupted the least.
+        let mut best: Option<(u64, u64)> = None;
+        for _ in 0..CALIBRATION_RUNS {
+            let start = (rdtsc(), hpet::counter());
+            let end = loop {
+                let end = (rdtsc(), hpet::counter());
+                if end.1.wrapping_sub(start.1) & 0xFFFF_FFFF >= cycles {
+                    break end;
+                }
+            };
+            let run = (end.0 - start.0, end.1.wrapping_sub(start.1) & 0xFFFF_FFFF);
+            if best.is_none_or(|best| {
+                run.0 as u128 * (best.1 as u128) < best.0 as u128 * (run.1 as u128)
+            }) {
+                best = Some(run);
+            }
+        }
+        if let Some((tsc_cycles, hpet_cycles)) = best {
+            return tsc_cycles * hpet::frequency() / hpet_cycles;
+        }
+    }
+    let mut shortest = u64::MAX;
+    for _ in 0..CALIBRATION_RUNS {
+        let start = rdtsc();
+        pit::oneshot_wait(CALIBRATION_CYCLES);
+        shortest = shortest.min(rdtsc() - start);
+    }
+    shortest * PIT_FREQUENCY as u64 / CALIBRATION_CYCLES as u64
+}
+
+/// Measures the frequency of the local APIC timer, divided by 16, against PIT
+/// channel 2.
+pub fn calibrate_lapic_timer() -> u64 {
+    let _irq = super::interrupts::pop_irq();
+    apic::timer_set_divide(TimerDivide::By16);
+    let mut shortest = u64::MAX;
+    for _ in 0..CALIBRATION_RUNS {
+        apic::timer_start(u32::MAX, false);
+        pit::oneshot_wait(CALIBRATION_CYCLES);
+        shortest = shortest.min((u32::MAX - apic::timer_current_count()) as u64);
+    }
+    apic::timer_stop();
+    shortest * PIT_FREQUENCY as u64 / CALIBRATION_CYCLES as u64
+}
+
+/// Called for the local APIC timer IRQ.
+fn lapic_timer_irq(_irq: u8) { crate::time::clock_event_fired("lapic", 0); }
+
+/// Registers the local APIC timer as a clock event device.
+fn init_lapic_timer() {
+    let frequency = calibrate_lapic_timer();
+    if crate::irq::register_irq_handler(LAPIC_TIMER_IRQ, lapic_timer_irq).is_err() {
+        return;
+    }
+    register_clock_event_device(ClockEventDevice {
+        name: "lapic",
+        frequency,
+        min_delta: (frequency / 100_000).max(1),
+        max_delta: u32::MAX as u64,
+        rating: 150,
+        data: 0,
+        program_oneshot: |_, delta| apic::timer_start(delta as u32, false),
+        program_periodic: Some(|_, period| apic::timer_start(period as u32, true)),
+        stop: |_| apic::timer_stop(),
+    });
+}
+
+/// Reads the PIT tick count.
+fn read_pit() -> u64 { pit::ticks() }
+
+/// Reads the HPET main counter.
+fn read_hpet() -> u64 { hpet::counter() }
+
+/// The rate of the periodic RTC interrupt, 1024 Hz.
+#[cfg(CONFIG_RTC_TICK = "true")]
+const RTC_TICK_RATE: u8 = 6;
+
+/// Reads the RTC tick count.
+#[cfg(CONFIG_RTC_TICK = "true")]
+fn read_rtc() -> u64 { super::rtc::ticks() }
+
+/// Returns whether PIT ticks are the clocksource, so channel 0 has to keep
+/// ticking.
+pub(super) fn pit_ticks_needed() -> bool {
+    crate::time::clocksource().is_some_and(|clocksource| clocksource.name == "pit")
+}
+
+/// Registers the clocksources: RTC and PIT ticks as fallbacks, the HPET and
+/// the calibrated TSC. Registers the local APIC timer and the HPET as clock
+/// event devices, or PIT channel 0 if neither works. Stops channel 0 ticking
+/// if its ticks aren't needed, so that it doesn't wake idle CPUs. Sets the
+/// wall clock from the RTC.
+#[aphrodite_proc_macros::kernel_item(TimeInit)]
+pub fn init_time() {
+    #[cfg(CONFIG_RTC_TICK = "true")]
+    {
+        super::rtc::start_periodic(RTC_TICK_RATE);
+        register_clocksource(Clocksource {
+            name: "rtc",
+            read: read_rtc,
+            frequency: super::rtc::periodic_frequency() as u64,
+            rating: 5,
+        });
+    }
+
+    if pit::frequency() == 0 {
+        pit::start(PIT_DEFAULT_HZ);
+    }
+    register_clocksource(Clocksource {
+        name: "pit",
+        read: read_pit,
+        frequency: pit::frequency() as u64,
+        rating: 10,
+    });
+
+    if super::irq::using_apic() {
+        init_lapic_timer();
+    }
+
+    if hpet::init() && hpet::counter_64bit() {
+        register_clocksource(Clocksource {
+            name: "hpet",
+            read: read_hpet,
+            frequency: hpet::frequency(),
+            rating: 250,
+        });
+    }
+
+    if tsc_supported() {
+        let invariant = tsc_invariant();
+        if !invariant {
+            swarningsln("The TSC isn't invariant; time may drift when the CPU frequency changes");
+        }
+        register_clocksource(Clocksource {
+            name: "tsc",
+            read: rdtsc,
+            frequency: calibrate_tsc(),
+            rating: if invariant { 300 } else { 100 },
+        });
+    }
+
+    if !pit_ticks_needed() && !super::watchdog::watchdog_running() {
+        pit::stop();
+        if crate::time::clock_event_device().is_none() {
+            pit::register_clock_event();
+        }
+    }
+
+    crate::time::set_wall_clock(super::rtc::read_time());
+}
@@ -0,0 +1,173 @@
+//! Task state segments.
+#![cfg(target_arch = "x86")]
+#![allow(static_mut_refs)]
+
+use crate::memsections::{MemorySection, Owner, SectionType};
+
+/// The size of the kernel stack used for ring transitions until a task
+/// provides its own with [set_kernel_stack].
+const DEFAULT_KERNEL_STACK_SIZE: usize = 16384;
+
+/// A 32-bit task state segment, laid out as the hardware expects it. Segment
+/// selector fields are 32 bits wide; their upper 16 bits are reserved and
+/// should be zero.
+#[repr(C)]
+#[derive(Clone, Copy)]
+pub struct TaskStateSegment {
+    /// The selector of the previous task. Set by the CPU on nested task
+    /// switches.
+    pub link: u32,
+    /// The stack pointer to load when switching to ring 0.
+    pub esp0: u32,
+    /// The stack segment to load when switching to ring 0.
+    pub ss0: u32,
+    /// The stack pointer to load when switching to ring 1.
+    pub esp1: u32,
+    /// The stack segment to load when switching to ring 1.
+    pub ss1: u32,
+    /// The stack pointer to load when switching to ring 2.
+    pub esp2: u32,
+    /// The stack segment to load when switching to ring 2.
+    pub ss2: u32,
+    /// The page directory of the task.
+    pub cr3: u32,
+    /// The saved instruction pointer.
+    pub eip: u32,
+    /// The saved flags.
+    pub eflags: u32,
+    /// The saved eax.
+    pub eax: u32,
+    /// The saved ecx.
+    pub ecx: u32,
+    /// The saved edx.
+    pub edx: u32,
+    /// The saved ebx.
+    pub ebx: u32,
+    /// The saved stack pointer.
+    pub esp: u32,
+    /// The saved ebp.
+    pub ebp: u32,
+    /// The saved esi.
+    pub esi: u32,
+    /// The saved edi.
+    pub edi: u32,
+    /// The saved es.
+    pub es: u32,
+    /// The saved cs.
+    pub cs: u32,
+    /// The saved ss.
+    pub ss: u32,
+    /// The saved ds.
+    pub ds: u32,
+    /// The saved fs.
+    pub fs: u32,
+    /// The saved gs.
+    pub gs: u32,
+    /// The LDT selector of the task.
+    pub ldtr: u32,
+    /// Bit 0 raises a debug exception on switches to this task.
+    pub trap: u16,
+    /// The offset of the I/O permission bitmap from the start of the TSS.
+    pub iomap_base: u16,
+}
+
+impl TaskStateSegment {
+    /// Creates an empty TSS with no I/O permission bitmap.
+    pub const fn new() -> Self {
+        TaskStateSegment {
+            link: 0,
+            esp0: 0,
+            ss0: 0,
+            esp1: 0,
+            ss1: 0,
+            esp2: 0,
+            ss2: 0,
+            cr3: 0,
+            eip: 0,
+            eflags: 0,
+            eax: 0,
+            ecx: 0,
+            edx: 0,
+            ebx: 0,
+            esp: 0,
+            ebp: 0,
+            esi: 0,
+            edi: 0,
+            es: 0,
+            cs: 0,
+            ss: 0,
+            ds: 0,
+            fs: 0,
+            gs: 0,
+            ldtr: 0,
+            trap: 0,
+            iomap_base: size_of::<TaskStateSegment>() as u16,
+        }
+    }
+}
+
+impl TaskStateSegment {
+    /// Sets the stack that the CPU switches to when entering ring 0 from a
+    /// less privileged ring.
+    pub fn set_ring0_stack(&mut self, ss0: u16, esp0: u32) {
+        self.ss0 = ss0 as u32;
+        self.esp0 = esp0;
+    }
+
+    /// Returns the stack segment and stack pointer used when entering ring 0.
+    pub const fn ring0_stack(&self) -> (u16, u32) { (self.ss0 as u16, self.esp0) }
+
+    /// Returns a [MemorySection] describing this TSS, for use with
+    /// [crate::memsections::MemorySections]. The TSS has to stay valid for as
+    /// long as the section is loaded.
+    pub fn memory_section(&'static self) -> MemorySection {
+        MemorySection {
+            section_type: SectionType::TaskSection { busy: false },
+            owner: Owner::Kernelspace,
+            minimal_read: false,
+            readable: false,
+            writable: false,
+            address: self as *const Self as usize as u64,
+            length: size_of::<TaskStateSegment>() as u64,
+        }
+    }
+}
+
+impl Default for TaskStateSegment {
+    fn default() -> Self { Self::new() }
+}
+
+/// The TSS of the kernel. The CPU saves the state of the interrupted code here
+/// when switching to another task, e.g. on a double fault.
+pub(super) static mut KERNEL_TSS: TaskStateSegment = TaskStateSegment::new();
+
+/// The TSS of the double fault task.
+pub(super) static mut DOUBLE_FAULT_TSS: TaskStateSegment = TaskStateSegment::new();
+
+/// The kernel stack used for ring transitions until a task provides its own.
+static mut DEFAULT_KERNEL_STACK: [u8; DEFAULT_KERNEL_STACK_SIZE] = [0; DEFAULT_KERNEL_STACK_SIZE];
+
+/// Points the ring 0 stack of the kernel TSS at the default kernel stack.
+pub(super) fn init_kernel_tss() {
+    unsafe {
+        KERNEL_TSS.set_ring0_stack(
+            super::gdt::KERNEL_DATA_SELECTOR.bits(),
+            (&raw const DEFAULT_KERNEL_STACK) as usize as u32 + DEFAULT_KERNEL_STACK_SIZE as u32,
+        );
+    }
+}
+
+/// Returns the stack pointer the CPU switches to when entering ring 0 from
+/// user mode.
+pub fn kernel_stack() -> u32 { unsafe { KERNEL_TSS.esp0 } }
+
+/// Sets the stack pointer the CPU switches to when entering ring 0 from user
+/// mode, through interrupts as well as SYSENTER. Has to be called with the top
+/// of the next task's kernel stack whenever a task is switched to.
+pub fn set_kernel_stack(esp0: u32) {
+    let _irq = super::interrupts::pop_irq();
+    unsafe {
+        KERNEL_TSS.esp0 = esp0;
+    }
+    super::syscall::set_sysenter_stack(esp0);
+}
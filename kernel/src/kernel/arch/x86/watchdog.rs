@@ -0,0 +1,102 @@
+//! The x86 side of [crate::watchdog]. The watchdog is driven by the
+//! interrupts of [PIT](super::pit) channel 0, whether it ticks or is the clock
+//! event device. With the APICs, a ticking IRQ 0 is delivered as an NMI while
+//! the watchdog runs so that a CPU stuck with interrupts disabled is still
+//! caught. With the PICs, or when channel 0 is the clock event device, it stays
+//! a normal, maskable IRQ, so only CPUs stuck with interrupts enabled are
+//! caught. A channel 0 started only for the watchdog ticks at the watchdog's
+//! frequency, which is then how often it wakes idle CPUs.
+#![cfg(target_arch = "x86")]
+
+use super::interrupts::InterruptFrame;
+use super::pit::{PIT_FREQUENCY, PIT_IRQ};
+
+/// Whether the watchdog is delivered as an NMI.
+static mut NMI_MODE: bool = false;
+/// The number of PIT cycles per watchdog tick, or 0 if the watchdog is
+/// stopped.
+static mut CYCLES_PER_CHECK: u32 = 0;
+/// The number of PIT cycles since the last watchdog tick.
+static mut PENDING_CYCLES: u32 = 0;
+
+/// Called for every interrupt of channel 0, `cycles` PIT cycles after the
+/// last one. Calls [crate::watchdog::watchdog_tick] at the watchdog's
+/// frequency and reports where the CPU was stuck if it fired.
+pub(super) fn pit_tick(frame: Option<&InterruptFrame>, cycles: u32) {
+    unsafe {
+        if CYCLES_PER_CHECK == 0 {
+            return;
+        }
+        PENDING_CYCLES = PENDING_CYCLES.saturating_add(cycles);
+        if PENDING_CYCLES < CYCLES_PER_CHECK {
+            return;
+        }
+        PENDING_CYCLES = 0;
+    }
+    if crate::watchdog::watchdog_tick() &&
+        let Some(frame) = frame
+    {
+        super::exceptions::report_stuck(frame, crate::watchdog::watchdog_timeout());
+    }
+}
+
+/// Called by the NMI handler. Returns whether the NMI was a PIT tick, which
+/// it isn't if the chipset reports an NMI source or channel 0 didn't just
+/// reload.
+pub(super) fn watchdog_nmi(frame: &InterruptFrame) -> bool {
+    if !unsafe { NMI_MODE } ||
+        super::exceptions::chipset_nmi() ||
+        !super::pit::channel0_just_fired()
+    {
+        return false;
+    }
+    super::pit::tick(Some(frame));
+    true
+}
+
+/// Returns whether the watchdog is running.
+pub(super) fn watchdog_running() -> bool { unsafe { CYCLES_PER_CHECK != 0 } }
+
+/// Starts calling [crate::watchdog::watchdog_tick] `hz` times a second,
+/// starting channel 0 ticking at `hz` unless it already ticks or is the clock
+/// event device. Without the APICs, or with channel 0 as the clock event
+/// device, the ticks are masked along with other interrupts, so a warning is
+/// output that a CPU stuck with interrupts disabled won't be caught.
+#[aphrodite_proc_macros::kernel_item(WatchdogStart)]
+pub fn start_watchdog(hz: u32) {
+    if super::pit::frequency() == 0 {
+        super::pit::start(hz);
+    }
+    unsafe {
+        PENDING_CYCLES = 0;
+        CYCLES_PER_CHECK = (PIT_FREQUENCY / hz.max(1)).max(1);
+    }
+    if super::irq::using_apic() && !super::pit::clock_event_mode() {
+        unsafe {
+            NMI_MODE = true;
+        }
+        super::apic::route_as_nmi(PIT_IRQ);
+    } else {
+        super::output::swarningsln(
+            "Watchdog can't catch a CPU stuck with interrupts disabled without an NMI source",
+        );
+    }
+}
+
+/// Stops the watchdog. Channel 0 stops ticking too unless its ticks are the
+/// clocksource.
+#[aphrodite_proc_macros::kernel_item(WatchdogStop)]
+pub fn stop_watchdog() {
+    unsafe {
+        CYCLES_PER_CHECK = 0;
+    }
+    if unsafe { NMI_MODE } {
+        super::apic::unmask(PIT_IRQ);
+        unsafe {
+            NMI_MODE = false;
+        }
+    }
+    if !super::time::pit_ticks_needed() {
+        super::pit::stop();
+    }
+}
@@ -147,4 +147,8 @@ pub struct BootInfo<'a> {
 
     /// Provides a way to display text.
     pub output: Option<&'a dyn crate::display::TextDisplay>,
+
+    /// The address of a copy of the ACPI RSDP provided by the bootloader. If
+    /// None, the kernel will search for it.
+    pub rsdp: Option<usize>,
 }
@@ -0,0 +1,94 @@
+//! Deferred interrupt work. IRQ handlers queue work that is too slow to do
+//! with interrupts disabled; it runs with interrupts enabled when the last
+//! nested IRQ exits, or whenever [run_deferred_work] is called.
+#![allow(static_mut_refs)]
+
+/// A function that does deferred work. It is passed the data it was queued
+/// with.
+pub type DeferredFn = fn(usize);
+
+/// The number of work items that can be queued at once.
+pub const DEFERRED_QUEUE_LEN: usize = 64;
+
+/// Returned by [queue_work] if the queue is full.
+pub const ERR_QUEUE_FULL: i16 = -1;
+
+/// A queued work item.
+#[derive(Clone, Copy)]
+struct DeferredWork {
+    /// The function to run.
+    func: DeferredFn,
+    /// The data passed to the function.
+    data: usize,
+}
+
+/// The queued work items, as a ring buffer.
+static mut QUEUE: [Option<DeferredWork>; DEFERRED_QUEUE_LEN] = [None; DEFERRED_QUEUE_LEN];
+
+/// The index of the oldest queued work item.
+static mut HEAD: usize = 0;
+
+/// The number of queued work items.
+static mut LEN: usize = 0;
+
+/// Whether deferred work is currently running.
+static mut RUNNING: bool = false;
+
+/// Queues a function to be called with `data` later with interrupts enabled.
+pub fn queue_work(func: DeferredFn, data: usize) -> Result<(), crate::Error<'static>> {
+    let _irq = crate::arch::interrupts::InterruptsPop();
+    unsafe {
+        if LEN == DEFERRED_QUEUE_LEN {
+            return Err(crate::Error::new(
+                "deferred work queue is full",
+                ERR_QUEUE_FULL,
+            ));
+        }
+        QUEUE[(HEAD + LEN) % DEFERRED_QUEUE_LEN] = Some(DeferredWork { func, data });
+        LEN += 1;
+    }
+    Ok(())
+}
+
+/// Returns whether any work is queued.
+pub fn work_pending() -> bool { unsafe { LEN != 0 } }
+
+/// Returns whether deferred work is currently running.
+pub fn in_deferred_work() -> bool { unsafe { RUNNING } }
+
+/// Removes the oldest work item from the queue.
+fn pop_work() -> Option<DeferredWork> {
+    let _irq = crate::arch::interrupts::InterruptsPop();
+    unsafe {
+        if LEN == 0 {
+            return None;
+        }
+        let work = QUEUE[HEAD].take();
+        HEAD = (HEAD + 1) % DEFERRED_QUEUE_LEN;
+        LEN -= 1;
+        work
+    }
+}
+
+/// Runs queued work until the queue is empty. Interrupts are enabled while the
+/// work runs and restored afterwards. Does nothing if deferred work is already
+/// running further up the stack.
+pub fn run_deferred_work() {
+    let irq = crate::arch::interrupts::InterruptsPop();
+    unsafe {
+        if RUNNING {
+            return;
+        }
+        RUNNING = true;
+    }
+    crate::arch::interrupts::InterruptsEnable();
+    while let Some(work) = pop_work() {
+        (work.func)(work.data);
+        crate::watchdog::watchdog_touch();
+    }
+    crate::arch::interrupts::InterruptsDisable();
+    unsafe {
+        RUNNING = false;
+    }
+    drop(irq);
+}
@@ -0,0 +1,393 @@
+//! The device model. Devices are found on a bus: platform devices are
+//! registered by the architecture, and every PCI function becomes a PCI
+//! device, or a virtio device if it's a virtio transport. Drivers carry a
+//! table of the devices they handle. Whenever a device or driver is
+//! registered, each unbound device is probed by the first driver that
+//! matches it, and stays bound to it until one of them is removed.
+#![allow(static_mut_refs)]
+
+use crate::arch::output::*;
+use crate::pci::{PciDevice, PciMatch};
+
+/// The number of devices the registry can hold.
+pub const MAX_DEVICES: usize = 192;
+/// The number of drivers the registry can hold.
+pub const MAX_DRIVERS: usize = 32;
+
+/// Returned if the registry is full.
+pub const ERR_REGISTRY_FULL: i16 = -1;
+/// Returned if the driver is already registered.
+pub const ERR_DRIVER_REGISTERED: i16 = -2;
+
+/// The vendor ID of virtio PCI functions.
+pub const VIRTIO_VENDOR_ID: u16 = 0x1AF4;
+/// The first device ID of transitional virtio PCI functions, whose device
+/// type is their subsystem ID.
+const VIRTIO_TRANSITIONAL_DEVICE_ID: u16 = 0x1000;
+/// The device ID of modern virtio PCI functions of device type 0.
+const VIRTIO_MODERN_DEVICE_ID: u16 = 0x1040;
+/// The last device ID of virtio PCI functions.
+const VIRTIO_LAST_DEVICE_ID: u16 = 0x107F;
+
+/// The virtio device type of network cards.
+pub const VIRTIO_NET: u16 = 1;
+/// The virtio device type of block devices.
+pub const VIRTIO_BLOCK: u16 = 2;
+/// The virtio device type of consoles.
+pub const VIRTIO_CONSOLE: u16 = 3;
+/// The virtio device type of entropy sources.
+pub const VIRTIO_ENTROPY: u16 = 4;
+/// The virtio device type of GPUs.
+pub const VIRTIO_GPU: u16 = 16;
+/// The virtio device type of input devices.
+pub const VIRTIO_INPUT: u16 = 18;
+
+/// A bus devices are found on.
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum Bus {
+    /// Devices that can't be enumerated, registered by the architecture.
+    Platform,
+    /// PCI functions.
+    Pci,
+    /// Virtio devices.
+    Virtio,
+}
+
+impl Bus {
+    /// Returns the name of the bus.
+    pub const fn name(self) -> &'static str {
+        match self {
+            Bus::Platform => "platform",
+            Bus::Pci => "pci",
+            Bus::Virtio => "virtio",
+        }
+    }
+}
+
+/// What a device is, depending on its bus.
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum DeviceKind {
+    /// A platform device, identified by its name.
+    Platform {
+        /// The name, e.g. "i8042-keyboard".
+        name: &'static str,
+    },
+    /// A PCI function.
+    Pci(PciDevice),
+    /// A virtio device.
+    Virtio {
+        /// The virtio device type, e.g. [VIRTIO_BLOCK].
+        device_type: u16,
+        /// The PCI function it's reached through.
+        transport: PciDevice,
+    },
+}
+
+impl DeviceKind {
+    /// Returns the bus of the device.
+    pub const fn bus(&self) -> Bus {
+        match self {
+            DeviceKind::Platform { .. } => Bus::Platform,
+            DeviceKind::Pci(_) => Bus::Pci,
+            DeviceKind::Virtio { .. } => Bus::Virtio,
+        }
+    }
+}
+
+/// An entry of the match table of a driver.
+#[derive(Clone, Copy)]
+pub enum DeviceMatch {
+    /// Matches platform devices with a name.
+    Platform(&'static str),
+    /// Matches PCI functions.
+    Pci(PciMatch),
+    /// Matches virtio devices of a device type.
+    Virtio(u16),
+}
+
+impl DeviceMatch {
+    /// Returns whether a device matches.
+    pub fn matches(&self, kind: &DeviceKind) -> bool {
+        match (self, kind) {
+            (DeviceMatch::Platform(name), DeviceKind::Platform { name: device }) => name == device,
+            (DeviceMatch::Pci(pci_match), DeviceKind::Pci(device)) => pci_match.matches(device),
+            (
+                DeviceMatch::Virtio(device_type),
+                DeviceKind::Virtio {
+                    device_type: ty, ..
+                },
+            ) => device_type == ty,
+            _ => false,
+        }
+    }
+}
+
+/// Identifies a device in the registry.
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub struct DeviceId(u16);
+
+/// A device in the registry.
+#[derive(Clone, Copy)]
+pub struct Device {
+    /// The ID of the device.
+    pub id: DeviceId,
+    /// What the device is.
+    pub kind: DeviceKind,
+    /// The driver bound to the device.
+    driver: Option<&'static Driver>,
+}
+
+impl Device {
+    /// Returns the bus of the device.
+    pub const fn bus(&self) -> Bus { self.kind.bus() }
+
+    /// Returns the driver bound to the device.
+    pub fn driver(&self) -> Option<&'static Driver> { self.driver }
+}
+
+/// A driver.
+pub struct Driver {
+    /// The name of the driver, e.g. "ps2-keyboard".
+    pub name: &'static str,
+    /// The devices the driver handles.
+    pub matches: &'static [DeviceMatch],
+    /// Sets up a matching device. The driver is bound to it if this succeeds.
+    pub probe: fn(&Device) -> Result<(), crate::Error<'static>>,
+    /// Stops using a device the driver is bound to.
+    pub remove: fn(&Device),
+}
+
+impl Driver {
+    /// Returns whether the driver handles a device.
+    pub fn matches(&self, kind: &DeviceKind) -> bool {
+        self.matches.iter().any(|entry| entry.matches(kind))
+    }
+}
+
+/// The registered devices.
+static mut DEVICES: [Option<Device>; MAX_DEVICES] = [None; MAX_DEVICES];
+/// The registered drivers.
+static mut DRIVERS: [Option<&'static Driver>; MAX_DRIVERS] = [None; MAX_DRIVERS];
+
+/// Returns whether two references are to the same driver.
+fn same_driver(a: &'static Driver, b: &'static Driver) -> bool { core::ptr::eq(a, b) }
+
+/// Returns a device from the registry.
+pub fn device(id: DeviceId) -> Option<Device> {
+    unsafe { DEVICES.get(id.0 as usize).copied().flatten() }
+}
+
+/// Returns every registered device.
+pub fn devices() -> impl Iterator<Item = Device> {
+    (0..MAX_DEVICES).filter_map(|index| device(DeviceId(index as u16)))
+}
+
+/// Returns every registered driver.
+pub fn drivers() -> impl Iterator<Item = &'static Driver> {
+    (0..MAX_DRIVERS).filter_map(|index| unsafe { DRIVERS[index] })
+}
+
+/// Probes an unbound device with a driver, if it matches, and binds them if
+/// it succeeds. Returns whether they were bound.
+fn try_bind(id: DeviceId, driver: &'static Driver) -> bool {
+    let Some(device) = device(id) else {
+        return false;
+    };
+    if device.driver.is_some() || !driver.matches(&device.kind) {
+        return false;
+    }
+    if (driver.probe)(&device).is_err() {
+        swarnings("Driver ");
+        swarningsnp(driver.name);
+        swarningsnpln(" failed to probe a device");
+        return false;
+    }
+    let _irq = crate::arch::interrupts::InterruptsPop();
+    unsafe {
+        // The device may have been removed by the probe.
+        if let Some(device) = DEVICES[id.0 as usize].as_mut() {
+            device.driver = Some(driver);
+        }
+    }
+    true
+}
+
+/// Adds a device to the registry and binds it to the first registered driver
+/// that matches it.
+pub fn register_device(kind: DeviceKind) -> Result<DeviceId, crate::Error<'static>> {
+    let id = {
+        let _irq = crate::arch::interrupts::InterruptsPop();
+        let Some(index) = (unsafe { DEVICES.iter().position(Option::is_none) }) else {
+            return Err(crate::Error::new(
+                "device registry is full",
+                ERR_REGISTRY_FULL,
+            ));
+        };
+        let id = DeviceId(index as u16);
+        unsafe {
+            DEVICES[index] = Some(Device {
+                id,
+                kind,
+                driver: None,
+            });
+        }
+        id
+    };
+    for driver in drivers() {
+        if try_bind(id, driver) {
+            break;
+        }
+    }
+    Ok(id)
+}
+
+/// Removes a device from the registry, after its driver stops using it.
+pub fn remove_device(id: DeviceId) {
+    let Some(device) = device(id) else {
+        return;
+    };
+    if let Some(driver) = device.driver {
+        (driver.remove)(&device);
+    }
+    let _irq = crate::arch::interrupts::InterruptsPop();
+    unsafe {
+        DEVICES[id.0 as usize] = None;
+    }
+}
+
+/// Adds a driver to the registry and binds it to every unbound device it
+/// matches.
+pub fn register_driver(driver: &'static Driver) -> Result<(), crate::Error<'static>> {
+    {
+        let _irq = crate::arch::interrupts::InterruptsPop();
+        if drivers().any(|registered| same_driver(registered, driver)) {
+            return Err(crate::Error::new(
+                "driver is already registered",
+                ERR_DRIVER_REGISTERED,
+            ));
+        }
+        let Some(slot) = (unsafe { DRIVERS.iter_mut().find(|slot| slot.is_none()) }) else {
+            return Err(crate::Error::new(
+                "driver registry is full",
+                ERR_REGISTRY_FULL,
+            ));
+        };
+        *slot = Some(driver);
+    }
+    for index in 0..MAX_DEVICES {
+        try_bind(DeviceId(index as u16), driver);
+    }
+    Ok(())
+}
+
+/// Removes a driver from the registry, after it stops using the devices bound
+/// to it. They're left unbound.
+pub fn unregister_driver(driver: &'static Driver) {
+    for mut device in devices() {
+        if device
+            .driver
+            .is_some_and(|bound| same_driver(bound, driver))
+        {
+            (driver.remove)(&device);
+            device.driver = None;
+            let _irq = crate::arch::interrupts::InterruptsPop();
+            unsafe {
+                DEVICES[device.id.0 as usize] = Some(device);
+            }
+        }
+    }
+    let _irq = crate::arch::interrupts::InterruptsPop();
+    unsafe {
+        for slot in DRIVERS.iter_mut() {
+            if slot.is_some_and(|registered| same_driver(registered, driver)) {
+                *slot = None;
+            }
+        }
+    }
+}
+
+/// Returns the virtio device type of a PCI function, if it's a virtio
+/// transport.
+pub fn virtio_device_type(device: &PciDevice) -> Option<u16> {
+    if device.vendor_id != VIRTIO_VENDOR_ID ||
+        !(VIRTIO_TRANSITIONAL_DEVICE_ID..=VIRTIO_LAST_DEVICE_ID).contains(&device.device_id)
+    {
+        return None;
+    }
+    if device.device_id >= VIRTIO_MODERN_DEVICE_ID {
+        Some(device.device_id - VIRTIO_MODERN_DEVICE_ID)
+    } else {
+        Some(device.subsystem_id)
+    }
+}
+
+/// Registers the PCI functions found by [crate::pci::init_pci] and the
+/// platform devices and drivers of the architecture. Called once IRQs are set
+/// up.
+pub fn init_devices() {
+    for pci_device in crate::pci::pci_devices() {
+        let kind = match virtio_device_type(pci_device) {
+            Some(device_type) => DeviceKind::Virtio {
+                device_type,
+                transport: *pci_device,
+            },
+            None => DeviceKind::Pci(*pci_device),
+        };
+        if register_device(kind).is_err() {
+            swarningsln("Some devices didn't fit in the registry");
+            break;
+        }
+    }
+    crate::arch::device::DeviceInit();
+}
+
+/// Outputs a number through the debug output.
+fn output_number(value: usize) { sdebugbnp(&crate::usize_as_u8_slice(value)); }
+
+/// Outputs a PCI address through the debug output.
+fn output_pci_address(device: &PciDevice) {
+    let hex = |value: u8, digits: usize| {
+        sdebugbnp(&crate::u32_as_hex_u8_slice(value as u32)[8 - digits..]);
+    };
+    hex(device.address.bus, 2);
+    sdebugsnp(":");
+    hex(device.address.device, 2);
+    sdebugsnp(".");
+    hex(device.address.function, 1);
+}
+
+/// Outputs the registry through the debug output, one device per line with
+/// its bus and driver, followed by the drivers.
+pub fn print_devices() {
+    for device in devices() {
+        sdebugs("Device ");
+        output_number(device.id.0 as usize);
+        sdebugsnp(" ");
+        sdebugsnp(device.bus().name());
+        sdebugsnp(" ");
+        match device.kind {
+            DeviceKind::Platform { name } => sdebugsnp(name),
+            DeviceKind::Pci(pci_device) => output_pci_address(&pci_device),
+            DeviceKind::Virtio {
+                device_type,
+                transport,
+            } => {
+                sdebugsnp("type ");
+                output_number(device_type as usize);
+                sdebugsnp(" at ");
+                output_pci_address(&transport);
+            },
+        }
+        match device.driver {
+            Some(driver) => {
+                sdebugsnp(" bound to ");
+                sdebugsnpln(driver.name);
+            },
+            None => sdebugsnpln(" unbound"),
+        }
+    }
+    for driver in drivers() {
+        sdebugs("Driver ");
+        sdebugsnpln(driver.name);
+    }
+}
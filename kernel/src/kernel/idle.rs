@@ -0,0 +1,26 @@
+//! The idle loop. It waits for interrupts with the CPU halted instead of
+//! spinning, and stops the timer tick while no timer is due soon.
+
+/// Waits for an interrupt once. Deferred work is run first, and the tick is
+/// stopped while waiting if no timer is due soon.
+pub fn idle() {
+    crate::arch::interrupts::InterruptsDisable();
+    if crate::deferred::work_pending() {
+        crate::deferred::run_deferred_work();
+    }
+    // Idling is progress, not a hang.
+    crate::watchdog::watchdog_touch();
+    crate::timer::tick_stop();
+    // Enables interrupts and halts without an interrupt getting in between,
+    // so that an interrupt that arrived after the checks above still wakes
+    // the CPU.
+    crate::arch::interrupts::InterruptsWait();
+    crate::timer::tick_resume();
+}
+
+/// Idles forever. Interrupt handlers, deferred work and timers do the work.
+pub fn idle_loop() -> ! {
+    loop {
+        idle();
+    }
+}
@@ -76,5 +76,43 @@ fn indep_boot_entry(
         }
         tdebugsln("", display).unwrap();
     }
-    loop {}
+
+    match crate::acpi::init_acpi(BI.rsdp) {
+        Ok(()) => tdebugsln("ACPI tables found", display).unwrap(),
+        Err(err) => {
+            twarnings("ACPI tables unavailable: ", display).unwrap();
+            err.display_np(display);
+        },
+    }
+
+    crate::pci::init_pci();
+    tdebugs("PCI functions found: ", display).unwrap();
+    tdebugbnpln(
+        &crate::usize_as_u8_slice(crate::pci::pci_devices().len()),
+        display,
+    )
+    .unwrap();
+    crate::pci::print_pci_devices();
+
+    crate::irq::init_irqs();
+    crate::syscall::init_syscalls();
+    crate::arch::serial::SerialInit();
+    crate::device::init_devices();
+    crate::device::print_devices();
+    crate::time::init_time();
+    crate::timer::init_timers();
+    if let Some(clocksource) = crate::time::clocksource() {
+        tdebugs("Using clocksource ", display).unwrap();
+        tdebugsnpln(clocksource.name, display).unwrap();
+    }
+    crate::arch::interrupts::InterruptsEnable();
+    tdebugsln("IRQs enabled", display).unwrap();
+
+    #[cfg(CONFIG_WATCHDOG = "true")]
+    {
+        crate::watchdog::watchdog_start(crate::watchdog::WATCHDOG_TIMEOUT_SECONDS);
+        tdebugsln("Watchdog started", display).unwrap();
+    }
+
+    crate::idle::idle_loop()
 }
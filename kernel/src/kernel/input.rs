@@ -0,0 +1,90 @@
+//! The input event queue. Input drivers report key, motion, wheel and button
+//! events with [report_input_event], usually from their IRQ handler, and the
+//! kernel reads them in order with [read_input_event]. Key events are also
+//! queued separately by [crate::keyboard] for reading characters.
+
+use core::sync::atomic::{AtomicU32, Ordering};
+
+use crate::keyboard::KeyEvent;
+use crate::ringbuf::RingBuffer;
+
+/// The number of input events that can be queued.
+pub const INPUT_EVENT_QUEUE_LEN: usize = 256;
+
+/// A mouse button.
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum MouseButton {
+    /// The left button.
+    Left,
+    /// The right button.
+    Right,
+    /// The middle button, usually the wheel.
+    Middle,
+    /// The first side button, usually back.
+    Fourth,
+    /// The second side button, usually forward.
+    Fifth,
+}
+
+/// An event from an input device.
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum InputEvent {
+    /// A key was pressed or released.
+    Key(KeyEvent),
+    /// The mouse moved. X grows to the right and Y grows downwards, like
+    /// screen coordinates.
+    MouseMotion {
+        /// The horizontal movement.
+        dx: i16,
+        /// The vertical movement.
+        dy: i16,
+    },
+    /// The mouse wheel turned. Positive values scroll down.
+    MouseWheel {
+        /// The number of wheel steps.
+        delta: i8,
+    },
+    /// A mouse button was pressed or released.
+    MouseButton {
+        /// The button.
+        button: MouseButton,
+        /// Whether the button was pressed or released.
+        pressed: bool,
+    },
+}
+
+/// The queued input events.
+static INPUT_EVENTS: RingBuffer<InputEvent, INPUT_EVENT_QUEUE_LEN> = RingBuffer::new();
+
+/// The number of input events lost because the queue was full.
+static DROPPED_INPUT_EVENTS: AtomicU32 = AtomicU32::new(0);
+
+/// Queues an input event. Called by input drivers.
+pub fn report_input_event(event: InputEvent) {
+    // Several devices may report events, possibly from nested IRQs.
+    let _irq = crate::arch::interrupts::InterruptsPop();
+    if !INPUT_EVENTS.push(event) {
+        DROPPED_INPUT_EVENTS.fetch_add(1, Ordering::Relaxed);
+    }
+}
+
+/// Returns the oldest queued input event without waiting, if there is one.
+pub fn read_input_event() -> Option<InputEvent> {
+    // The kernel is the only consumer, but may be interrupted by itself.
+    let _irq = crate::arch::interrupts::InterruptsPop();
+    INPUT_EVENTS.pop()
+}
+
+/// Waits for an input event and returns it. Must be called with interrupts
+/// enabled, outside of interrupt context.
+pub fn read_input_event_blocking() -> InputEvent {
+    loop {
+        if let Some(event) = read_input_event() {
+            return event;
+        }
+        crate::idle::idle();
+    }
+}
+
+/// Returns the number of input events lost because the queue was full.
+pub fn dropped_input_events() -> u32 { DROPPED_INPUT_EVENTS.load(Ordering::Relaxed) }
@@ -0,0 +1,119 @@
+//! Architecture independent handling of hardware interrupts (IRQs). The
+//! architecture delivers IRQs to [handle_irq] from whatever interrupt
+//! controller it uses.
+#![allow(static_mut_refs)]
+
+/// A function that handles an IRQ. It is passed the number of the IRQ.
+pub type IrqHandler = fn(u8);
+
+/// The highest number of IRQs that can have handlers.
+pub const IRQ_COUNT: usize = 224;
+
+/// Returned by [register_irq_handler] if the IRQ number is too high.
+pub const ERR_INVALID_IRQ: i16 = -1;
+
+/// Returned by [register_irq_handler] if the IRQ already has a handler.
+pub const ERR_IRQ_IN_USE: i16 = -2;
+
+/// The registered IRQ handlers, indexed by IRQ.
+static mut IRQ_HANDLERS: [Option<IrqHandler>; IRQ_COUNT] = [None; IRQ_COUNT];
+
+/// The number of IRQs that arrived without a handler.
+static mut UNHANDLED_IRQS: u32 = 0;
+
+/// Initalizes the interrupt controller of the architecture. Every IRQ starts
+/// masked until a handler is registered for it.
+pub fn init_irqs() { crate::arch::irq::IrqInit(); }
+
+/// Registers a handler for an IRQ and unmasks it.
+pub fn register_irq_handler(irq: u8, handler: IrqHandler) -> Result<(), crate::Error<'static>> {
+    if irq as usize >= IRQ_COUNT {
+        return Err(crate::Error::new("invalid IRQ", ERR_INVALID_IRQ));
+    }
+    let _irq = crate::arch::interrupts::InterruptsPop();
+    unsafe {
+        if IRQ_HANDLERS[irq as usize].is_some() {
+            return Err(crate::Error::new(
+                "IRQ already has a handler",
+                ERR_IRQ_IN_USE,
+            ));
+        }
+        IRQ_HANDLERS[irq as usize] = Some(handler);
+    }
+    set_masked(irq, false);
+    Ok(())
+}
+
+/// Masks an IRQ and removes its handler.
+pub fn unregister_irq_handler(irq: u8) {
+    if irq as usize >= IRQ_COUNT {
+        return;
+    }
+    let _irq = crate::arch::interrupts::InterruptsPop();
+    set_masked(irq, true);
+    unsafe {
+        IRQ_HANDLERS[irq as usize] = None;
+    }
+}
+
+/// Masks or unmasks an IRQ, in the function that sends it if it's a message
+/// signaled interrupt and in the interrupt controller otherwise.
+fn set_masked(irq: u8, masked: bool) {
+    if crate::msi::set_msi_masked(irq, masked) {
+        return;
+    }
+    if masked {
+        crate::arch::irq::IrqMask(irq);
+    } else {
+        crate::arch::irq::IrqUnmask(irq);
+    }
+}
+
+/// Returns whether an IRQ has a handler.
+pub fn irq_has_handler(irq: u8) -> bool {
+    unsafe {
+        IRQ_HANDLERS
+            .get(irq as usize)
+            .is_some_and(|handler| handler.is_some())
+    }
+}
+
+/// Returns the number of IRQs that arrived without a handler.
+pub fn unhandled_irqs() -> u32 { unsafe { UNHANDLED_IRQS } }
+
+/// Runs the handler of an IRQ. Called by the architecture with interrupts
+/// disabled, between [irq_enter] and acknowledging the IRQ.
+pub fn handle_irq(irq: u8) {
+    match unsafe { IRQ_HANDLERS.get(irq as usize).copied().flatten() } {
+        Some(handler) => handler(irq),
+        None => unsafe {
+            UNHANDLED_IRQS += 1;
+        },
+    }
+}
+
+/// Marks the start of an IRQ. Called by the architecture before
+/// [handle_irq].
+pub fn irq_enter() {
+    let depth = crate::per_cpu!(irq_depth) + 1;
+    crate::per_cpu!(irq_depth = depth);
+}
+
+/// Marks the end of an IRQ. Called by the architecture after acknowledging
+/// the IRQ. When the outermost IRQ exits, deferred work is run with interrupts
+/// enabled.
+pub fn irq_exit() {
+    let depth = crate::per_cpu!(irq_depth).saturating_sub(1);
+    crate::per_cpu!(irq_depth = depth);
+    if depth == 0 && crate::deferred::work_pending() {
+        crate::deferred::run_deferred_work();
+    }
+}
+
+/// Returns the number of nested IRQs currently being handled by the current
+/// CPU.
+pub fn irq_depth() -> u32 { crate::per_cpu!(irq_depth) }
+
+/// Returns whether the current code runs in interrupt context: in an IRQ
+/// handler or in deferred work.
+pub fn in_interrupt() -> bool { irq_depth() != 0 || crate::deferred::in_deferred_work() }
@@ -0,0 +1,547 @@
+//! Architecture independent keyboard handling. Keyboard drivers report key
+//! presses and releases as [KeyCode]s with [report_key]; they are turned into
+//! [KeyEvent]s with the current modifiers and the character the active
+//! [Keymap] produces, and queued for [read_key_event].
+#![allow(static_mut_refs)]
+
+use core::sync::atomic::{AtomicU32, Ordering};
+
+use crate::ringbuf::RingBuffer;
+
+/// The number of key events that can be queued.
+pub const KEY_EVENT_QUEUE_LEN: usize = 128;
+
+/// A physical key. Keys are named after what they produce on a US keyboard,
+/// regardless of the active [Keymap].
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+#[repr(u8)]
+#[allow(missing_docs)]
+pub enum KeyCode {
+    Escape,
+    F1,
+    F2,
+    F3,
+    F4,
+    F5,
+    F6,
+    F7,
+    F8,
+    F9,
+    F10,
+    F11,
+    F12,
+    Grave,
+    Key1,
+    Key2,
+    Key3,
+    Key4,
+    Key5,
+    Key6,
+    Key7,
+    Key8,
+    Key9,
+    Key0,
+    Minus,
+    Equals,
+    Backspace,
+    Tab,
+    Q,
+    W,
+    E,
+    R,
+    T,
+    Y,
+    U,
+    I,
+    O,
+    P,
+    LeftBracket,
+    RightBracket,
+    Backslash,
+    CapsLock,
+    A,
+    S,
+    D,
+    F,
+    G,
+    H,
+    J,
+    K,
+    L,
+    Semicolon,
+    Apostrophe,
+    Enter,
+    LeftShift,
+    /// The key between left shift and Z on ISO keyboards.
+    NonUsBackslash,
+    Z,
+    X,
+    C,
+    V,
+    B,
+    N,
+    M,
+    Comma,
+    Period,
+    Slash,
+    RightShift,
+    LeftCtrl,
+    LeftSuper,
+    LeftAlt,
+    Space,
+    RightAlt,
+    RightSuper,
+    Menu,
+    RightCtrl,
+    PrintScreen,
+    ScrollLock,
+    Pause,
+    Insert,
+    Home,
+    PageUp,
+    Delete,
+    End,
+    PageDown,
+    Up,
+    Left,
+    Down,
+    Right,
+    NumLock,
+    KeypadDivide,
+    KeypadMultiply,
+    KeypadMinus,
+    KeypadPlus,
+    KeypadEnter,
+    Keypad1,
+    Keypad2,
+    Keypad3,
+    Keypad4,
+    Keypad5,
+    Keypad6,
+    Keypad7,
+    Keypad8,
+    Keypad9,
+    Keypad0,
+    KeypadPeriod,
+}
+
+/// The number of [KeyCode]s.
+const KEY_CODES: usize = KeyCode::KeypadPeriod as usize + 1;
+
+/// The held modifier keys and the active locks.
+#[derive(Clone, Copy, PartialEq, Eq, Debug, Default)]
+pub struct Modifiers(pub u8);
+
+impl Modifiers {
+    /// Either shift key is held.
+    pub const SHIFT: u8 = 1 << 0;
+    /// Either control key is held.
+    pub const CTRL: u8 = 1 << 1;
+    /// The left alt key is held.
+    pub const ALT: u8 = 1 << 2;
+    /// The right alt key is held, which selects the third level of many
+    /// layouts.
+    pub const ALT_GR: u8 = 1 << 3;
+    /// Either super key is held.
+    pub const SUPER: u8 = 1 << 4;
+    /// Caps lock is on.
+    pub const CAPS_LOCK: u8 = 1 << 5;
+    /// Num lock is on.
+    pub const NUM_LOCK: u8 = 1 << 6;
+    /// Scroll lock is on.
+    pub const SCROLL_LOCK: u8 = 1 << 7;
+
+    /// Returns whether every bit of `flags` is set.
+    pub const fn contains(self, flags: u8) -> bool { self.0 & flags == flags }
+
+    /// Returns whether shift is held.
+    pub const fn shift(self) -> bool { self.contains(Self::SHIFT) }
+
+    /// Returns whether letters are upper case: shift xor caps lock.
+    pub const fn upper_case(self) -> bool { self.shift() != self.contains(Self::CAPS_LOCK) }
+}
+
+/// A key press or release.
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub struct KeyEvent {
+    /// The key.
+    pub code: KeyCode,
+    /// Whether the key was pressed or released.
+    pub pressed: bool,
+    /// Whether this is a press repeated by holding the key down.
+    pub repeat: bool,
+    /// The modifiers after the event.
+    pub modifiers: Modifiers,
+    /// The character the key produces, for presses of keys that produce one.
+    pub character: Option<char>,
+}
+
+/// Maps keys to the characters they produce in a layout.
+#[derive(Clone, Copy)]
+pub struct Keymap {
+    /// The name of the layout, e.g. "us".
+    pub name: &'static str,
+    /// Returns the character a key produces with the given modifiers. Only
+    /// called for keys whose character depends on the layout; the keypad,
+    /// enter, tab, backspace, escape and space are handled by [translate].
+    pub map: fn(KeyCode, Modifiers) -> Option<char>,
+}
+
+/// Returns the lower or upper case letter, as [Modifiers::upper_case] asks.
+pub fn letter(lower: char, modifiers: Modifiers) -> char {
+    if modifiers.upper_case() {
+        lower.to_ascii_uppercase()
+    } else {
+        lower
+    }
+}
+
+/// Picks between the unshifted and the shifted character.
+const fn shifted(modifiers: Modifiers, normal: char, shift: char) -> Option<char> {
+    if modifiers.shift() {
+        Some(shift)
+    } else {
+        Some(normal)
+    }
+}
+
+/// Returns the letter a key is named after.
+fn key_letter(code: KeyCode) -> Option<char> {
+    use KeyCode::*;
+    Some(match code {
+        A => 'a',
+        B => 'b',
+        C => 'c',
+        D => 'd',
+        E => 'e',
+        F => 'f',
+        G => 'g',
+        H => 'h',
+        I => 'i',
+        J => 'j',
+        K => 'k',
+        L => 'l',
+        M => 'm',
+        N => 'n',
+        O => 'o',
+        P => 'p',
+        Q => 'q',
+        R => 'r',
+        S => 's',
+        T => 't',
+        U => 'u',
+        V => 'v',
+        W => 'w',
+        X => 'x',
+        Y => 'y',
+        Z => 'z',
+        _ => return None,
+    })
+}
+
+/// Returns the digit on a key of the number row.
+fn key_digit(code: KeyCode) -> Option<char> {
+    use KeyCode::*;
+    Some(match code {
+        Key1 => '1',
+        Key2 => '2',
+        Key3 => '3',
+        Key4 => '4',
+        Key5 => '5',
+        Key6 => '6',
+        Key7 => '7',
+        Key8 => '8',
+        Key9 => '9',
+        Key0 => '0',
+        _ => return None,
+    })
+}
+
+/// The US layout.
+pub static KEYMAP_US: Keymap = Keymap {
+    name: "us",
+    map: |code, modifiers| {
+        use KeyCode::*;
+        if modifiers.contains(Modifiers::ALT_GR) {
+            return None;
+        }
+        if let Some(lower) = key_letter(code) {
+            return Some(letter(lower, modifiers));
+        }
+        if let Some(digit) = key_digit(code) &&
+            !modifiers.shift()
+        {
+            return Some(digit);
+        }
+        match code {
+            Key1 => Some('!'),
+            Key2 => Some('@'),
+            Key3 => Some('#'),
+            Key4 => Some('$'),
+            Key5 => Some('%'),
+            Key6 => Some('^'),
+            Key7 => Some('&'),
+            Key8 => Some('*'),
+            Key9 => Some('('),
+            Key0 => Some(')'),
+            Grave => shifted(modifiers, '`', '~'),
+            Minus => shifted(modifiers, '-', '_'),
+            Equals => shifted(modifiers, '=', '+'),
+            LeftBracket => shifted(modifiers, '[', '{'),
+            RightBracket => shifted(modifiers, ']', '}'),
+            Backslash | NonUsBackslash => shifted(modifiers, '\\', '|'),
+            Semicolon => shifted(modifiers, ';', ':'),
+            Apostrophe => shifted(modifiers, '\'', '"'),
+            Comma => shifted(modifiers, ',', '<'),
+            Period => shifted(modifiers, '.', '>'),
+            Slash => shifted(modifiers, '/', '?'),
+            _ => None,
+        }
+    },
+};
+
+/// The German layout. Dead keys produce their accent directly.
+pub static KEYMAP_DE: Keymap = Keymap {
+    name: "de",
+    map: |code, modifiers| {
+        use KeyCode::*;
+        if modifiers.contains(Modifiers::ALT_GR) {
+            return match code {
+                Key2 => Some('²'),
+                Key3 => Some('³'),
+                Key7 => Some('{'),
+                Key8 => Some('['),
+                Key9 => Some(']'),
+                Key0 => Some('}'),
+                Minus => Some('\\'),
+                Q => Some('@'),
+                E => Some('€'),
+                M => Some('µ'),
+                RightBracket => Some('~'),
+                NonUsBackslash => Some('|'),
+                _ => None,
+            };
+        }
+        let umlaut = |lower: char, upper: char| {
+            if modifiers.upper_case() {
+                Some(upper)
+            } else {
+                Some(lower)
+            }
+        };
+        match code {
+            // QWERTZ swaps Y and Z.
+            Y => return Some(letter('z', modifiers)),
+            Z => return Some(letter('y', modifiers)),
+            LeftBracket => return umlaut('ü', 'Ü'),
+            Semicolon => return umlaut('ö', 'Ö'),
+            Apostrophe => return umlaut('ä', 'Ä'),
+            _ => {},
+        }
+        if let Some(lower) = key_letter(code) {
+            return Some(letter(lower, modifiers));
+        }
+        if let Some(digit) = key_digit(code) &&
+            !modifiers.shift()
+        {
+            return Some(digit);
+        }
+        match code {
+            Key1 => Some('!'),
+            Key2 => Some('"'),
+            Key3 => Some('§'),
+            Key4 => Some('$'),
+            Key5 => Some('%'),
+            Key6 => Some('&'),
+            Key7 => Some('/'),
+            Key8 => Some('('),
+            Key9 => Some(')'),
+            Key0 => Some('='),
+            Grave => shifted(modifiers, '^', '°'),
+            Minus => shifted(modifiers, 'ß', '?'),
+            Equals => shifted(modifiers, '´', '`'),
+            RightBracket => shifted(modifiers, '+', '*'),
+            Backslash => shifted(modifiers, '#', '\''),
+            NonUsBackslash => shifted(modifiers, '<', '>'),
+            Comma => shifted(modifiers, ',', ';'),
+            Period => shifted(modifiers, '.', ':'),
+            Slash => shifted(modifiers, '-', '_'),
+            _ => None,
+        }
+    },
+};
+
+/// Every built in keymap.
+pub static KEYMAPS: [&Keymap; 2] = [&KEYMAP_US, &KEYMAP_DE];
+
+/// Returns the built in keymap with a name.
+pub fn keymap_from_name(name: &str) -> Option<&'static Keymap> {
+    KEYMAPS.iter().copied().find(|keymap| keymap.name == name)
+}
+
+/// Returns the keymap selected with `CONFIG_KEYMAP`, US by default.
+pub fn configured_keymap() -> &'static Keymap {
+    if cfg!(CONFIG_KEYMAP = "de") {
+        &KEYMAP_DE
+    } else {
+        &KEYMAP_US
+    }
+}
+
+/// The active keymap, or [None] for [configured_keymap].
+static mut KEYMAP: Option<&'static Keymap> = None;
+
+/// Returns the active keymap.
+pub fn keymap() -> &'static Keymap { unsafe { KEYMAP }.unwrap_or_else(configured_keymap) }
+
+/// Makes a keymap the active one.
+pub fn set_keymap(keymap: &'static Keymap) {
+    unsafe {
+        KEYMAP = Some(keymap);
+    }
+}
+
+/// Returns the character a key produces with some modifiers in the active
+/// keymap. Keys held with control or alt produce no character.
+pub fn translate(code: KeyCode, modifiers: Modifiers) -> Option<char> {
+    use KeyCode::*;
+    if modifiers.contains(Modifiers::CTRL) || modifiers.contains(Modifiers::ALT) {
+        return None;
+    }
+    let num_lock = modifiers.contains(Modifiers::NUM_LOCK);
+    match code {
+        Escape => Some('\x1B'),
+        Backspace => Some('\x08'),
+        Tab => Some('\t'),
+        Enter | KeypadEnter => Some('\n'),
+        Space => Some(' '),
+        KeypadDivide => Some('/'),
+        KeypadMultiply => Some('*'),
+        KeypadMinus => Some('-'),
+        KeypadPlus => Some('+'),
+        Keypad1 if num_lock => Some('1'),
+        Keypad2 if num_lock => Some('2'),
+        Keypad3 if num_lock => Some('3'),
+        Keypad4 if num_lock => Some('4'),
+        Keypad5 if num_lock => Some('5'),
+        Keypad6 if num_lock => Some('6'),
+        Keypad7 if num_lock => Some('7'),
+        Keypad8 if num_lock => Some('8'),
+        Keypad9 if num_lock => Some('9'),
+        Keypad0 if num_lock => Some('0'),
+        KeypadPeriod if num_lock => Some('.'),
+        _ => (keymap().map)(code, modifiers),
+    }
+}
+
+/// The keys that are held down, one bit per [KeyCode].
+static mut HELD_KEYS: [u32; KEY_CODES.div_ceil(32)] = [0; KEY_CODES.div_ceil(32)];
+
+/// The active locks. The held modifiers are computed from [HELD_KEYS].
+static mut LOCKS: u8 = 0;
+
+/// The queued key events.
+static KEY_EVENTS: RingBuffer<KeyEvent, KEY_EVENT_QUEUE_LEN> = RingBuffer::new();
+
+/// The number of key events lost because the queue was full.
+static DROPPED_KEY_EVENTS: AtomicU32 = AtomicU32::new(0);
+
+/// Returns whether a key is held down.
+pub fn key_held(code: KeyCode) -> bool {
+    let code = code as usize;
+    unsafe { HELD_KEYS[code / 32] & (1 << (code % 32)) != 0 }
+}
+
+/// Returns the held modifier keys and the active locks.
+pub fn modifiers() -> Modifiers {
+    use KeyCode::*;
+    let mut modifiers = unsafe { LOCKS };
+    for (keys, flag) in [
+        (&[LeftShift, RightShift][..], Modifiers::SHIFT),
+        (&[LeftCtrl, RightCtrl], Modifiers::CTRL),
+        (&[LeftAlt], Modifiers::ALT),
+        (&[RightAlt], Modifiers::ALT_GR),
+        (&[LeftSuper, RightSuper], Modifiers::SUPER),
+    ] {
+        if keys.iter().any(|key| key_held(*key)) {
+            modifiers |= flag;
+        }
+    }
+    Modifiers(modifiers)
+}
+
+/// Called by keyboard drivers for every key press and release, usually from
+/// their IRQ handler. Updates the modifiers and queues a [KeyEvent], both here
+/// and in the [input event queue](crate::input).
+pub fn report_key(code: KeyCode, pressed: bool) {
+    let _irq = crate::arch::interrupts::InterruptsPop();
+    let repeat = pressed && key_held(code);
+    let index = code as usize;
+    unsafe {
+        if pressed {
+            HELD_KEYS[index / 32] |= 1 << (index % 32);
+        } else {
+            HELD_KEYS[index / 32] &= !(1 << (index % 32));
+        }
+    }
+    let lock = match code {
+        KeyCode::CapsLock => Modifiers::CAPS_LOCK,
+        KeyCode::NumLock => Modifiers::NUM_LOCK,
+        KeyCode::ScrollLock => Modifiers::SCROLL_LOCK,
+        _ => 0,
+    };
+    if pressed && !repeat && lock != 0 {
+        unsafe {
+            LOCKS ^= lock;
+        }
+        crate::arch::keyboard::KeyboardSetLeds(modifiers());
+    }
+    let modifiers = modifiers();
+    let event = KeyEvent {
+        code,
+        pressed,
+        repeat,
+        modifiers,
+        character: if pressed {
+            translate(code, modifiers)
+        } else {
+            None
+        },
+    };
+    if !KEY_EVENTS.push(event) {
+        DROPPED_KEY_EVENTS.fetch_add(1, Ordering::Relaxed);
+    }
+    crate::input::report_input_event(crate::input::InputEvent::Key(event));
+}
+
+/// Returns the oldest queued key event without waiting, if there is one.
+pub fn read_key_event() -> Option<KeyEvent> {
+    // The kernel is the only consumer, but may be interrupted by itself.
+    let _irq = crate::arch::interrupts::InterruptsPop();
+    KEY_EVENTS.pop()
+}
+
+/// Returns the character of the oldest key press that produced one without
+/// waiting. Key events without a character are discarded.
+pub fn read_char() -> Option<char> {
+    while let Some(event) = read_key_event() {
+        if event.character.is_some() {
+            return event.character;
+        }
+    }
+    None
+}
+
+/// Waits for a key press that produces a character and returns it. Must be
+/// called with interrupts enabled, outside of interrupt context.
+pub fn read_char_blocking() -> char {
+    loop {
+        if let Some(character) = read_char() {
+            return character;
+        }
+        crate::idle::idle();
+    }
+}
+
+/// Returns the number of key events lost because the queue was full.
+pub fn dropped_key_events() -> u32 { DROPPED_KEY_EVENTS.load(Ordering::Relaxed) }
@@ -24,20 +24,35 @@
 
 extern crate alloc;
 
+pub mod acpi;
 pub mod arch;
 pub mod boot;
 pub mod cmdline;
 mod constants;
+pub mod deferred;
+pub mod device;
 pub mod display;
 mod errors;
+pub mod idle;
 pub mod indep_boot_entry;
+pub mod input;
+pub mod irq;
+pub mod keyboard;
 pub mod mem;
 pub mod memsections;
+pub mod msi;
 pub mod multiboot2;
 pub mod output;
+pub mod pci;
+pub mod percpu;
 pub mod psfont;
+pub mod ringbuf;
+pub mod syscall;
+pub mod time;
+pub mod timer;
 mod traits;
 mod util;
+pub mod watchdog;
 
 #[macro_use]
 pub(crate) mod cfg;
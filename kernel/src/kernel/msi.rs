@@ -0,0 +1,360 @@
+//! Message signaled interrupts. PCI functions with an MSI or MSI-X capability
+//! deliver their interrupts as memory writes to addresses the architecture
+//! provides, each with an IRQ of its own instead of a shared legacy line.
+//! The IRQs are used like any other: [crate::irq::register_irq_handler]
+//! unmasks them and [crate::irq::unregister_irq_handler] masks them, per
+//! vector in the capability.
+#![allow(static_mut_refs)]
+
+use crate::irq::IRQ_COUNT;
+use crate::pci::{
+    Bar, CAP_MSI, CAP_MSIX, COMMAND_BUS_MASTER, COMMAND_INTX_DISABLE, PciAddress, PciDevice,
+};
+
+/// Returned if the function has no MSI or MSI-X capability.
+pub const ERR_NO_CAPABILITY: i16 = -1;
+/// Returned if not enough IRQs are free.
+pub const ERR_NO_FREE_IRQS: i16 = -2;
+/// Returned if the architecture can't deliver message signaled interrupts.
+pub const ERR_UNSUPPORTED: i16 = -3;
+/// Returned if the MSI-X table isn't in a usable memory BAR.
+pub const ERR_INVALID_TABLE: i16 = -4;
+/// Returned if more vectors are asked for than the function has.
+pub const ERR_TOO_MANY_VECTORS: i16 = -5;
+
+/// The most vectors that can be enabled at once for a function.
+pub const MAX_VECTORS: u8 = 32;
+
+/// The message control register of either capability.
+const REG_MESSAGE_CONTROL: u16 = 0x02;
+/// The low half of the message address of MSI.
+const REG_MSI_ADDRESS: u16 = 0x04;
+/// The table offset and BAR of MSI-X.
+const REG_MSIX_TABLE: u16 = 0x04;
+
+/// Enables MSI.
+const MSI_ENABLE: u16 = 1 << 0;
+/// The shift of the log2 of the number of vectors the function supports.
+const MSI_MULTIPLE_CAPABLE_SHIFT: u16 = 1;
+/// The shift of the log2 of the number of vectors enabled.
+const MSI_MULTIPLE_ENABLE_SHIFT: u16 = 4;
+/// Set if the message address is 64 bits wide.
+const MSI_64BIT: u16 = 1 << 7;
+/// Set if the vectors can be masked individually.
+const MSI_PER_VECTOR_MASK: u16 = 1 << 8;
+/// The table size minus one.
+const MSIX_TABLE_SIZE: u16 = 0x7FF;
+/// Masks every vector of MSI-X.
+const MSIX_FUNCTION_MASK: u16 = 1 << 14;
+/// Enables MSI-X.
+const MSIX_ENABLE: u16 = 1 << 15;
+/// The BAR indicator of the MSI-X table.
+const MSIX_TABLE_BIR: u32 = 0b111;
+/// The size of an MSI-X table entry.
+const MSIX_ENTRY_SIZE: usize = 16;
+/// The offset of the vector control in an MSI-X table entry.
+const MSIX_ENTRY_CONTROL: usize = 12;
+/// Masks an MSI-X vector.
+const MSIX_VECTOR_MASKED: u32 = 1 << 0;
+
+/// The kind of message signaled interrupts.
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum MsiKind {
+    /// MSI: up to 32 vectors with consecutive data, configured in
+    /// configuration space.
+    Msi,
+    /// MSI-X: up to 2048 independent vectors, configured in a table in a BAR.
+    MsiX,
+}
+
+/// The IRQs enabled for a function by [enable_msi] or [enable_msix].
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub struct MsiIrqs {
+    /// The function.
+    pub address: PciAddress,
+    /// MSI or MSI-X.
+    pub kind: MsiKind,
+    /// The IRQ of the first vector.
+    pub first_irq: u8,
+    /// The number of vectors. Vector `n` has IRQ `first_irq + n`.
+    pub count: u8,
+}
+
+impl MsiIrqs {
+    /// Returns the IRQ of a vector.
+    pub fn irq(&self, vector: u8) -> Option<u8> {
+        (vector < self.count).then_some(self.first_irq + vector)
+    }
+}
+
+/// Where an IRQ is masked.
+#[derive(Clone, Copy)]
+enum VectorMask {
+    /// A bit in the MSI mask bits register.
+    Msi {
+        /// The function.
+        address: PciAddress,
+        /// The offset of the mask bits register.
+        register: u16,
+        /// The bit of the vector.
+        bit: u8,
+    },
+    /// The vector control of an MSI-X table entry.
+    MsiX {
+        /// The address of the table entry.
+        entry: usize,
+    },
+    /// The vector can't be masked individually.
+    Unmaskable,
+}
+
+/// How every IRQ used for message signaled interrupts is masked.
+static mut VECTORS: [Option<VectorMask>; IRQ_COUNT] = [None; IRQ_COUNT];
+
+/// Returns whether the architecture can deliver message signaled interrupts.
+pub fn msi_supported() -> bool { crate::arch::msi::MsiSupported() }
+
+/// Masks or unmasks an IRQ if it's used for message signaled interrupts.
+/// Returns whether it is. Called by [crate::irq].
+pub fn set_msi_masked(irq: u8, masked: bool) -> bool {
+    let Some(mask) = (unsafe { VECTORS.get(irq as usize).copied().flatten() }) else {
+        return false;
+    };
+    match mask {
+        VectorMask::Msi {
+            address,
+            register,
+            bit,
+        } => {
+            let bits = address.read_u32(register);
+            let bits = if masked {
+                bits | 1 << bit
+            } else {
+                bits & !(1 << bit)
+            };
+            address.write_u32(register, bits);
+        },
+        VectorMask::MsiX { entry } => {
+            let control = (entry + MSIX_ENTRY_CONTROL) as *mut u32;
+            unsafe {
+                let value = core::ptr::read_volatile(control);
+                core::ptr::write_volatile(
+                    control,
+                    if masked {
+                        value | MSIX_VECTOR_MASKED
+                    } else {
+                        value & !MSIX_VECTOR_MASKED
+                    },
+                );
+            }
+        },
+        VectorMask::Unmaskable => {},
+    }
+    true
+}
+
+/// Allocates `count` consecutive IRQs whose vectors are aligned to `align`.
+fn allocate_irqs(count: u8, align: u8) -> Result<u8, crate::Error<'static>> {
+    if !msi_supported() {
+        return Err(crate::Error::new(
+            "message signaled interrupts aren't supported",
+            ERR_UNSUPPORTED,
+        ));
+    }
+    crate::arch::msi::MsiAllocateIrqs(count, align).ok_or(crate::Error::new(
+        "not enough free IRQs for message signaled interrupts",
+        ERR_NO_FREE_IRQS,
+    ))
+}
+
+/// Stops using IRQs for message signaled interrupts and frees them.
+fn free_irqs(first_irq: u8, count: u8) {
+    for irq in first_irq..first_irq + count {
+        crate::irq::unregister_irq_handler(irq);
+        unsafe {
+            VECTORS[irq as usize] = None;
+        }
+    }
+    crate::arch::msi::MsiFreeIrqs(first_irq, count);
+}
+
+/// Lets the function master the bus, which it needs to send messages, and
+/// disables its legacy interrupt.
+fn prepare_function(address: PciAddress) {
+    address.enable(COMMAND_BUS_MASTER | COMMAND_INTX_DISABLE);
+}
+
+/// Enables MSI for a function with `count` vectors, rounded up to a power of
+/// two. Every vector starts masked until a handler is registered for its IRQ.
+pub fn enable_msi(address: PciAddress, count: u8) -> Result<MsiIrqs, crate::Error<'static>> {
+    let Some(capability) = address.find_capability(CAP_MSI) else {
+        return Err(crate::Error::new(
+            "function has no MSI capability",
+            ERR_NO_CAPABILITY,
+        ));
+    };
+    let capability = capability as u16;
+    let control = address.read_u16(capability + REG_MESSAGE_CONTROL);
+    let supported = 1u8 << ((control >> MSI_MULTIPLE_CAPABLE_SHIFT) & 0b111).min(5);
+    let count = count.max(1).next_power_of_two();
+    if count > supported {
+        return Err(crate::Error::new(
+            "function has fewer MSI vectors",
+            ERR_TOO_MANY_VECTORS,
+        ));
+    }
+    // The function puts the vector number in the low bits of the data, so
+    // the vectors have to be aligned to their count.
+    let first_irq = allocate_irqs(count, count)?;
+    let Some((message_address, message_data)) = crate::arch::msi::MsiMessage(first_irq) else {
+        crate::arch::msi::MsiFreeIrqs(first_irq, count);
+        return Err(crate::Error::new(
+            "message signaled interrupts aren't supported",
+            ERR_UNSUPPORTED,
+        ));
+    };
+
+    let wide = control & MSI_64BIT != 0;
+    let data_register = capability + if wide { 0x0C } else { 0x08 };
+    let mask_register = data_register + 4;
+    let _irq = crate::arch::interrupts::InterruptsPop();
+    address.write_u16(capability + REG_MESSAGE_CONTROL, control & !MSI_ENABLE);
+    address.write_u32(capability + REG_MSI_ADDRESS, message_address as u32);
+    if wide {
+        address.write_u32(
+            capability + REG_MSI_ADDRESS + 4,
+            (message_address >> 32) as u32,
+        );
+    }
+    address.write_u16(data_register, message_data as u16);
+    for vector in 0..count {
+        let mask = if control & MSI_PER_VECTOR_MASK != 0 {
+            VectorMask::Msi {
+                address,
+                register: mask_register,
+                bit: vector,
+            }
+        } else {
+            VectorMask::Unmaskable
+        };
+        unsafe {
+            VECTORS[(first_irq + vector) as usize] = Some(mask);
+        }
+        set_msi_masked(first_irq + vector, true);
+    }
+    prepare_function(address);
+    let control = (control & !(0b111 << MSI_MULTIPLE_ENABLE_SHIFT)) |
+        (count.trailing_zeros() as u16) << MSI_MULTIPLE_ENABLE_SHIFT |
+        MSI_ENABLE;
+    address.write_u16(capability + REG_MESSAGE_CONTROL, control);
+    Ok(MsiIrqs {
+        address,
+        kind: MsiKind::Msi,
+        first_irq,
+        count,
+    })
+}
+
+/// Returns the address of the MSI-X table of a function.
+fn msix_table(device: &PciDevice, capability: u16) -> Option<usize> {
+    let table = device.address.read_u32(capability + REG_MSIX_TABLE);
+    let Some(Bar::Memory { address, .. }) = device.bar((table & MSIX_TABLE_BIR) as usize) else {
+        return None;
+    };
+    let table = address + (table & !MSIX_TABLE_BIR) as u64;
+    (table != 0 && table <= usize::MAX as u64).then_some(table as usize)
+}
+
+/// Enables MSI-X for a function with its first `count` vectors. Every vector
+/// starts masked until a handler is registered for its IRQ.
+pub fn enable_msix(device: &PciDevice, count: u8) -> Result<MsiIrqs, crate::Error<'static>> {
+    let address = device.address;
+    let Some(capability) = address.find_capability(CAP_MSIX) else {
+        return Err(crate::Error::new(
+            "function has no MSI-X capability",
+            ERR_NO_CAPABILITY,
+        ));
+    };
+    let capability = capability as u16;
+    let control = address.read_u16(capability + REG_MESSAGE_CONTROL);
+    let count = count.max(1);
+    if count > MAX_VECTORS || count as u16 > (control & MSIX_TABLE_SIZE) + 1 {
+        return Err(crate::Error::new(
+            "function has fewer MSI-X vectors",
+            ERR_TOO_MANY_VECTORS,
+        ));
+    }
+    let Some(table) = msix_table(device, capability) else {
+        return Err(crate::Error::new(
+            "MSI-X table isn't in a memory BAR",
+            ERR_INVALID_TABLE,
+        ));
+    };
+    let first_irq = allocate_irqs(count, 1)?;
+
+    let _irq = crate::arch::interrupts::InterruptsPop();
+    // The table is only accessible with memory decoding on, and no vector may
+    // fire while the entries are written.
+    address.enable(crate::pci::COMMAND_MEMORY_SPACE);
+    address.write_u16(
+        capability + REG_MESSAGE_CONTROL,
+        control | MSIX_ENABLE | MSIX_FUNCTION_MASK,
+    );
+    for vector in 0..count {
+        let irq = first_irq + vector;
+        let Some((message_address, message_data)) = crate::arch::msi::MsiMessage(irq) else {
+            address.write_u16(capability + REG_MESSAGE_CONTROL, control & !MSIX_ENABLE);
+            free_irqs(first_irq, count);
+            return Err(crate::Error::new(
+                "message signaled interrupts aren't supported",
+                ERR_UNSUPPORTED,
+            ));
+        };
+        let entry = table + vector as usize * MSIX_ENTRY_SIZE;
+        unsafe {
+            core::ptr::write_volatile(entry as *mut u32, message_address as u32);
+            core::ptr::write_volatile((entry + 4) as *mut u32, (message_address >> 32) as u32);
+            core::ptr::write_volatile((entry + 8) as *mut u32, message_data);
+            VECTORS[irq as usize] = Some(VectorMask::MsiX { entry });
+        }
+        set_msi_masked(irq, true);
+    }
+    prepare_function(address);
+    address.write_u16(
+        capability + REG_MESSAGE_CONTROL,
+        (control | MSIX_ENABLE) & !MSIX_FUNCTION_MASK,
+    );
+    Ok(MsiIrqs {
+        address,
+        kind: MsiKind::MsiX,
+        first_irq,
+        count,
+    })
+}
+
+/// Enables MSI-X for a function if it has it, and MSI otherwise.
+pub fn enable_message_interrupts(
+    device: &PciDevice,
+    count: u8,
+) -> Result<MsiIrqs, crate::Error<'static>> {
+    if device.address.find_capability(CAP_MSIX).is_some() {
+        enable_msix(device, count)
+    } else {
+        enable_msi(device.address, count)
+    }
+}
+
+/// Disables message signaled interrupts for a function, unregisters the
+/// handlers of their IRQs and frees them.
+pub fn disable_message_interrupts(irqs: MsiIrqs) {
+    let (id, enable) = match irqs.kind {
+        MsiKind::Msi => (CAP_MSI, MSI_ENABLE),
+        MsiKind::MsiX => (CAP_MSIX, MSIX_ENABLE),
+    };
+    if let Some(capability) = irqs.address.find_capability(id) {
+        let register = capability as u16 + REG_MESSAGE_CONTROL;
+        irqs.address
+            .write_u16(register, irqs.address.read_u16(register) & !enable);
+    }
+    free_irqs(irqs.first_irq, irqs.count);
+}
@@ -0,0 +1,715 @@
+//! PCI devices. The buses are scanned recursively through the configuration
+//! space access of the architecture, and every function found is kept in a
+//! registry that drivers can search with a [PciMatch]. Where the architecture
+//! provides PCI Express extended configuration space, its capabilities can be
+//! walked too.
+#![allow(static_mut_refs)]
+
+use crate::arch::output::*;
+
+/// The number of functions the registry can hold.
+pub const MAX_PCI_DEVICES: usize = 128;
+
+/// The vendor ID read from functions that don't exist.
+pub const INVALID_VENDOR_ID: u16 = 0xFFFF;
+
+/// The vendor and device IDs.
+pub const REG_ID: u16 = 0x00;
+/// The command and status registers.
+pub const REG_COMMAND: u16 = 0x04;
+/// The revision, programming interface, subclass and class.
+pub const REG_CLASS: u16 = 0x08;
+/// The cache line size, latency timer, header type and BIST.
+pub const REG_HEADER_TYPE: u16 = 0x0C;
+/// The first base address register.
+pub const REG_BAR0: u16 = 0x10;
+/// The primary, secondary and subordinate bus numbers of a bridge.
+pub const REG_BRIDGE_BUSES: u16 = 0x18;
+/// The subsystem vendor and subsystem IDs of a normal function.
+pub const REG_SUBSYSTEM: u16 = 0x2C;
+/// The offset of the first capability.
+pub const REG_CAPABILITIES: u16 = 0x34;
+/// The interrupt line and pin.
+pub const REG_INTERRUPT: u16 = 0x3C;
+
+/// Enables responses to I/O space accesses.
+pub const COMMAND_IO_SPACE: u16 = 1 << 0;
+/// Enables responses to memory space accesses.
+pub const COMMAND_MEMORY_SPACE: u16 = 1 << 1;
+/// Allows the function to master the bus, e.g. for DMA and MSIs.
+pub const COMMAND_BUS_MASTER: u16 = 1 << 2;
+/// Disables the legacy INTx interrupt.
+pub const COMMAND_INTX_DISABLE: u16 = 1 << 10;
+/// Set in the status if the function has a capability list.
+pub const STATUS_CAPABILITIES: u16 = 1 << 4;
+
+/// The power management capability.
+pub const CAP_POWER_MANAGEMENT: u8 = 0x01;
+/// The MSI capability.
+pub const CAP_MSI: u8 = 0x05;
+/// The vendor specific capability.
+pub const CAP_VENDOR: u8 = 0x09;
+/// The PCI Express capability.
+pub const CAP_PCI_EXPRESS: u8 = 0x10;
+/// The MSI-X capability.
+pub const CAP_MSIX: u8 = 0x11;
+
+/// The advanced error reporting extended capability.
+pub const EXT_CAP_AER: u16 = 0x0001;
+/// The device serial number extended capability.
+pub const EXT_CAP_DEVICE_SERIAL_NUMBER: u16 = 0x0003;
+/// The single root I/O virtualization extended capability.
+pub const EXT_CAP_SRIOV: u16 = 0x0010;
+
+/// The offset of the first extended capability.
+pub const EXTENDED_CONFIG_START: u16 = 0x100;
+/// The size of the configuration space of a PCI Express function.
+pub const EXTENDED_CONFIG_SIZE: u16 = 0x1000;
+
+/// The class of bridges.
+pub const CLASS_BRIDGE: u8 = 0x06;
+/// The subclass of PCI-to-PCI bridges.
+pub const SUBCLASS_PCI_BRIDGE: u8 = 0x04;
+
+/// The header type of normal functions.
+const HEADER_NORMAL: u8 = 0x00;
+/// The header type of PCI-to-PCI bridges.
+const HEADER_BRIDGE: u8 = 0x01;
+/// Set in the header type if the device has several functions.
+const HEADER_MULTIFUNCTION: u8 = 1 << 7;
+
+/// Set in a BAR that decodes I/O space.
+const BAR_IO: u32 = 1 << 0;
+/// The type bits of a memory BAR.
+const BAR_MEMORY_TYPE: u32 = 0b11 << 1;
+/// The type of a 64-bit memory BAR.
+const BAR_MEMORY_64BIT: u32 = 0b10 << 1;
+/// Set in a memory BAR that is prefetchable.
+const BAR_PREFETCHABLE: u32 = 1 << 3;
+
+/// The most capabilities followed, to stop at malformed lists.
+const MAX_CAPABILITIES: usize = 48;
+/// The most extended capabilities followed, to stop at malformed lists.
+const MAX_EXTENDED_CAPABILITIES: usize = 960;
+
+/// The location of a function in configuration space.
+#[derive(Clone, Copy, PartialEq, Eq, Debug, Default)]
+pub struct PciAddress {
+    /// The bus.
+    pub bus: u8,
+    /// The device on the bus, below 32.
+    pub device: u8,
+    /// The function of the device, below 8.
+    pub function: u8,
+}
+
+impl PciAddress {
+    /// Creates an address.
+    pub const fn new(bus: u8, device: u8, function: u8) -> Self {
+        PciAddress {
+            bus,
+            device,
+            function,
+        }
+    }
+
+    /// Reads a dword of configuration space. The offset is rounded down to a
+    /// multiple of 4.
+    pub fn read_u32(self, offset: u16) -> u32 { crate::arch::pci::PciConfigRead(self, offset & !3) }
+
+    /// Reads a word of configuration space. The offset is rounded down to a
+    /// multiple of 2.
+    pub fn read_u16(self, offset: u16) -> u16 {
+        (self.read_u32(offset) >> ((offset & 2) * 8)) as u16
+    }
+
+    /// Reads a byte of configuration space.
+    pub fn read_u8(self, offset: u16) -> u8 { (self.read_u32(offset) >> ((offset & 3) * 8)) as u8 }
+
+    /// Writes a dword of configuration space. The offset is rounded down to a
+    /// multiple of 4.
+    pub fn write_u32(self, offset: u16, value: u32) {
+        crate::arch::pci::PciConfigWrite(self, offset & !3, value)
+    }
+
+    /// Writes a word of configuration space. The offset is rounded down to a
+    /// multiple of 2.
+    pub fn write_u16(self, offset: u16, value: u16) {
+        let shift = (offset & 2) * 8;
+        let dword = self.read_u32(offset) & !(0xFFFF << shift);
+        self.write_u32(offset, dword | (value as u32) << shift);
+    }
+
+    /// Writes a byte of configuration space.
+    pub fn write_u8(self, offset: u16, value: u8) {
+        let shift = (offset & 3) * 8;
+        let dword = self.read_u32(offset) & !(0xFF << shift);
+        self.write_u32(offset, dword | (value as u32) << shift);
+    }
+
+    /// Returns the vendor ID, or [INVALID_VENDOR_ID] if there is no function
+    /// at the address.
+    pub fn vendor_id(self) -> u16 { self.read_u16(REG_ID) }
+
+    /// Returns the header type without the multifunction bit.
+    pub fn header_type(self) -> u8 { self.read_u8(REG_HEADER_TYPE + 2) & !HEADER_MULTIFUNCTION }
+
+    /// Returns the command register.
+    pub fn command(self) -> u16 { self.read_u16(REG_COMMAND) }
+
+    /// Writes the command register. The status register next to it is left
+    /// alone, as writing ones to it clears its error bits.
+    pub fn set_command(self, command: u16) { self.write_u32(REG_COMMAND, command as u32) }
+
+    /// Returns the status register.
+    pub fn status(self) -> u16 { self.read_u16(REG_COMMAND + 2) }
+
+    /// Sets bits of the command register.
+    pub fn enable(self, bits: u16) { self.set_command(self.command() | bits) }
+
+    /// Clears bits of the command register.
+    pub fn disable(self, bits: u16) { self.set_command(self.command() & !bits) }
+
+    /// Returns the capabilities of the function.
+    pub fn capabilities(self) -> PciCapabilities {
+        let offset = if self.status() & STATUS_CAPABILITIES != 0 {
+            self.read_u8(REG_CAPABILITIES) & !3
+        } else {
+            0
+        };
+        PciCapabilities {
+            address: self,
+            offset,
+            remaining: MAX_CAPABILITIES,
+        }
+    }
+
+    /// Returns the offset of the first capability with an ID.
+    pub fn find_capability(self, id: u8) -> Option<u8> {
+        self.capabilities()
+            .find(|capability| capability.id == id)
+            .map(|capability| capability.offset)
+    }
+
+    /// Returns the extended capabilities of the function. Empty if extended
+    /// configuration space isn't available, or the function isn't PCI Express.
+    pub fn extended_capabilities(self) -> PciExtendedCapabilities {
+        let offset = if extended_config_available() {
+            EXTENDED_CONFIG_START
+        } else {
+            0
+        };
+        PciExtendedCapabilities {
+            address: self,
+            offset,
+            remaining: MAX_EXTENDED_CAPABILITIES,
+        }
+    }
+
+    /// Returns the offset of the first extended capability with an ID.
+    pub fn find_extended_capability(self, id: u16) -> Option<u16> {
+        self.extended_capabilities()
+            .find(|capability| capability.id == id)
+            .map(|capability| capability.offset)
+    }
+}
+
+/// A capability in the capability list of a function.
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub struct PciCapability {
+    /// The ID of the capability, e.g. [CAP_MSI].
+    pub id: u8,
+    /// The offset of the capability in configuration space.
+    pub offset: u8,
+}
+
+/// Iterates over the capability list of a function.
+pub struct PciCapabilities {
+    /// The function.
+    address: PciAddress,
+    /// The offset of the next capability, or 0 at the end of the list.
+    offset: u8,
+    /// How many more capabilities are followed.
+    remaining: usize,
+}
+
+impl Iterator for PciCapabilities {
+    type Item = PciCapability;
+
+    fn next(&mut self) -> Option<PciCapability> {
+        // The first 64 bytes are the header, so no capability can be there.
+        if self.offset < 0x40 || self.remaining == 0 {
+            return None;
+        }
+        self.remaining -= 1;
+        let header = self.address.read_u16(self.offset as u16);
+        let capability = PciCapability {
+            id: header as u8,
+            offset: self.offset,
+        };
+        self.offset = (header >> 8) as u8 & !3;
+        Some(capability)
+    }
+}
+
+/// A capability in the extended capability list of a PCI Express function.
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub struct PciExtendedCapability {
+    /// The ID of the capability, e.g. [EXT_CAP_AER].
+    pub id: u16,
+    /// The version of the capability.
+    pub version: u8,
+    /// The offset of the capability in configuration space.
+    pub offset: u16,
+}
+
+/// Iterates over the extended capability list of a function.
+pub struct PciExtendedCapabilities {
+    /// The function.
+    address: PciAddress,
+    /// The offset of the next capability, or 0 at the end of the list.
+    offset: u16,
+    /// How many more capabilities are followed.
+    remaining: usize,
+}
+
+impl Iterator for PciExtendedCapabilities {
+    type Item = PciExtendedCapability;
+
+    fn next(&mut self) -> Option<PciExtendedCapability> {
+        if self.offset < EXTENDED_CONFIG_START ||
+            self.offset >= EXTENDED_CONFIG_SIZE ||
+            self.remaining == 0
+        {
+            return None;
+        }
+        self.remaining -= 1;
+        let header = self.address.read_u32(self.offset);
+        // Functions without extended capabilities have a header of 0, and
+        // conventional PCI functions read as all ones.
+        if header == 0 || header == 0xFFFF_FFFF {
+            return None;
+        }
+        let capability = PciExtendedCapability {
+            id: header as u16,
+            version: (header >> 16) as u8 & 0xF,
+            offset: self.offset,
+        };
+        self.offset = (header >> 20) as u16 & !3;
+        Some(capability)
+    }
+}
+
+/// A base address register, with the size of the region it decodes.
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum Bar {
+    /// A region of I/O space.
+    Io {
+        /// The first port.
+        port: u32,
+        /// The number of ports.
+        size: u32,
+    },
+    /// A region of memory space.
+    Memory {
+        /// The physical address.
+        address: u64,
+        /// The size in bytes.
+        size: u64,
+        /// Whether reads have no side effects, so they may be cached.
+        prefetchable: bool,
+        /// Whether the BAR takes two slots, for a 64-bit address.
+        wide: bool,
+    },
+}
+
+/// A function found on a bus.
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub struct PciDevice {
+    /// Where the function is.
+    pub address: PciAddress,
+    /// The vendor ID.
+    pub vendor_id: u16,
+    /// The device ID.
+    pub device_id: u16,
+    /// The base class.
+    pub class: u8,
+    /// The subclass.
+    pub subclass: u8,
+    /// The programming interface.
+    pub prog_if: u8,
+    /// The revision.
+    pub revision: u8,
+    /// The header type, without the multifunction bit.
+    pub header_type: u8,
+    /// The subsystem vendor ID, or 0 for bridges.
+    pub subsystem_vendor_id: u16,
+    /// The subsystem ID, or 0 for bridges.
+    pub subsystem_id: u16,
+    /// The legacy interrupt line set up by the firmware.
+    pub interrupt_line: u8,
+    /// The legacy interrupt pin, from 1 for INTA to 4 for INTD, or 0 if the
+    /// function doesn't use one.
+    pub interrupt_pin: u8,
+    /// The base address registers. The second slot of a 64-bit BAR is [None].
+    pub bars: [Option<Bar>; 6],
+}
+
+impl PciDevice {
+    /// An empty registry slot.
+    const EMPTY: PciDevice = PciDevice {
+        address: PciAddress::new(0, 0, 0),
+        vendor_id: INVALID_VENDOR_ID,
+        device_id: 0,
+        class: 0,
+        subclass: 0,
+        prog_if: 0,
+        revision: 0,
+        header_type: 0,
+        subsystem_vendor_id: 0,
+        subsystem_id: 0,
+        interrupt_line: 0,
+        interrupt_pin: 0,
+        bars: [None; 6],
+    };
+
+    /// Reads the configuration header of a function.
+    pub fn probe(address: PciAddress) -> Option<Self> {
+        let id = address.read_u32(REG_ID);
+        if id as u16 == INVALID_VENDOR_ID {
+            return None;
+        }
+        let class = address.read_u32(REG_CLASS);
+        let header_type = address.header_type();
+        let (subsystem_vendor_id, subsystem_id) = if header_type == HEADER_NORMAL {
+            let subsystem = address.read_u32(REG_SUBSYSTEM);
+            (subsystem as u16, (subsystem >> 16) as u16)
+        } else {
+            (0, 0)
+        };
+        let interrupt = address.read_u16(REG_INTERRUPT);
+        Some(PciDevice {
+            address,
+            vendor_id: id as u16,
+            device_id: (id >> 16) as u16,
+            class: (class >> 24) as u8,
+            subclass: (class >> 16) as u8,
+            prog_if: (class >> 8) as u8,
+            revision: class as u8,
+            header_type,
+            subsystem_vendor_id,
+            subsystem_id,
+            interrupt_line: interrupt as u8,
+            interrupt_pin: (interrupt >> 8) as u8,
+            bars: read_bars(address, header_type),
+        })
+    }
+
+    /// Returns whether the function is a PCI-to-PCI bridge.
+    pub fn is_bridge(&self) -> bool {
+        self.header_type == HEADER_BRIDGE &&
+            self.class == CLASS_BRIDGE &&
+            self.subclass == SUBCLASS_PCI_BRIDGE
+    }
+
+    /// Returns a BAR, if it's implemented.
+    pub fn bar(&self, index: usize) -> Option<Bar> { self.bars.get(index).copied().flatten() }
+}
+
+/// Returns the number of BARs of a header type.
+const fn bar_count(header_type: u8) -> usize {
+    match header_type {
+        HEADER_NORMAL => 6,
+        HEADER_BRIDGE => 2,
+        _ => 0,
+    }
+}
+
+/// Reads the BARs of a function and sizes them by writing all ones and
+/// reading back which bits stick. Decoding is disabled meanwhile, so the
+/// function doesn't respond at the temporary addresses.
+fn read_bars(address: PciAddress, header_type: u8) -> [Option<Bar>; 6] {
+    let mut bars = [None; 6];
+    let count = bar_count(header_type);
+    let command = address.command();
+    address.set_command(command & !(COMMAND_IO_SPACE | COMMAND_MEMORY_SPACE));
+    let mut index = 0;
+    while index < count {
+        let offset = REG_BAR0 + index as u16 * 4;
+        let (value, mask) = size_register(address, offset);
+        if value & BAR_IO != 0 {
+            let mask = mask & !0b11;
+            if mask != 0 {
+                bars[index] = Some(Bar::Io {
+                    port: value & !0b11,
+                    size: (!mask).wrapping_add(1) & 0xFFFF,
+                });
+            }
+            index += 1;
+            continue;
+        }
+        let wide = value & BAR_MEMORY_TYPE == BAR_MEMORY_64BIT && index + 1 < count;
+        let mut address_value = (value & !0xF) as u64;
+        let mut size_mask = (mask & !0xF) as u64 | 0xFFFF_FFFF_0000_0000;
+        if wide {
+            let (high, high_mask) = size_register(address, offset + 4);
+            address_value |= (high as u64) << 32;
+            size_mask = (size_mask & 0xFFFF_FFFF) | (high_mask as u64) << 32;
+        }
+        if mask & !0xF != 0 || (wide && size_mask >> 32 != 0) {
+            bars[index] = Some(Bar::Memory {
+                address: address_value,
+                size: (!size_mask).wrapping_add(1),
+                prefetchable: value & BAR_PREFETCHABLE != 0,
+                wide,
+            });
+        }
+        index += if wide { 2 } else { 1 };
+    }
+    address.set_command(command);
+    bars
+}
+
+/// Returns the value of a BAR and the bits that stick when writing all ones
+/// to it, then restores it.
+fn size_register(address: PciAddress, offset: u16) -> (u32, u32) {
+    let value = address.read_u32(offset);
+    address.write_u32(offset, 0xFFFF_FFFF);
+    let mask = address.read_u32(offset);
+    address.write_u32(offset, value);
+    (value, mask)
+}
+
+/// What a driver supports. [None] fields match anything.
+#[derive(Clone, Copy, PartialEq, Eq, Debug, Default)]
+pub struct PciMatch {
+    /// The vendor ID.
+    pub vendor_id: Option<u16>,
+    /// The device ID.
+    pub device_id: Option<u16>,
+    /// The base class.
+    pub class: Option<u8>,
+    /// The subclass.
+    pub subclass: Option<u8>,
+    /// The programming interface.
+    pub prog_if: Option<u8>,
+}
+
+impl PciMatch {
+    /// Matches every function.
+    pub const ANY: PciMatch = PciMatch {
+        vendor_id: None,
+        device_id: None,
+        class: None,
+        subclass: None,
+        prog_if: None,
+    };
+
+    /// Matches a vendor and device ID.
+    pub const fn device(vendor_id: u16, device_id: u16) -> Self {
+        PciMatch {
+            vendor_id: Some(vendor_id),
+            device_id: Some(device_id),
+            ..Self::ANY
+        }
+    }
+
+    /// Matches a class and subclass.
+    pub const fn class(class: u8, subclass: u8) -> Self {
+        PciMatch {
+            class: Some(class),
+            subclass: Some(subclass),
+            ..Self::ANY
+        }
+    }
+
+    /// Returns whether a function matches.
+    pub fn matches(&self, device: &PciDevice) -> bool {
+        self.vendor_id.is_none_or(|id| id == device.vendor_id) &&
+            self.device_id.is_none_or(|id| id == device.device_id) &&
+            self.class.is_none_or(|class| class == device.class) &&
+            self.subclass
+                .is_none_or(|subclass| subclass == device.subclass) &&
+            self.prog_if.is_none_or(|prog_if| prog_if == device.prog_if)
+    }
+}
+
+/// The functions found by [init_pci].
+static mut PCI_DEVICES: [PciDevice; MAX_PCI_DEVICES] = [PciDevice::EMPTY; MAX_PCI_DEVICES];
+/// The number of functions in [PCI_DEVICES].
+static mut PCI_DEVICE_COUNT: usize = 0;
+/// The number of functions found that didn't fit in [PCI_DEVICES].
+static mut DROPPED_PCI_DEVICES: usize = 0;
+/// The buses already scanned, one bit each, to stop at bridge loops.
+static mut SCANNED_BUSES: [u32; 8] = [0; 8];
+
+/// Returns every function found.
+pub fn pci_devices() -> &'static [PciDevice] { unsafe { &PCI_DEVICES[..PCI_DEVICE_COUNT] } }
+
+/// Returns the first function that matches.
+pub fn find_pci_device(pci_match: &PciMatch) -> Option<&'static PciDevice> {
+    pci_devices()
+        .iter()
+        .find(|device| pci_match.matches(device))
+}
+
+/// Returns every function that matches.
+pub fn matching_pci_devices(pci_match: &PciMatch) -> impl Iterator<Item = &'static PciDevice> {
+    pci_devices()
+        .iter()
+        .filter(move |device| pci_match.matches(device))
+}
+
+/// Returns the function at an address, if one was found there.
+pub fn pci_device_at(address: PciAddress) -> Option<&'static PciDevice> {
+    pci_devices()
+        .iter()
+        .find(|device| device.address == address)
+}
+
+/// Adds a function to the registry.
+fn register(device: PciDevice) {
+    unsafe {
+        if PCI_DEVICE_COUNT == MAX_PCI_DEVICES {
+            DROPPED_PCI_DEVICES += 1;
+            return;
+        }
+        PCI_DEVICES[PCI_DEVICE_COUNT] = device;
+        PCI_DEVICE_COUNT += 1;
+    }
+}
+
+/// Scans a function, and the bus behind it if it's a bridge.
+fn scan_function(address: PciAddress) {
+    let Some(device) = PciDevice::probe(address) else {
+        return;
+    };
+    register(device);
+    if device.is_bridge() {
+        scan_bus(address.read_u8(REG_BRIDGE_BUSES + 1));
+    }
+}
+
+/// Scans every device on a bus.
+fn scan_bus(bus: u8) {
+    unsafe {
+        let (word, bit) = (bus as usize / 32, 1 << (bus % 32));
+        if SCANNED_BUSES[word] & bit != 0 {
+            return;
+        }
+        SCANNED_BUSES[word] |= bit;
+    }
+    for device in 0..32 {
+        let address = PciAddress::new(bus, device, 0);
+        if address.vendor_id() == INVALID_VENDOR_ID {
+            continue;
+        }
+        scan_function(address);
+        if address.read_u8(REG_HEADER_TYPE + 2) & HEADER_MULTIFUNCTION != 0 {
+            for function in 1..8 {
+                scan_function(PciAddress::new(bus, device, function));
+            }
+        }
+    }
+}
+
+/// Returns whether the architecture can access extended configuration space,
+/// the 4 KiB of every PCI Express function.
+pub fn extended_config_available() -> bool { crate::arch::pci::PciExtendedConfig() }
+
+/// Scans every bus and fills the registry. Does nothing if the architecture
+/// has no configuration space access.
+pub fn init_pci() {
+    if !crate::arch::pci::PciInit() {
+        return;
+    }
+    unsafe {
+        PCI_DEVICE_COUNT = 0;
+        DROPPED_PCI_DEVICES = 0;
+        SCANNED_BUSES = [0; 8];
+    }
+    let host = PciAddress::new(0, 0, 0);
+    if host.read_u8(REG_HEADER_TYPE + 2) & HEADER_MULTIFUNCTION == 0 {
+        scan_bus(0);
+        return;
+    }
+    // Every function of a multifunction host bridge is the host bridge of
+    // the bus with its number.
+    for function in 0..8 {
+        if PciAddress::new(0, 0, function).vendor_id() != INVALID_VENDOR_ID {
+            scan_bus(function);
+        }
+    }
+}
+
+/// Returns the last `digits` hexadecimal digits of a value.
+fn hex(value: u32, digits: usize) -> [u8; 8] {
+    let mut hex = crate::u32_as_hex_u8_slice(value);
+    hex.rotate_left(8 - digits);
+    hex
+}
+
+/// Outputs a value in hexadecimal without a prefix.
+fn output_hex(value: u32, digits: usize) { sdebugbnp(&hex(value, digits)[..digits]); }
+
+/// Outputs the registry through the debug output, one function per line,
+/// followed by its BARs.
+pub fn print_pci_devices() {
+    for device in pci_devices() {
+        sdebugs("PCI ");
+        output_hex(device.address.bus as u32, 2);
+        sdebugsnp(":");
+        output_hex(device.address.device as u32, 2);
+        sdebugsnp(".");
+        output_hex(device.address.function as u32, 1);
+        sdebugsnp(" ");
+        output_hex(device.vendor_id as u32, 4);
+        sdebugsnp(":");
+        output_hex(device.device_id as u32, 4);
+        sdebugsnp(" class ");
+        output_hex(device.class as u32, 2);
+        output_hex(device.subclass as u32, 2);
+        output_hex(device.prog_if as u32, 2);
+        if device.is_bridge() {
+            sdebugsnp(" bridge");
+        }
+        sdebugsnpln("");
+        if device.address.capabilities().next().is_some() {
+            sdebugs("  capabilities");
+            for capability in device.address.capabilities() {
+                sdebugsnp(" ");
+                output_hex(capability.id as u32, 2);
+            }
+            for capability in device.address.extended_capabilities() {
+                sdebugsnp(" ");
+                output_hex(capability.id as u32, 4);
+            }
+            sdebugsnpln("");
+        }
+        for (index, bar) in device.bars.iter().enumerate() {
+            let Some(bar) = bar else {
+                continue;
+            };
+            sdebugs("  BAR");
+            sdebugunp(b'0' + index as u8);
+            let (kind, base, size) = match *bar {
+                Bar::Io { port, size } => (" io 0x", port as u64, size as u64),
+                Bar::Memory { address, size, .. } => (" mem 0x", address, size),
+            };
+            sdebugsnp(kind);
+            if base >> 32 != 0 {
+                output_hex((base >> 32) as u32, 8);
+            }
+            output_hex(base as u32, 8);
+            sdebugsnp(" size 0x");
+            if size >> 32 != 0 {
+                output_hex((size >> 32) as u32, 8);
+            }
+            output_hex(size as u32, 8);
+            sdebugsnpln("");
+        }
+    }
+    if unsafe { DROPPED_PCI_DEVICES } != 0 {
+        swarningsln("Some PCI functions didn't fit in the registry");
+    }
+}
@@ -0,0 +1,52 @@
+//! Per-CPU data. Every CPU has its own [PerCpu], found through an
+//! architecture specific register. Use [per_cpu!](crate::per_cpu) to access
+//! the data of the current CPU.
+
+/// The data of one CPU.
+#[repr(C)]
+pub struct PerCpu {
+    /// Points to this PerCpu. Architectures rely on it being the first field
+    /// to find the PerCpu through their per-CPU register.
+    pub this: *mut PerCpu,
+    /// The number of the CPU, starting at 0 for the boot CPU.
+    pub cpu_id: u32,
+    /// The number of nested IRQs currently being handled by the CPU.
+    pub irq_depth: u32,
+}
+
+impl PerCpu {
+    /// Creates the data of a CPU.
+    pub const fn new(cpu_id: u32) -> Self {
+        PerCpu {
+            this: core::ptr::null_mut(),
+            cpu_id,
+            irq_depth: 0,
+        }
+    }
+}
+
+/// The data of the boot CPU.
+static mut BOOT_CPU: PerCpu = PerCpu::new(0);
+
+/// Returns the data of the boot CPU. Architectures make the per-CPU register
+/// point to it during early boot.
+pub fn boot_cpu() -> *mut PerCpu { &raw mut BOOT_CPU }
+
+/// Returns a pointer to the data of the current CPU.
+pub fn this_cpu() -> *mut PerCpu { crate::arch::percpu::ThisCpu() }
+
+/// Reads (`per_cpu!(field)`) or writes (`per_cpu!(field = value)`) a field of
+/// the current CPU's [PerCpu](crate::percpu::PerCpu). Interrupts should be
+/// disabled if the current task could move to another CPU in between.
+#[macro_export]
+macro_rules! per_cpu {
+    ($field:ident) => {
+        unsafe { (*$crate::percpu::this_cpu()).$field }
+    };
+    ($field:ident = $value:expr) => {{
+        // Evaluated outside of the unsafe block, so that it can't call unsafe
+        // code without the caller noticing.
+        let value = $value;
+        unsafe { (*$crate::percpu::this_cpu()).$field = value }
+    }};
+}
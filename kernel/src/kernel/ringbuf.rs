@@ -0,0 +1,76 @@
+//! A lock-free ring buffer for one producer and one consumer, e.g. an IRQ
+//! handler and the rest of the kernel. Several producers or consumers have to
+//! be serialized by the caller, e.g. by disabling interrupts.
+
+use core::cell::UnsafeCell;
+use core::mem::MaybeUninit;
+use core::sync::atomic::{AtomicUsize, Ordering};
+
+/// A ring buffer holding up to `N - 1` values.
+pub struct RingBuffer<T: Copy, const N: usize> {
+    /// The storage of the values.
+    buffer: UnsafeCell<[MaybeUninit<T>; N]>,
+    /// The index of the oldest value. Only written by the consumer.
+    head: AtomicUsize,
+    /// The index the next value is written to. Only written by the producer.
+    tail: AtomicUsize,
+}
+
+unsafe impl<T: Copy + Send, const N: usize> Sync for RingBuffer<T, N> {}
+
+impl<T: Copy, const N: usize> RingBuffer<T, N> {
+    /// Creates an empty ring buffer.
+    pub const fn new() -> Self {
+        RingBuffer {
+            buffer: UnsafeCell::new([MaybeUninit::uninit(); N]),
+            head: AtomicUsize::new(0),
+            tail: AtomicUsize::new(0),
+        }
+    }
+
+    /// Returns the number of values the buffer can hold.
+    pub const fn capacity(&self) -> usize { N - 1 }
+
+    /// Adds a value. Returns false if the buffer is full. Must only be called
+    /// by the producer.
+    pub fn push(&self, value: T) -> bool {
+        let tail = self.tail.load(Ordering::Relaxed);
+        let next = (tail + 1) % N;
+        if next == self.head.load(Ordering::Acquire) {
+            return false;
+        }
+        unsafe {
+            (*self.buffer.get())[tail] = MaybeUninit::new(value);
+        }
+        self.tail.store(next, Ordering::Release);
+        true
+    }
+
+    /// Removes the oldest value. Must only be called by the consumer.
+    pub fn pop(&self) -> Option<T> {
+        let head = self.head.load(Ordering::Relaxed);
+        if head == self.tail.load(Ordering::Acquire) {
+            return None;
+        }
+        let value = unsafe { (*self.buffer.get())[head].assume_init() };
+        self.head.store((head + 1) % N, Ordering::Release);
+        Some(value)
+    }
+
+    /// Returns the number of values in the buffer.
+    pub fn len(&self) -> usize {
+        let head = self.head.load(Ordering::Acquire);
+        let tail = self.tail.load(Ordering::Acquire);
+        (tail + N - head) % N
+    }
+
+    /// Returns whether the buffer is empty.
+    pub fn is_empty(&self) -> bool { self.len() == 0 }
+
+    /// Returns whether the buffer is full.
+    pub fn is_full(&self) -> bool { self.len() == self.capacity() }
+}
+
+impl<T: Copy, const N: usize> Default for RingBuffer<T, N> {
+    fn default() -> Self { Self::new() }
+}
@@ -0,0 +1,80 @@
+//! Architecture independent syscall dispatch. The architecture passes every
+//! syscall to [dispatch_syscall] with the syscall number and three arguments.
+#![allow(static_mut_refs)]
+
+/// A function that handles a syscall. It is passed the three arguments of the
+/// syscall and returns the result.
+pub type SyscallHandler = fn(u32, u32, u32) -> u32;
+
+/// The number of syscall numbers that can have handlers.
+pub const SYSCALL_COUNT: usize = 256;
+
+/// Returned by [register_syscall] if the syscall number is too high.
+pub const ERR_INVALID_SYSCALL: i16 = -1;
+
+/// Returned by [register_syscall] if the syscall number already has a handler.
+pub const ERR_SYSCALL_IN_USE: i16 = -2;
+
+/// Returned to userspace, sign extended to a u32, by syscalls without a
+/// handler.
+pub const ERR_UNKNOWN_SYSCALL: i16 = -3;
+
+/// Sets the thread pointer of the calling thread to its first argument.
+pub const SYSCALL_SET_THREAD_POINTER: u32 = 0;
+
+/// The registered syscall handlers, indexed by syscall number.
+static mut SYSCALL_TABLE: [Option<SyscallHandler>; SYSCALL_COUNT] = [None; SYSCALL_COUNT];
+
+/// Sets up the architecture's syscall entry points and registers the syscalls
+/// handled by the kernel itself.
+pub fn init_syscalls() {
+    crate::arch::syscall::SyscallInit();
+    let _ = register_syscall(SYSCALL_SET_THREAD_POINTER, sys_set_thread_pointer);
+}
+
+/// Handles [SYSCALL_SET_THREAD_POINTER].
+fn sys_set_thread_pointer(ptr: u32, _: u32, _: u32) -> u32 {
+    crate::arch::percpu::SetThreadPointer(ptr as usize);
+    0
+}
+
+/// Registers a handler for a syscall number.
+pub fn register_syscall(id: u32, handler: SyscallHandler) -> Result<(), crate::Error<'static>> {
+    if id as usize >= SYSCALL_COUNT {
+        return Err(crate::Error::new(
+            "invalid syscall number",
+            ERR_INVALID_SYSCALL,
+        ));
+    }
+    let _irq = crate::arch::interrupts::InterruptsPop();
+    unsafe {
+        if SYSCALL_TABLE[id as usize].is_some() {
+            return Err(crate::Error::new(
+                "syscall already has a handler",
+                ERR_SYSCALL_IN_USE,
+            ));
+        }
+        SYSCALL_TABLE[id as usize] = Some(handler);
+    }
+    Ok(())
+}
+
+/// Removes the handler of a syscall number.
+pub fn unregister_syscall(id: u32) {
+    if id as usize >= SYSCALL_COUNT {
+        return;
+    }
+    let _irq = crate::arch::interrupts::InterruptsPop();
+    unsafe {
+        SYSCALL_TABLE[id as usize] = None;
+    }
+}
+
+/// Runs the handler of a syscall and returns its result, or
+/// [ERR_UNKNOWN_SYSCALL] if there is no handler.
+pub fn dispatch_syscall(id: u32, arg0: u32, arg1: u32, arg2: u32) -> u32 {
+    match unsafe { SYSCALL_TABLE.get(id as usize).copied().flatten() } {
+        Some(handler) => handler(arg0, arg1, arg2),
+        None => ERR_UNKNOWN_SYSCALL as i32 as u32,
+    }
+}
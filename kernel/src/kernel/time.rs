@@ -0,0 +1,284 @@
+//! Kernel time. The monotonic clock counts nanoseconds since it was first read
+//! and is backed by the best registered [Clocksource]. The wall clock is the
+//! monotonic clock plus the time set with [set_wall_clock].
+#![allow(static_mut_refs)]
+
+/// The number of nanoseconds in a second.
+pub const NANOS_PER_SECOND: u64 = 1_000_000_000;
+
+/// A free running counter time can be read from.
+#[derive(Clone, Copy)]
+pub struct Clocksource {
+    /// The name of the clocksource, e.g. "tsc".
+    pub name: &'static str,
+    /// Reads the counter. It must never go backwards.
+    pub read: fn() -> u64,
+    /// The number of times the counter is incremented per second.
+    pub frequency: u64,
+    /// How good the clocksource is. The one with the highest rating is used.
+    pub rating: u32,
+}
+
+impl Clocksource {
+    /// Converts a number of counter increments to nanoseconds.
+    pub fn cycles_to_ns(&self, cycles: u64) -> u64 {
+        let seconds = cycles / self.frequency;
+        let rest = cycles % self.frequency;
+        seconds * NANOS_PER_SECOND + rest * NANOS_PER_SECOND / self.frequency
+    }
+}
+
+/// The clocksource in use.
+static mut CLOCKSOURCE: Option<Clocksource> = None;
+/// Added to the time read from the clocksource, so that time stays continuous
+/// when switching clocksources.
+static mut OFFSET_NS: u64 = 0;
+/// The last time returned by [monotonic_ns].
+static mut LAST_NS: u64 = 0;
+
+/// Sets up the architecture's clocksources.
+pub fn init_time() { crate::arch::time::TimeInit(); }
+
+/// Registers a clocksource. It's used if its rating is higher than the one of
+/// the clocksource in use.
+pub fn register_clocksource(clocksource: Clocksource) {
+    if clocksource.frequency == 0 {
+        return;
+    }
+    let _irq = crate::arch::interrupts::InterruptsPop();
+    unsafe {
+        if let Some(current) = CLOCKSOURCE &&
+            current.rating >= clocksource.rating
+        {
+            return;
+        }
+        let now = monotonic_ns();
+        CLOCKSOURCE = Some(clocksource);
+        OFFSET_NS = now.wrapping_sub(clocksource.cycles_to_ns((clocksource.read)()));
+    }
+}
+
+/// Returns the clocksource in use.
+pub fn clocksource() -> Option<Clocksource> { unsafe { CLOCKSOURCE } }
+
+/// Returns the number of nanoseconds since the monotonic clock started, or 0
+/// if there is no clocksource. Never goes backwards.
+pub fn monotonic_ns() -> u64 {
+    let _irq = crate::arch::interrupts::InterruptsPop();
+    unsafe {
+        let Some(clocksource) = CLOCKSOURCE else {
+            return LAST_NS;
+        };
+        let now = clocksource
+            .cycles_to_ns((clocksource.read)())
+            .wrapping_add(OFFSET_NS);
+        if now > LAST_NS {
+            LAST_NS = now;
+        }
+        LAST_NS
+    }
+}
+
+/// A timer that can raise an interrupt after some time. Drivers call
+/// [clock_event_fired] when it does.
+#[derive(Clone, Copy)]
+pub struct ClockEventDevice {
+    /// The name of the device, e.g. "hpet".
+    pub name: &'static str,
+    /// The number of cycles per second the device counts.
+    pub frequency: u64,
+    /// The smallest number of cycles the device can be programmed with.
+    pub min_delta: u64,
+    /// The largest number of cycles the device can be programmed with.
+    pub max_delta: u64,
+    /// How good the device is. The one with the highest rating is used.
+    pub rating: u32,
+    /// Passed to the callbacks, e.g. to select a comparator.
+    pub data: usize,
+    /// Makes the device fire once after a number of cycles.
+    pub program_oneshot: fn(data: usize, delta: u64),
+    /// Makes the device fire every time a number of cycles passed, if it can.
+    pub program_periodic: Option<fn(data: usize, period: u64)>,
+    /// Stops the device from firing.
+    pub stop: fn(data: usize),
+}
+
+impl ClockEventDevice {
+    /// Converts nanoseconds to cycles of the device, clamped to what it can be
+    /// programmed with.
+    pub fn ns_to_cycles(&self, ns: u64) -> u64 {
+        let seconds = ns / NANOS_PER_SECOND;
+        let rest = ns % NANOS_PER_SECOND;
+        let cycles = seconds
+            .saturating_mul(self.frequency)
+            .saturating_add(rest * self.frequency / NANOS_PER_SECOND);
+        cycles.clamp(self.min_delta, self.max_delta)
+    }
+}
+
+/// The clock event device in use.
+static mut CLOCK_EVENT_DEVICE: Option<ClockEventDevice> = None;
+/// Called when the clock event device fires.
+static mut CLOCK_EVENT_HANDLER: Option<fn()> = None;
+
+/// Registers a clock event device. It's used if its rating is higher than the
+/// one of the device in use, which is stopped.
+pub fn register_clock_event_device(device: ClockEventDevice) {
+    if device.frequency == 0 {
+        return;
+    }
+    let _irq = crate::arch::interrupts::InterruptsPop();
+    unsafe {
+        if let Some(current) = CLOCK_EVENT_DEVICE {
+            if current.rating >= device.rating {
+                return;
+            }
+            (current.stop)(current.data);
+        }
+        CLOCK_EVENT_DEVICE = Some(device);
+    }
+}
+
+/// Returns the clock event device in use.
+pub fn clock_event_device() -> Option<ClockEventDevice> { unsafe { CLOCK_EVENT_DEVICE } }
+
+/// Sets the function called when the clock event device fires. It's called in
+/// interrupt context.
+pub fn set_clock_event_handler(handler: fn()) {
+    unsafe {
+        CLOCK_EVENT_HANDLER = Some(handler);
+    }
+}
+
+/// Makes the clock event device fire once after about `ns` nanoseconds.
+/// Returns false if there is no clock event device.
+pub fn program_clock_event(ns: u64) -> bool {
+    let Some(device) = clock_event_device() else {
+        return false;
+    };
+    (device.program_oneshot)(device.data, device.ns_to_cycles(ns));
+    true
+}
+
+/// Makes the clock event device fire every `ns` nanoseconds. Returns false if
+/// there is no clock event device or it can't fire periodically.
+pub fn program_periodic_clock_event(ns: u64) -> bool {
+    let Some(device) = clock_event_device() else {
+        return false;
+    };
+    let Some(program_periodic) = device.program_periodic else {
+        return false;
+    };
+    program_periodic(device.data, device.ns_to_cycles(ns));
+    true
+}
+
+/// Stops the clock event device from firing.
+pub fn stop_clock_event() {
+    if let Some(device) = clock_event_device() {
+        (device.stop)(device.data);
+    }
+}
+
+/// Called by drivers when a clock event device fires. Only calls the handler
+/// if `name` and `data` are the ones of the device in use.
+pub fn clock_event_fired(name: &str, data: usize) {
+    let Some(device) = clock_event_device() else {
+        return;
+    };
+    if device.name != name || device.data != data {
+        return;
+    }
+    if let Some(handler) = unsafe { CLOCK_EVENT_HANDLER } {
+        handler();
+    }
+}
+
+/// The number of seconds in a day.
+const SECONDS_PER_DAY: u64 = 86400;
+
+/// A date and time in UTC.
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub struct DateTime {
+    /// The year, e.g. 2024.
+    pub year: u16,
+    /// The month, from 1 to 12.
+    pub month: u8,
+    /// The day of the month, from 1 to 31.
+    pub day: u8,
+    /// The hour, from 0 to 23.
+    pub hour: u8,
+    /// The minute, from 0 to 59.
+    pub minute: u8,
+    /// The second, from 0 to 59.
+    pub second: u8,
+}
+
+impl DateTime {
+    /// Returns the number of seconds since 1970-01-01 00:00:00 UTC. Dates
+    /// before it saturate to 0.
+    pub fn to_unix(&self) -> u64 {
+        // Counts years from March, so that the leap day is at the end of one.
+        let year = self.year as i64 - if self.month <= 2 { 1 } else { 0 };
+        let era = year.div_euclid(400);
+        let year_of_era = year - era * 400;
+        let month = self.month as i64;
+        let day_of_year =
+            (153 * (month + if month > 2 { -3 } else { 9 }) + 2) / 5 + self.day as i64 - 1;
+        let day_of_era = year_of_era * 365 + year_of_era / 4 - year_of_era / 100 + day_of_year;
+        let days = era * 146097 + day_of_era - 719468;
+        let seconds = days * SECONDS_PER_DAY as i64 +
+            self.hour as i64 * 3600 +
+            self.minute as i64 * 60 +
+            self.second as i64;
+        seconds.max(0) as u64
+    }
+
+    /// Returns the date and time a number of seconds after 1970-01-01 00:00:00
+    /// UTC.
+    pub fn from_unix(seconds: u64) -> Self {
+        let days = (seconds / SECONDS_PER_DAY) as i64 + 719468;
+        let rest = seconds % SECONDS_PER_DAY;
+        let era = days / 146097;
+        let day_of_era = days - era * 146097;
+        let year_of_era =
+            (day_of_era - day_of_era / 1460 + day_of_era / 36524 - day_of_era / 146096) / 365;
+        let day_of_year = day_of_era - (365 * year_of_era + year_of_era / 4 - year_of_era / 100);
+        let month = (5 * day_of_year + 2) / 153;
+        let day = day_of_year - (153 * month + 2) / 5 + 1;
+        let month = if month < 10 { month + 3 } else { month - 9 };
+        let year = year_of_era + era * 400 + if month <= 2 { 1 } else { 0 };
+        DateTime {
+            year: year as u16,
+            month: month as u8,
+            day: day as u8,
+            hour: (rest / 3600) as u8,
+            minute: (rest / 60 % 60) as u8,
+            second: (rest % 60) as u8,
+        }
+    }
+}
+
+/// The Unix time in nanoseconds when the monotonic clock was at 0, if the
+/// wall clock is known.
+static mut WALL_CLOCK_OFFSET_NS: Option<u64> = None;
+
+/// Sets the current wall clock time. Usually called by the architecture with
+/// the time of a hardware clock.
+pub fn set_wall_clock(now: DateTime) {
+    let unix_ns = now.to_unix() * NANOS_PER_SECOND;
+    unsafe {
+        WALL_CLOCK_OFFSET_NS = Some(unix_ns.saturating_sub(monotonic_ns()));
+    }
+}
+
+/// Returns the number of nanoseconds since 1970-01-01 00:00:00 UTC, if the
+/// wall clock is known.
+pub fn unix_time_ns() -> Option<u64> {
+    unsafe { WALL_CLOCK_OFFSET_NS }.map(|offset| offset + monotonic_ns())
+}
+
+/// Returns the current date and time, if the wall clock is known.
+pub fn now() -> Option<DateTime> {
+    unix_time_ns().map(|ns| DateTime::from_unix(ns / NANOS_PER_SECOND))
+}
@@ -0,0 +1,317 @@
+//! Kernel timers: callbacks that run once after a delay or periodically. The
+//! pending timers are kept in a binary heap ordered by expiry, checked on every
+//! tick of the clock event device. Expired callbacks are queued as
+//! [deferred work](crate::deferred), so they run with interrupts enabled.
+//! Every function can be called from interrupt context.
+#![allow(static_mut_refs)]
+
+use crate::time::{NANOS_PER_SECOND, monotonic_ns};
+
+/// A function called when a timer expires. It is passed the data the timer
+/// was armed with.
+pub type TimerCallback = fn(usize);
+
+/// The number of timers that can be pending at once.
+pub const MAX_TIMERS: usize = 128;
+
+/// How often timers are checked while ticking.
+pub const TIMER_HZ: u64 = 1000;
+
+/// The number of nanoseconds between two ticks.
+pub const TICK_NS: u64 = NANOS_PER_SECOND / TIMER_HZ;
+
+/// Returned by [add_timer] and [add_periodic_timer] if [MAX_TIMERS] timers are
+/// already pending.
+pub const ERR_NO_FREE_TIMERS: i16 = -1;
+
+/// Identifies an armed timer, to cancel it. Stays invalid once the timer
+/// expired or was cancelled, even if its slot is reused.
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub struct TimerHandle {
+    /// The slot of the timer.
+    slot: u16,
+    /// The generation of the slot when the timer was armed.
+    generation: u32,
+}
+
+/// A timer slot.
+#[derive(Clone, Copy)]
+struct Timer {
+    /// When the timer expires, in [monotonic_ns].
+    expires: u64,
+    /// The period of a periodic timer, or 0 for a one-shot timer.
+    period: u64,
+    /// The function to call.
+    callback: TimerCallback,
+    /// The data passed to the callback.
+    data: usize,
+    /// Incremented every time the slot is freed.
+    generation: u32,
+    /// Whether the slot holds a pending timer.
+    pending: bool,
+}
+
+/// The callback of free slots.
+fn no_callback(_: usize) {}
+
+/// The timer slots.
+static mut TIMERS: [Timer; MAX_TIMERS] = [Timer {
+    expires: 0,
+    period: 0,
+    callback: no_callback,
+    data: 0,
+    generation: 0,
+    pending: false,
+}; MAX_TIMERS];
+
+/// The slots of the pending timers as a binary min-heap on their expiry.
+static mut HEAP: [u16; MAX_TIMERS] = [0; MAX_TIMERS];
+
+/// The number of pending timers.
+static mut HEAP_LEN: usize = 0;
+
+/// Whether the clock event device is programmed periodically.
+static mut PERIODIC_TICK: bool = false;
+
+/// Whether ticking is stopped by [tick_stop].
+static mut TICK_STOPPED: bool = false;
+
+/// Returns the expiry of the timer at a heap position.
+unsafe fn expiry_at(position: usize) -> u64 { unsafe { TIMERS[HEAP[position] as usize].expires } }
+
+/// Moves the timer at a heap position up until the heap is ordered.
+unsafe fn sift_up(mut position: usize) {
+    unsafe {
+        while position > 0 {
+            let parent = (position - 1) / 2;
+            if expiry_at(parent) <= expiry_at(position) {
+                break;
+            }
+            HEAP.swap(parent, position);
+            position = parent;
+        }
+    }
+}
+
+/// Moves the timer at a heap position down until the heap is ordered.
+unsafe fn sift_down(mut position: usize) {
+    unsafe {
+        loop {
+            let left = position * 2 + 1;
+            let right = left + 1;
+            let mut smallest = position;
+            if left < HEAP_LEN && expiry_at(left) < expiry_at(smallest) {
+                smallest = left;
+            }
+            if right < HEAP_LEN && expiry_at(right) < expiry_at(smallest) {
+                smallest = right;
+            }
+            if smallest == position {
+                break;
+            }
+            HEAP.swap(smallest, position);
+            position = smallest;
+        }
+    }
+}
+
+/// Removes the timer at a heap position from the heap.
+unsafe fn remove_at(position: usize) {
+    unsafe {
+        HEAP_LEN -= 1;
+        if position == HEAP_LEN {
+            return;
+        }
+        HEAP[position] = HEAP[HEAP_LEN];
+        sift_down(position);
+        sift_up(position);
+    }
+}
+
+/// Arms a timer in a free slot.
+fn arm(
+    expires: u64,
+    period: u64,
+    callback: TimerCallback,
+    data: usize,
+) -> Result<TimerHandle, crate::Error<'static>> {
+    let _irq = crate::arch::interrupts::InterruptsPop();
+    unsafe {
+        let Some(slot) = TIMERS.iter().position(|timer| !timer.pending) else {
+            return Err(crate::Error::new(
+                "too many pending timers",
+                ERR_NO_FREE_TIMERS,
+            ));
+        };
+        let timer = &mut TIMERS[slot];
+        timer.expires = expires;
+        timer.period = period;
+        timer.callback = callback;
+        timer.data = data;
+        timer.pending = true;
+        HEAP[HEAP_LEN] = slot as u16;
+        HEAP_LEN += 1;
+        sift_up(HEAP_LEN - 1);
+        Ok(TimerHandle {
+            slot: slot as u16,
+            generation: timer.generation,
+        })
+    }
+}
+
+/// Calls `callback` with `data` once after at least `delay_ns` nanoseconds.
+pub fn add_timer(
+    delay_ns: u64,
+    callback: TimerCallback,
+    data: usize,
+) -> Result<TimerHandle, crate::Error<'static>> {
+    arm(monotonic_ns().saturating_add(delay_ns), 0, callback, data)
+}
+
+/// Calls `callback` with `data` every `period_ns` nanoseconds until the timer
+/// is cancelled. The period is at least one tick.
+pub fn add_periodic_timer(
+    period_ns: u64,
+    callback: TimerCallback,
+    data: usize,
+) -> Result<TimerHandle, crate::Error<'static>> {
+    let period_ns = period_ns.max(TICK_NS);
+    arm(
+        monotonic_ns().saturating_add(period_ns),
+        period_ns,
+        callback,
+        data,
+    )
+}
+
+/// Cancels a timer. Returns whether it was still pending. The callback may
+/// still run afterwards if it was already queued.
+pub fn cancel_timer(handle: TimerHandle) -> bool {
+    let _irq = crate::arch::interrupts::InterruptsPop();
+    unsafe {
+        let timer = &mut TIMERS[handle.slot as usize];
+        if !timer.pending || timer.generation != handle.generation {
+            return false;
+        }
+        let position = HEAP[..HEAP_LEN]
+            .iter()
+            .position(|slot| *slot == handle.slot)
+            .unwrap();
+        remove_at(position);
+        timer.pending = false;
+        timer.generation = timer.generation.wrapping_add(1);
+    }
+    true
+}
+
+/// Returns whether a timer is still pending.
+pub fn timer_pending(handle: TimerHandle) -> bool {
+    let timer = unsafe { TIMERS[handle.slot as usize] };
+    timer.pending && timer.generation == handle.generation
+}
+
+/// Returns when the next timer expires, in [monotonic_ns].
+pub fn next_expiry() -> Option<u64> {
+    let _irq = crate::arch::interrupts::InterruptsPop();
+    unsafe {
+        if HEAP_LEN == 0 {
+            None
+        } else {
+            Some(expiry_at(0))
+        }
+    }
+}
+
+/// Queues the callbacks of the expired timers and rearms periodic ones.
+pub fn run_timers() {
+    let now = monotonic_ns();
+    let _irq = crate::arch::interrupts::InterruptsPop();
+    unsafe {
+        while HEAP_LEN != 0 && expiry_at(0) <= now {
+            let slot = HEAP[0] as usize;
+            let timer = &mut TIMERS[slot];
+            let (callback, data) = (timer.callback, timer.data);
+            if timer.period != 0 {
+                // Skips missed periods instead of firing for each of them.
+                while timer.expires <= now {
+                    timer.expires += timer.period;
+                }
+                sift_down(0);
+            } else {
+                remove_at(0);
+                timer.pending = false;
+                timer.generation = timer.generation.wrapping_add(1);
+            }
+            if crate::deferred::queue_work(callback, data).is_err() {
+                callback(data);
+            }
+        }
+    }
+}
+
+/// Called on every tick of the clock event device, or by the architecture on
+/// every timer interrupt if there is no clock event device.
+pub fn timer_tick() {
+    run_timers();
+    if unsafe { !PERIODIC_TICK && !TICK_STOPPED } {
+        crate::time::program_clock_event(TICK_NS);
+    }
+}
+
+/// Stops ticking until the next timer expires, if that's more than a tick
+/// away, by programming the clock event device once for its expiry. Called
+/// before idling; [tick_resume] starts ticking again.
+pub fn tick_stop() {
+    let _irq = crate::arch::interrupts::InterruptsPop();
+    let Some(device) = crate::time::clock_event_device() else {
+        return;
+    };
+    let delay = match next_expiry() {
+        Some(expiry) => expiry.saturating_sub(monotonic_ns()),
+        None => u64::MAX,
+    };
+    if delay <= TICK_NS {
+        return;
+    }
+    (device.stop)(device.data);
+    // Without pending timers, only other interrupts wake the CPU.
+    if delay != u64::MAX {
+        crate::time::program_clock_event(delay);
+    }
+    unsafe {
+        TICK_STOPPED = true;
+    }
+}
+
+/// Starts ticking again after [tick_stop].
+pub fn tick_resume() {
+    let _irq = crate::arch::interrupts::InterruptsPop();
+    unsafe {
+        if !TICK_STOPPED {
+            return;
+        }
+        TICK_STOPPED = false;
+    }
+    run_timers();
+    if unsafe { PERIODIC_TICK } {
+        crate::time::program_periodic_clock_event(TICK_NS);
+    } else {
+        crate::time::program_clock_event(TICK_NS);
+    }
+}
+
+/// Returns whether ticking is stopped by [tick_stop].
+pub fn tick_stopped() -> bool { unsafe { TICK_STOPPED } }
+
+/// Starts ticking with the clock event device: periodically if it can, and
+/// by rearming it on every tick otherwise.
+pub fn init_timers() {
+    crate::time::set_clock_event_handler(timer_tick);
+    let periodic = crate::time::program_periodic_clock_event(TICK_NS);
+    unsafe {
+        PERIODIC_TICK = periodic;
+    }
+    if !periodic {
+        crate::time::program_clock_event(TICK_NS);
+    }
+}
@@ -90,6 +90,23 @@ pub const fn u64_as_u8_slice(mut value: u64) -> [u8; 20] {
     buf
 }
 
+/// Converts an u32 to an [u8; 8] of uppercase hexadecimal digits, padded with
+/// zeros.
+pub const fn u32_as_hex_u8_slice(value: u32) -> [u8; 8] {
+    let mut buf = [b'0'; 8];
+    let mut i = 0;
+    while i < 8 {
+        let digit = ((value >> ((7 - i) * 4)) & 0xF) as u8;
+        buf[i] = if digit < 10 {
+            b'0' + digit
+        } else {
+            b'A' + digit - 10
+        };
+        i += 1;
+    }
+    buf
+}
+
 /// Converts an &mut \[u8] to a i16.
 pub fn str_as_i16(mut value: &[u8]) -> i16 {
     let mut out = 0i16;
@@ -0,0 +1,81 @@
+//! A watchdog that detects a CPU making no progress. Code that makes progress
+//! calls [watchdog_touch]; the architecture calls [watchdog_tick]
+//! [WATCHDOG_HZ] times a second from a timer that fires even while the CPU is
+//! stuck, and reports where it was stuck if [watchdog_tick] returns true.
+#![allow(static_mut_refs)]
+
+use core::sync::atomic::{AtomicU32, Ordering};
+
+/// How often the architecture calls [watchdog_tick].
+pub const WATCHDOG_HZ: u32 = 20;
+
+/// The default number of seconds without progress before the watchdog fires.
+pub const WATCHDOG_TIMEOUT_SECONDS: u32 = 10;
+
+/// Incremented by [watchdog_touch].
+static PROGRESS: AtomicU32 = AtomicU32::new(0);
+
+/// The value of [PROGRESS] at the last tick.
+static mut LAST_PROGRESS: u32 = 0;
+
+/// The number of ticks since [PROGRESS] last changed.
+static mut STALLED_TICKS: u32 = 0;
+
+/// The number of seconds without progress before the watchdog fires.
+static mut TIMEOUT_SECONDS: u32 = 0;
+
+/// Whether the watchdog is running.
+static mut ARMED: bool = false;
+
+/// Starts the watchdog. It fires after `timeout_seconds` seconds without a
+/// call to [watchdog_touch].
+pub fn watchdog_start(timeout_seconds: u32) {
+    let _irq = crate::arch::interrupts::InterruptsPop();
+    unsafe {
+        LAST_PROGRESS = PROGRESS.load(Ordering::Relaxed);
+        STALLED_TICKS = 0;
+        TIMEOUT_SECONDS = timeout_seconds;
+        ARMED = true;
+    }
+    crate::arch::watchdog::WatchdogStart(WATCHDOG_HZ);
+}
+
+/// Stops the watchdog.
+pub fn watchdog_stop() {
+    let _irq = crate::arch::interrupts::InterruptsPop();
+    unsafe {
+        ARMED = false;
+    }
+    crate::arch::watchdog::WatchdogStop();
+}
+
+/// Returns whether the watchdog is running.
+pub fn watchdog_armed() -> bool { unsafe { ARMED } }
+
+/// Returns the number of seconds without progress before the watchdog fires.
+pub fn watchdog_timeout() -> u32 { unsafe { TIMEOUT_SECONDS } }
+
+/// Records that the current CPU made progress.
+pub fn watchdog_touch() { PROGRESS.fetch_add(1, Ordering::Relaxed); }
+
+/// Called by the architecture [WATCHDOG_HZ] times a second. Returns true once
+/// the timeout passes without progress; the watchdog is disarmed then.
+pub fn watchdog_tick() -> bool {
+    unsafe {
+        if !ARMED {
+            return false;
+        }
+        let progress = PROGRESS.load(Ordering::Relaxed);
+        if progress != LAST_PROGRESS {
+            LAST_PROGRESS = progress;
+            STALLED_TICKS = 0;
+            return false;
+        }
+        STALLED_TICKS += 1;
+        if STALLED_TICKS < TIMEOUT_SECONDS * WATCHDOG_HZ {
+            return false;
+        }
+        ARMED = false;
+        true
+    }
+}
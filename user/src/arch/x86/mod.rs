@@ -1,62 +1,95 @@
 //! x86 syscall method.
 
-/// Syscall method.
+/// Syscall method. The id is passed in eax and the arguments in ebx, ecx and
+/// edx; the kernel returns its result in eax.
 #[macro_export]
 macro_rules! syscall {
     ($id: expr) => {
         unsafe {
-            let out = 0u32;
-            asm!(
+            let out: u32;
+            ::core::arch::asm!(
                 "int 0xA0",
-                id = in("eax") const $id,
-                out("eax") out
-            )
+                inlateout("eax") $id as u32 => out,
+            );
             out
         }
     };
     ($id: expr, $d0: expr) => {
         unsafe {
-            let out = 0u32;
-            let d0 = $d0;
-            asm!(
+            let out: u32;
+            ::core::arch::asm!(
                 "int 0xA0",
-                id = in("eax") const $id,
-                d0 = in("ebx") $d0,
-                out("eax") out
-            )
+                inlateout("eax") $id as u32 => out,
+                in("ebx") $d0 as u32,
+            );
             out
         }
     };
     ($id: expr, $d0: expr, $d1: expr) => {
         unsafe {
-            let out = 0u32;
-            let d0 = $d0;
-            let d1 = $d1;
-            asm!(
+            let out: u32;
+            ::core::arch::asm!(
                 "int 0xA0",
-                id = in("eax") const $id,
-                d0 = in("ebx") $d0,
-                d1 = in("ecx") $d1,
-                out("eax") out
-            )
+                inlateout("eax") $id as u32 => out,
+                in("ebx") $d0 as u32,
+                in("ecx") $d1 as u32,
+            );
             out
         }
     };
     ($id: expr, $d0: expr, $d1: expr, $d2: expr) => {
         unsafe {
-            let out = 0u32;
-            let d0 = $d0;
-            let d1 = $d1;
-            let d2 = $d2;
-            asm!(
+            let out: u32;
+            ::core::arch::asm!(
                 "int 0xA0",
-                id = in("eax") const $id,
-                d0 = in("ebx") $d0,
-                d1 = in("ecx") $d1,
-                d2 = in("edx") $d2,
-                out("eax") out
-            )
+                inlateout("eax") $id as u32 => out,
+                in("ebx") $d0 as u32,
+                in("ecx") $d1 as u32,
+                in("edx") $d2 as u32,
+            );
             out
         }
-    }
-}
\ No newline at end of file
+    };
+}
+
+/// Fast syscall method using SYSENTER. Only usable if the kernel enabled
+/// SYSENTER. The id is passed in eax and the arguments in ebx, esi and edi; the
+/// kernel returns its result in eax. ecx and edx are clobbered.
+///
+/// LLVM reserves esi on x86, so it can't be an asm operand: the last two
+/// arguments are loaded from memory through edi, and esi is saved on the stack
+/// around SYSENTER.
+#[macro_export]
+macro_rules! fast_syscall {
+    ($id: expr) => {
+        $crate::fast_syscall!($id, 0u32, 0u32, 0u32)
+    };
+    ($id: expr, $d0: expr) => {
+        $crate::fast_syscall!($id, $d0, 0u32, 0u32)
+    };
+    ($id: expr, $d0: expr, $d1: expr) => {
+        $crate::fast_syscall!($id, $d0, $d1, 0u32)
+    };
+    ($id: expr, $d0: expr, $d1: expr, $d2: expr) => {{
+        let args: [u32; 2] = [$d1 as u32, $d2 as u32];
+        let out: u32;
+        unsafe {
+            ::core::arch::asm!(
+                "push esi",
+                "mov esi, [edi]",
+                "mov edi, [edi + 4]",
+                "mov ecx, esp",
+                "lea edx, [2f]",
+                "sysenter",
+                "2:",
+                "pop esi",
+                inlateout("eax") $id as u32 => out,
+                in("ebx") $d0 as u32,
+                inout("edi") args.as_ptr() => _,
+                out("ecx") _,
+                out("edx") _,
+            );
+        }
+        out
+    }};
+}
@@ -1,5 +1,7 @@
 #[warn(missing_docs)]
-
 mod arch;
 
-use arch::*;
\ No newline at end of file
+use arch::*;
+
+#[cfg(target_arch = "x86")]
+pub mod tls;
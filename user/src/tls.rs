@@ -0,0 +1,40 @@
+//! Thread-local storage. The kernel keeps a thread pointer per thread that
+//! points to its TLS block and is the base of the TLS segment loaded into fs;
+//! the first word of the block must point to the block itself.
+
+/// The syscall setting the thread pointer.
+pub const SYSCALL_SET_THREAD_POINTER: u32 = 0;
+
+/// The selector of the TLS segment.
+pub const TLS_SELECTOR: u16 = (8 << 3) | 3;
+
+/// Sets the thread pointer of the current thread to a TLS block and loads the
+/// TLS segment into fs.
+///
+/// # Safety
+///
+/// The first word of the block must be its own address and the block must
+/// stay valid while the thread runs.
+pub unsafe fn set_thread_pointer(block: *mut usize) {
+    crate::syscall!(SYSCALL_SET_THREAD_POINTER, block as usize);
+    unsafe {
+        ::core::arch::asm!(
+            "mov fs, {0:x}",
+            in(reg) TLS_SELECTOR as u32,
+            options(nostack, preserves_flags)
+        );
+    }
+}
+
+/// Returns the thread pointer set by [set_thread_pointer].
+pub fn thread_pointer() -> *mut usize {
+    let ptr: *mut usize;
+    unsafe {
+        ::core::arch::asm!(
+            "mov {}, fs:[0]",
+            out(reg) ptr,
+            options(nostack, readonly, preserves_flags)
+        );
+    }
+    ptr
+}